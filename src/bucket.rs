@@ -0,0 +1,794 @@
+use crate::{
+    codec,
+    context::Context,
+    iter::*,
+    node::Node,
+    pagecache::{Lsn, PageId},
+    prelude::*,
+    sync::*,
+    tree::*,
+};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    sync::Weak,
+};
+
+/// Derives the secondary key of a record from its primary key and value,
+/// records without a secondary key are left out of the index
+pub type Extractor = Arc<dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// Bucket names in the meta page used for index keyspaces start with this
+const INDEX_PREFIX: &[u8] = b"__index__\0";
+
+/// State shared by every handle of the same bucket
+#[derive(Default)]
+pub(crate) struct BucketState {
+    /// Registered indexes, writers hold the read lock for the whole write so
+    /// that an index can not miss a write while it is being built
+    indexes: RwLock<Vec<Index>>,
+    /// Indexes found in the meta page which have not been created again
+    /// since opening. Their extractors are not persisted, so writes are
+    /// refused until they are. Only changed under the `indexes` write lock.
+    unregistered: Mutex<Vec<Key>>,
+    /// Serializes writers when there are indexes to maintain, so that the
+    /// previous values they read stay valid until the write lands
+    writer: Mutex<()>,
+}
+
+/// A mutable key-value keyspace.
+///
+/// Unlike blocks, a bucket is not content addressed: it is registered by
+/// name in the meta page and every write is applied to it immediately.
+#[derive(Clone)]
+pub struct Bucket {
+    pub(crate) context: Context,
+    /// Name of the bucket in the meta page
+    name: Key,
+    /// Table ID
+    pub(crate) id: PageId,
+    state: Arc<BucketState>,
+}
+
+impl Bucket {
+    /// Open the bucket `name`, creating it if it does not exist yet
+    pub(crate) fn open(context: Context, name: Key, guard: &Guard) -> IResult<Self> {
+        if name.starts_with(INDEX_PREFIX) {
+            return Err(Error::PCError(crate::pagecache::Error::Unsupported(
+                "bucket names starting with __index__\\0 are reserved for indexes".into(),
+            )));
+        }
+
+        Self::open_inner(context, name, guard).map(|(bucket, _)| bucket)
+    }
+
+    /// Same as `open`, also tells whether the bucket was just created
+    fn open_inner(context: Context, name: Key, guard: &Guard) -> IResult<(Self, bool)> {
+        let prefix = index_prefix(&name);
+        let unregistered = context
+            .meta(guard)?
+            .bucket
+            .range(prefix.clone()..)
+            .take_while(|(keyspace, _)| keyspace.starts_with(&prefix))
+            .map(|(keyspace, _)| keyspace[prefix.len()..].to_vec())
+            .collect();
+
+        let state = {
+            let mut buckets = context.buckets.lock();
+            match buckets.get(&name).and_then(Weak::upgrade) {
+                Some(state) => state,
+                None => {
+                    let state = Arc::new(BucketState {
+                        unregistered: Mutex::new(unregistered),
+                        ..BucketState::default()
+                    });
+                    buckets.insert(name.clone(), Arc::downgrade(&state));
+                    state
+                }
+            }
+        };
+
+        if let Some(id) = context.meta(guard)?.get_bucket(&name) {
+            return Ok((
+                Self {
+                    context,
+                    name,
+                    id,
+                    state,
+                },
+                false,
+            ));
+        }
+
+        let (id, _) = context.allocate(Node::new(None), guard)?;
+
+        let (id, created) = match context.cas_bucket_in_meta(&name, None, Some(id), guard)? {
+            Ok(()) => (id, true),
+            // created concurrently, use that one instead
+            Err(Some(existing)) => {
+                if let Some((ptr, _, _)) = context.get(id, guard)? {
+                    let _ = context.free(id, ptr, guard)?;
+                }
+                (existing, false)
+            }
+            Err(None) => {
+                return Err(Error::PCError(crate::pagecache::Error::ReportableBug(
+                    "bucket vanished from the meta page while being created".into(),
+                )))
+            }
+        };
+
+        Ok((
+            Self {
+                context,
+                name,
+                id,
+                state,
+            },
+            created,
+        ))
+    }
+
+    /// Name of this bucket
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// Get value
+    pub fn get(&self, key: impl AsRef<[u8]>) -> DBResult<Value> {
+        lookup(&self.context, self.id, key.as_ref(), &pin())
+    }
+
+    /// insert a value, returns old value
+    pub fn insert(&self, key: Key, value: Value) -> DBResult<Value> {
+        self.write(key, Entry::Value { value })
+    }
+
+    /// remove a value, returns old value
+    pub fn remove(&self, key: Key) -> DBResult<Value> {
+        self.write(key, Entry::Deletion)
+    }
+
+    /// Apply all the operations of `batch` atomically
+    pub fn apply_batch(&self, batch: Batch) -> IResult<()> {
+        self.apply_batch_with(batch, Durability::None)
+    }
+
+    /// Like `apply_batch`, but only returns once the batch has reached
+    /// `durability`
+    pub fn apply_batch_with(&self, batch: Batch, durability: Durability) -> IResult<()> {
+        if batch.ops.is_empty() {
+            return Ok(());
+        }
+
+        let lsn = {
+            let indexes = self.state.indexes.read();
+            self.check_registered()?;
+            if indexes.is_empty() {
+                self.link_ops(batch.ops)?
+            } else {
+                let _writer = self.state.writer.lock();
+                self.write_indexed(&indexes, batch.ops)?.1
+            }
+        };
+
+        if let Some(lsn) = lsn {
+            self.context.make_durable(lsn, durability)?;
+        }
+        Ok(())
+    }
+
+    /// Flush the pending writes to disk
+    pub fn flush(&self) -> IResult<()> {
+        self.context.flush()?;
+        Ok(())
+    }
+
+    /// Iterator over the bucket, which is just a (..) Range of it
+    pub fn iter(&self) -> Iter {
+        self.range(..)
+    }
+
+    /// Scan with some prefix
+    pub fn scan_prefix(&self, key: &Key) -> Iter {
+        let mut upper = key.to_vec();
+        while let Some(last) = upper.pop() {
+            if last < u8::MAX {
+                upper.push(last + 1);
+                return self.range(key..&upper);
+            }
+        }
+        self.range(key..)
+    }
+
+    /// Scan with key-range
+    pub fn range<R>(&self, range: R) -> Iter
+    where
+        R: RangeBounds<Key> + Clone,
+    {
+        Iter::new(self.context.clone(), self.id, None, range)
+    }
+
+    /// Create a secondary index named `name` over this bucket.
+    ///
+    /// The index lives in its own keyspace and is updated in the same atomic
+    /// batch as every later write to this bucket. Extractors are not
+    /// persisted, so the index has to be created again with the same
+    /// extractor after reopening the database, or once every handle of
+    /// this bucket has been dropped. It is only built from the
+    /// existing records the first time, use `rebuild_index` after changing
+    /// the extractor. Writes to a reopened bucket are refused until every
+    /// index it had is created again, or dropped with `drop_index`.
+    pub fn create_index<F>(&self, name: impl Into<Key>, extractor: F) -> IResult<Index>
+    where
+        F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let mut indexes = self.state.indexes.write();
+
+        if let Some(index) = indexes.iter().find(|index| index.name == name) {
+            return Ok(index.clone());
+        }
+
+        let guard = pin();
+        let (keyspace, created) =
+            Self::open_inner(self.context.clone(), self.index_keyspace(&name), &guard)?;
+
+        let index = Index {
+            name,
+            keyspace,
+            primary: self.clone_without_state(),
+            extractor: Arc::new(extractor),
+        };
+
+        if created {
+            index.build()?;
+        }
+
+        indexes.push(index.clone());
+        self.state
+            .unregistered
+            .lock()
+            .retain(|unregistered| unregistered != &index.name);
+
+        Ok(index)
+    }
+
+    /// The registered index named `name`
+    pub fn index(&self, name: &[u8]) -> Option<Index> {
+        self.state
+            .indexes
+            .read()
+            .iter()
+            .find(|index| index.name == name)
+            .cloned()
+    }
+
+    /// Throw away the content of the index named `name` and derive it again
+    /// from the records of this bucket
+    pub fn rebuild_index(&self, name: &[u8]) -> IResult<()> {
+        // keeps the writers out while rebuilding
+        let indexes = self.state.indexes.write();
+
+        match indexes.iter().find(|index| index.name == name) {
+            Some(index) => index.rebuild(),
+            None => Err(Error::PCError(crate::pagecache::Error::CollectionNotFound(
+                name.to_vec(),
+            ))),
+        }
+    }
+
+    /// Remove the index named `name` along with its entries, whether or not
+    /// it has been created since opening
+    pub fn drop_index(&self, name: &[u8]) -> IResult<()> {
+        let mut indexes = self.state.indexes.write();
+        indexes.retain(|index| index.name != name);
+        self.state
+            .unregistered
+            .lock()
+            .retain(|unregistered| unregistered != name);
+
+        let keyspace = self.index_keyspace(name);
+        self.context.buckets.lock().remove(&keyspace);
+
+        let guard = pin();
+        let id = match self.context.meta(&guard)?.get_bucket(&keyspace) {
+            Some(id) => id,
+            None => {
+                return Err(Error::PCError(crate::pagecache::Error::CollectionNotFound(
+                    name.to_vec(),
+                )))
+            }
+        };
+
+        if self
+            .context
+            .cas_bucket_in_meta(&keyspace, Some(id), None, &guard)?
+            .is_ok()
+        {
+            if let Some((ptr, _, _)) = self.context.get(id, &guard)? {
+                let _ = self.context.free(id, ptr, &guard)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index_keyspace(&self, name: &[u8]) -> Key {
+        let mut keyspace = index_prefix(&self.name);
+        keyspace.extend_from_slice(name);
+        keyspace
+    }
+
+    /// Refuse writes while some of the indexes of this bucket are not
+    /// maintained, the caller must hold the `indexes` read lock
+    fn check_registered(&self) -> IResult<()> {
+        match self.state.unregistered.lock().first() {
+            Some(name) => Err(Error::PCError(crate::pagecache::Error::Unsupported(
+                format!(
+                    "index {:?} of bucket {:?} has to be created again before writing",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(&self.name),
+                ),
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// A handle which does not keep the shared state alive, indexes hold one
+    /// of these to reach their primary records
+    fn clone_without_state(&self) -> Self {
+        Self {
+            context: self.context.clone(),
+            name: self.name.clone(),
+            id: self.id,
+            state: Arc::new(BucketState::default()),
+        }
+    }
+
+    fn write(&self, key: Key, entry: Entry) -> DBResult<Value> {
+        let indexes = self.state.indexes.read();
+        self.check_registered()?;
+        if !indexes.is_empty() {
+            let _writer = self.state.writer.lock();
+            let mut ops = BTreeMap::new();
+            ops.insert(key, entry);
+
+            return Ok(self.write_indexed(&indexes, ops)?.0.pop().flatten());
+        }
+
+        let guard = pin();
+        let mut node = Node::new(None);
+        node.inner.insert(key, entry);
+
+        let (mut ptr, mut page) = self.page(&guard)?;
+        loop {
+            // the key is the only one in the fragment
+            let old = match page.inner.get(node.inner.keys().next().unwrap()) {
+                Some(Entry::Value { value }) => Some(value.clone()),
+                _ => None,
+            };
+
+            match self.context.link(self.id, ptr, node, &guard)? {
+                Ok(_) => return Ok(old),
+                Err(Some((_, rejected))) => {
+                    node = rejected;
+                    let (current, current_page) = self.page(&guard)?;
+                    ptr = current;
+                    page = current_page;
+                }
+                Err(None) => return Err(self.missing()),
+            }
+        }
+    }
+
+    /// Write `ops` together with the index changes they cause as one atomic
+    /// batch, returns the previous values in key order. The caller must hold
+    /// the writer lock.
+    fn write_indexed(
+        &self,
+        indexes: &[Index],
+        ops: BTreeMap<Key, Entry>,
+    ) -> IResult<(Vec<Option<Value>>, Option<Lsn>)> {
+        let guard = pin();
+        let (_, page) = self.page(&guard)?;
+
+        let mut olds = Vec::with_capacity(ops.len());
+        let mut index_ops = vec![BTreeMap::new(); indexes.len()];
+
+        for (key, entry) in ops.iter() {
+            let old = match page.inner.get(key) {
+                Some(Entry::Value { value }) => Some(value.clone()),
+                _ => None,
+            };
+            let new = match entry {
+                Entry::Value { value } => Some(value),
+                Entry::Deletion => None,
+            };
+
+            for (index, ops) in indexes.iter().zip(index_ops.iter_mut()) {
+                let old_secondary = old.as_ref().and_then(|old| (index.extractor)(key, old));
+                let new_secondary = new.and_then(|new| (index.extractor)(key, new));
+
+                if old_secondary == new_secondary {
+                    continue;
+                }
+                if let Some(secondary) = old_secondary {
+                    ops.insert(index_key(&secondary, key), Entry::Deletion);
+                }
+                if let Some(secondary) = new_secondary {
+                    ops.insert(index_key(&secondary, key), Entry::Value { value: vec![] });
+                }
+            }
+
+            olds.push(old);
+        }
+
+        let batch = self.context.pin_log()?;
+
+        let mut lsn = self.link_ops(ops)?;
+        for (index, ops) in indexes.iter().zip(index_ops) {
+            lsn = lsn.max(index.keyspace.link_ops(ops)?);
+        }
+
+        batch.seal_batch()?;
+
+        Ok((olds, lsn))
+    }
+
+    /// Append `ops` to the page of this bucket, returning the Lsn they
+    /// were written at
+    fn link_ops(&self, ops: BTreeMap<Key, Entry>) -> IResult<Option<Lsn>> {
+        if ops.is_empty() {
+            return Ok(None);
+        }
+
+        let guard = pin();
+        let mut node = Node::new(None);
+        node.inner = ops;
+
+        let mut ptr = self.page(&guard)?.0;
+        loop {
+            match self.context.link(self.id, ptr, node, &guard)? {
+                Ok(new) => return Ok(Some(new.last_lsn())),
+                Err(Some((current, rejected))) => {
+                    ptr = current;
+                    node = rejected;
+                }
+                Err(None) => return Err(self.missing()),
+            }
+        }
+    }
+
+    fn page<'g>(
+        &self,
+        guard: &'g Guard,
+    ) -> IResult<(crate::pagecache::PagePtr<'g, Node>, &'g Node)> {
+        match self.context.get(self.id, guard)? {
+            Some((ptr, node, _)) => Ok((ptr, node)),
+            None => Err(self.missing()),
+        }
+    }
+
+    fn missing(&self) -> Error {
+        Error::PCError(crate::pagecache::Error::ReportableBug(format!(
+            "bucket pid {} is missing",
+            self.id
+        )))
+    }
+}
+
+/// A set of operations applied to a `Bucket` at once
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    pub(crate) ops: BTreeMap<Key, Entry>,
+}
+
+impl Batch {
+    /// Set a key to a value in this batch
+    pub fn insert(&mut self, key: Key, value: Value) {
+        self.ops.insert(key, Entry::Value { value });
+    }
+
+    /// Remove a key in this batch
+    pub fn remove(&mut self, key: Key) {
+        self.ops.insert(key, Entry::Deletion);
+    }
+
+    /// Whether this batch has no operations
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Keyspaces of the indexes of `bucket` are named by this followed by the
+/// index name
+fn index_prefix(bucket: &[u8]) -> Key {
+    let mut prefix = INDEX_PREFIX.to_vec();
+    codec::escape_into(&mut prefix, bucket);
+    prefix
+}
+
+/// The secondary key followed by the primary key, so that entries sort by
+/// the secondary key first
+fn index_key(secondary: &[u8], primary: &[u8]) -> Key {
+    let mut key = Vec::with_capacity(secondary.len() + primary.len() + 2);
+    codec::escape_into(&mut key, secondary);
+    key.extend_from_slice(primary);
+    key
+}
+
+/// The smallest key greater than every key starting with `prefix`, escaped
+/// prefixes end with a terminator so the last byte can always be bumped
+fn prefix_end(mut prefix: Key) -> Key {
+    let last = prefix.last_mut().expect("escaped keys are never empty");
+    *last += 1;
+    prefix
+}
+
+/// A secondary index over the records of a `Bucket`
+#[derive(Clone)]
+pub struct Index {
+    name: Key,
+    /// Where the index entries are stored
+    keyspace: Bucket,
+    /// The indexed bucket
+    primary: Bucket,
+    extractor: Extractor,
+}
+
+impl Index {
+    /// Name of this index
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    /// The primary records whose secondary key falls in `range`, ordered by
+    /// the secondary key and then the primary key
+    pub fn range<R>(&self, range: R) -> IndexIter
+    where
+        R: RangeBounds<Key>,
+    {
+        let escaped = |key: &Key| {
+            let mut out = vec![];
+            codec::escape_into(&mut out, key);
+            out
+        };
+
+        let lo = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(escaped(key)),
+            Bound::Excluded(key) => Bound::Included(prefix_end(escaped(key))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let hi = match range.end_bound() {
+            Bound::Included(key) => Bound::Excluded(prefix_end(escaped(key))),
+            Bound::Excluded(key) => Bound::Excluded(escaped(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        IndexIter {
+            inner: self.keyspace.range((lo, hi)),
+            primary: self.primary.clone(),
+        }
+    }
+
+    /// The primary records whose secondary key is `secondary`
+    pub fn get(&self, secondary: &[u8]) -> IndexIter {
+        self.range(secondary.to_vec()..=secondary.to_vec())
+    }
+
+    fn rebuild(&self) -> IResult<()> {
+        let mut clear = BTreeMap::new();
+        for res in self.keyspace.iter() {
+            let (key, _) = res?;
+            clear.insert(key, Entry::Deletion);
+        }
+        self.keyspace.link_ops(clear)?;
+
+        self.build()
+    }
+
+    fn build(&self) -> IResult<()> {
+        let mut ops = BTreeMap::new();
+        for res in self.primary.iter() {
+            let (key, value) = res?;
+            if let Some(secondary) = (self.extractor)(&key, &value) {
+                ops.insert(index_key(&secondary, &key), Entry::Value { value: vec![] });
+            }
+        }
+
+        self.keyspace.link_ops(ops)?;
+        Ok(())
+    }
+}
+
+/// An iterator over the primary records found through an `Index`
+pub struct IndexIter {
+    inner: Iter,
+    primary: Bucket,
+}
+
+impl Iterator for IndexIter {
+    type Item = IResult<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, _) = match self.inner.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let primary = match codec::unescape(&key) {
+                Ok((_, primary)) => primary.to_vec(),
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            match self.primary.get(&primary) {
+                Ok(Some(value)) => return Some(Ok((primary, value))),
+                // removed after the index entry has been read
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Database;
+
+    fn by_city(_key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        value
+            .iter()
+            .position(|b| *b == b'@')
+            .map(|at| value[at + 1..].to_vec())
+    }
+
+    fn primaries(iter: IndexIter) -> Vec<Key> {
+        iter.map(|res| res.map(|(k, _)| k))
+            .collect::<IResult<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_bucket_index() {
+        let db = Database::default();
+        let bucket = db.open_bucket(b"people".to_vec()).unwrap();
+
+        bucket
+            .insert(b"ann".to_vec(), b"ann@paris".to_vec())
+            .unwrap();
+        bucket
+            .insert(b"bob".to_vec(), b"bob@oslo".to_vec())
+            .unwrap();
+
+        // built from the existing records
+        let index = bucket.create_index(b"city".to_vec(), by_city).unwrap();
+        assert_eq!(primaries(index.get(b"paris")), vec![b"ann".to_vec()]);
+
+        let mut batch = Batch::default();
+        batch.insert(b"cid".to_vec(), b"cid@paris".to_vec());
+        batch.insert(b"dan".to_vec(), b"no city".to_vec());
+        bucket.apply_batch(batch).unwrap();
+
+        // moving a record updates its entry
+        bucket
+            .insert(b"bob".to_vec(), b"bob@lima".to_vec())
+            .unwrap();
+        bucket.remove(b"ann".to_vec()).unwrap();
+
+        assert_eq!(primaries(index.get(b"paris")), vec![b"cid".to_vec()]);
+        assert_eq!(primaries(index.get(b"oslo")), Vec::<Key>::new());
+        assert_eq!(
+            primaries(index.range(b"l".to_vec()..b"q".to_vec())),
+            vec![b"bob".to_vec(), b"cid".to_vec()]
+        );
+        assert_eq!(
+            primaries(index.range((Bound::Excluded(b"lima".to_vec()), Bound::Unbounded))),
+            vec![b"cid".to_vec()]
+        );
+
+        // other handles of the bucket maintain the index too
+        let other = db.open_bucket(b"people".to_vec()).unwrap();
+        other.insert(b"eve".to_vec(), b"eve@lima".to_vec()).unwrap();
+        assert_eq!(
+            primaries(index.get(b"lima")),
+            vec![b"bob".to_vec(), b"eve".to_vec()]
+        );
+
+        bucket.rebuild_index(b"city").unwrap();
+        assert_eq!(
+            primaries(index.range(..)),
+            vec![b"bob".to_vec(), b"eve".to_vec(), b"cid".to_vec()]
+        );
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_index_after_reopen() {
+        let path = std::env::temp_dir().join(format!("cloyster.index.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let db = Database::open(path.clone()).unwrap();
+        let bucket = db.open_bucket(b"people".to_vec()).unwrap();
+        bucket.create_index(b"city".to_vec(), by_city).unwrap();
+        bucket
+            .insert(b"ann".to_vec(), b"ann@paris".to_vec())
+            .unwrap();
+        let other = db.open_bucket(b"other".to_vec()).unwrap();
+        other.create_index(b"city".to_vec(), by_city).unwrap();
+        bucket.flush().unwrap();
+        drop((bucket, other, db));
+
+        let db = Database::open(path.clone()).unwrap();
+        assert!(db.open_bucket(INDEX_PREFIX.to_vec()).is_err());
+
+        // the index would miss these until it is created again
+        let bucket = db.open_bucket(b"people".to_vec()).unwrap();
+        assert!(bucket
+            .insert(b"bob".to_vec(), b"bob@paris".to_vec())
+            .is_err());
+        assert!(bucket.apply_batch(Batch::default()).is_ok());
+        let mut batch = Batch::default();
+        batch.remove(b"ann".to_vec());
+        assert!(bucket.apply_batch(batch).is_err());
+
+        let index = bucket.create_index(b"city".to_vec(), by_city).unwrap();
+        bucket
+            .insert(b"bob".to_vec(), b"bob@paris".to_vec())
+            .unwrap();
+        assert_eq!(
+            primaries(index.get(b"paris")),
+            vec![b"ann".to_vec(), b"bob".to_vec()]
+        );
+
+        // or dropped
+        let other = db.open_bucket(b"other".to_vec()).unwrap();
+        assert!(other.insert(b"cid".to_vec(), vec![]).is_err());
+        other.drop_index(b"city").unwrap();
+        other.insert(b"cid".to_vec(), vec![]).unwrap();
+        assert!(other.drop_index(b"city").is_err());
+        drop((index, bucket, other, db));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_batch_durability() {
+        let db = Database::default();
+        let bucket = db.open_bucket(b"durability".to_vec()).unwrap();
+        bucket.flush().unwrap();
+
+        let batch = |key: &[u8]| {
+            let mut batch = Batch::default();
+            batch.insert(key.to_vec(), b"value".to_vec());
+            batch
+        };
+
+        // left in the IO buffers for the next flush
+        bucket
+            .apply_batch_with(batch(b"a"), Durability::None)
+            .unwrap();
+        assert!(bucket.context.flush().unwrap() > 0);
+
+        // written out, but left for the next flush to sync
+        bucket
+            .apply_batch_with(batch(b"b"), Durability::Buffered)
+            .unwrap();
+        assert!(bucket.context.flush().unwrap() > 0);
+
+        // nothing is left over for an explicit flush
+        bucket
+            .apply_batch_with(batch(b"c"), Durability::Synced)
+            .unwrap();
+        assert_eq!(bucket.context.flush().unwrap(), 0);
+
+        let block = db.genesis().unwrap();
+        block.insert(b"d".to_vec(), b"value".to_vec()).unwrap();
+        assert!(block.commit_with(Durability::Synced).unwrap().is_created());
+        assert_eq!(bucket.context.flush().unwrap(), 0);
+    }
+}
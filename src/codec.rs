@@ -0,0 +1,831 @@
+//! Order-preserving key encoding.
+//!
+//! Any `Serialize` type can be turned into a byte key whose lexicographic
+//! order matches the logical order of the values, so `range` and
+//! `scan_prefix` work on structured keys:
+//!
+//! * integers are stored big-endian, signed ones with the sign bit flipped
+//! * floats are stored as their bits, flipped so negatives sort first
+//! * strings and byte slices escape `0x00` as `0x00 0xFF` and end with
+//!   `0x00 0x00`, so a prefix sorts before its extensions
+//! * tuples and structs are the concatenation of their fields
+//! * options, sequences and maps mark every element with `0x01` and end
+//!   with `0x00`
+//! * enum variants are prefixed with their index
+//!
+//! The encoding is not self-describing, so decoding needs the type.
+use serde::{
+    de::{self, DeserializeOwned, IntoDeserializer, Visitor},
+    ser, Serialize,
+};
+use std::fmt;
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x00;
+
+const END: u8 = 0x00;
+const MORE: u8 = 0x01;
+
+/// Encoding or decoding failure
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key codec error: {}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+/// Result of key encoding
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Encode `value` into an order-preserving byte key
+pub fn to_key<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut serializer = Serializer { out: vec![] };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+/// Decode a byte key produced by `to_key`
+pub fn from_key<T: DeserializeOwned>(raw: &[u8]) -> Result<T> {
+    let mut deserializer = Deserializer { input: raw };
+    let value = T::deserialize(&mut deserializer)?;
+    if deserializer.input.is_empty() {
+        Ok(value)
+    } else {
+        Err(Error(format!(
+            "{} trailing bytes after the key",
+            deserializer.input.len()
+        )))
+    }
+}
+
+/// Append `bytes` to `out` escaped and terminated, the encoding of a byte
+/// slice. Keys built this way can be followed by more data and still sort by
+/// `bytes` first.
+pub(crate) fn escape_into(out: &mut Vec<u8>, bytes: &[u8]) {
+    Serializer::escape(out, bytes)
+}
+
+/// Split an escaped byte slice from the front of `raw`, returning it and the
+/// remaining bytes
+pub(crate) fn unescape(raw: &[u8]) -> Result<(Vec<u8>, &[u8])> {
+    let mut deserializer = Deserializer { input: raw };
+    let bytes = deserializer.take_escaped()?;
+    Ok((bytes, deserializer.input))
+}
+
+struct Serializer {
+    out: Vec<u8>,
+}
+
+impl Serializer {
+    fn escape(out: &mut Vec<u8>, bytes: &[u8]) {
+        for byte in bytes {
+            out.push(*byte);
+            if *byte == ESCAPE {
+                out.push(ESCAPED_ZERO);
+            }
+        }
+        out.push(ESCAPE);
+        out.push(TERMINATOR);
+    }
+
+    fn write_escaped(&mut self, bytes: &[u8]) {
+        Self::escape(&mut self.out, bytes)
+    }
+}
+
+impl ser::Serializer for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.out.push(v as u8);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_u8((v as u8) ^ (1 << 7))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_u16((v as u16) ^ (1 << 15))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_u32((v as u32) ^ (1 << 31))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.serialize_u64((v as u64) ^ (1 << 63))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        self.serialize_u128((v as u128) ^ (1 << 127))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.out.push(v);
+        Ok(())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        self.out.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        let bits = v.to_bits();
+        let bits = if bits >> 31 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 31)
+        };
+        self.serialize_u32(bits)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        let bits = v.to_bits();
+        let bits = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 63)
+        };
+        self.serialize_u64(bits)
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_escaped(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_escaped(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.out.push(END);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        self.out.push(MORE);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.out.push(MORE);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.out.push(MORE);
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        self.out.push(END);
+        Ok(())
+    }
+}
+
+macro_rules! concatenated {
+    ($trait:ident, $method:ident) => {
+        impl ser::$trait for &mut Serializer {
+            type Ok = ();
+            type Error = Error;
+
+            fn $method<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<()> {
+                Ok(())
+            }
+        }
+    };
+}
+
+concatenated!(SerializeTuple, serialize_element);
+concatenated!(SerializeTupleStruct, serialize_field);
+concatenated!(SerializeTupleVariant, serialize_field);
+
+impl ser::SerializeStruct for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Serializer {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.input.len() < N {
+            return Err(Error("unexpected end of key".into()));
+        }
+        let mut buf = [0; N];
+        buf.copy_from_slice(&self.input[..N]);
+        self.input = &self.input[N..];
+        Ok(buf)
+    }
+
+    fn take_byte(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn take_marker(&mut self) -> Result<bool> {
+        match self.take_byte()? {
+            MORE => Ok(true),
+            END => Ok(false),
+            other => Err(Error(format!("invalid element marker {:#04x}", other))),
+        }
+    }
+
+    fn take_escaped(&mut self) -> Result<Vec<u8>> {
+        let mut out = vec![];
+        loop {
+            match self.take_byte()? {
+                ESCAPE => match self.take_byte()? {
+                    TERMINATOR => return Ok(out),
+                    ESCAPED_ZERO => out.push(0),
+                    other => return Err(Error(format!("invalid escape {:#04x}", other))),
+                },
+                byte => out.push(byte),
+            }
+        }
+    }
+
+    fn take_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn take_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(Error("keys are not self-describing".into()))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.take_byte()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            other => Err(Error(format!("invalid bool {:#04x}", other))),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i8((self.take_byte()? ^ (1 << 7)) as i8)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i16((u16::from_be_bytes(self.take()?) ^ (1 << 15)) as i16)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i32((self.take_u32()? ^ (1 << 31)) as i32)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i64((self.take_u64()? ^ (1 << 63)) as i64)
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_i128((u128::from_be_bytes(self.take()?) ^ (1 << 127)) as i128)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u8(self.take_byte()?)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u16(u16::from_be_bytes(self.take()?))
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.take_u32()?)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u64(self.take_u64()?)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u128(u128::from_be_bytes(self.take()?))
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = self.take_u32()?;
+        let bits = if bits >> 31 == 1 {
+            bits ^ (1 << 31)
+        } else {
+            !bits
+        };
+        visitor.visit_f32(f32::from_bits(bits))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let bits = self.take_u64()?;
+        let bits = if bits >> 63 == 1 {
+            bits ^ (1 << 63)
+        } else {
+            !bits
+        };
+        visitor.visit_f64(f64::from_bits(bits))
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let raw = self.take_u32()?;
+        match std::char::from_u32(raw) {
+            Some(c) => visitor.visit_char(c),
+            None => Err(Error(format!("invalid char {:#x}", raw))),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match String::from_utf8(self.take_escaped()?) {
+            Ok(s) => visitor.visit_string(s),
+            Err(e) => Err(Error(e.to_string())),
+        }
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_byte_buf(self.take_escaped()?)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.take_marker()? {
+            visitor.visit_some(self)
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Marked { de: self })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        visitor.visit_seq(Fixed { de: self, len })
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_map(Marked { de: self })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_enum(self)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_u32(self.take_u32()?)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value> {
+        Err(Error("keys are not self-describing".into()))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Elements preceded by a marker, for sequences and maps
+struct Marked<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Marked<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        if self.de.take_marker()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Marked<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.de.take_marker()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+/// A known number of concatenated elements, for tuples and structs
+struct Fixed<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    len: usize,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Fixed<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        if self.len == 0 {
+            return Ok(None);
+        }
+        self.len -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let index: de::value::U32Deserializer<Error> = self.take_u32()?.into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use serde::Deserialize;
+    use std::fmt::Debug;
+
+    fn assert_ordered<T>(mut values: Vec<T>)
+    where
+        T: Serialize + DeserializeOwned + PartialOrd + Debug,
+    {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let keys = values
+            .iter()
+            .map(|v| to_key(v).unwrap())
+            .collect::<Vec<_>>();
+
+        for (window, values) in keys.windows(2).zip(values.windows(2)) {
+            assert_eq!(
+                window[0].cmp(&window[1]),
+                values[0].partial_cmp(&values[1]).unwrap(),
+                "{:?} and {:?} are encoded out of order",
+                values[0],
+                values[1]
+            );
+        }
+
+        for (key, value) in keys.iter().zip(values.iter()) {
+            assert_eq!(&from_key::<T>(key).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_codec_integers() {
+        let mut rng = StdRng::seed_from_u64(29);
+
+        let mut signed = vec![i64::MIN, -1, 0, 1, i64::MAX];
+        signed.extend((0..256).map(|_| rng.gen::<i64>()));
+        assert_ordered(signed);
+
+        assert_ordered(vec![i8::MIN, -1i8, 0, 1, i8::MAX]);
+        assert_ordered((0..256).map(|_| rng.gen::<u32>()).collect());
+        assert_ordered(vec![
+            f64::NEG_INFINITY,
+            -2.5,
+            -0.0,
+            0.5,
+            1e300,
+            f64::INFINITY,
+        ]);
+    }
+
+    #[test]
+    fn test_codec_strings_and_bytes() {
+        assert_ordered(vec![
+            String::new(),
+            "\0".to_string(),
+            "\0\0".to_string(),
+            "a".to_string(),
+            "a\0".to_string(),
+            "a\0b".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+
+        let mut rng = StdRng::seed_from_u64(30);
+        assert_ordered(
+            (0..256)
+                .map(|_| {
+                    let len = rng.gen_range(0..6);
+                    (0..len).map(|_| rng.gen_range(0..3u8)).collect::<Vec<_>>()
+                })
+                .collect(),
+        );
+    }
+
+    #[test]
+    fn test_codec_tuples() {
+        assert_ordered(vec![
+            ("".to_string(), -5i32),
+            ("".to_string(), 7),
+            ("a".to_string(), -1),
+            ("a".to_string(), 0),
+            ("a\0".to_string(), i32::MIN),
+            ("ab".to_string(), 3),
+        ]);
+
+        #[derive(Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+        enum Kind {
+            Unit,
+            Id(u16),
+            Named { name: String, at: i64 },
+        }
+
+        assert_ordered(vec![
+            (Kind::Unit, None),
+            (Kind::Id(3), Some(true)),
+            (Kind::Id(300), None),
+            (
+                Kind::Named {
+                    name: "x".into(),
+                    at: -3,
+                },
+                Some(false),
+            ),
+        ]);
+
+        assert_ordered(vec![vec![], vec![1u8], vec![1, 0], vec![2]]);
+    }
+
+    #[test]
+    fn test_codec_decode_errors() {
+        assert!(from_key::<u64>(&[0, 1]).is_err());
+        assert!(from_key::<u8>(&[0, 1]).is_err());
+        assert!(from_key::<String>(b"abc").is_err());
+    }
+}
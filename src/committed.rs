@@ -0,0 +1,153 @@
+use crate::{context::Context, iter::*, node::Node, pagecache::PageId, prelude::*, tree::*};
+use std::ops::RangeBounds;
+
+/// A block which has been committed and can no longer change.
+///
+/// Reads go straight to the pagecache since there are no pending changes to
+/// look at, new changes are made on a `fork()` of it.
+#[derive(Clone)]
+pub struct CommittedBlock {
+    pub(crate) context: Context,
+    /// Table ID
+    pub(crate) id: PageId,
+    hash: Hash,
+}
+
+impl CommittedBlock {
+    /// Open the committed block stored in page `id`
+    pub(crate) fn open(context: Context, id: PageId, guard: &Guard) -> DBResult<Self> {
+        let hash = match context.get(id, guard)? {
+            Some((_, node, _)) => node.hash,
+            None => return Ok(None),
+        };
+
+        Ok(hash.map(|raw| Self {
+            context,
+            id,
+            hash: raw.into(),
+        }))
+    }
+
+    /// Hash of this block
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// The preceded block
+    pub fn prev(&self) -> DBResult<Self> {
+        let guard = pin();
+        let page = self.context.get(self.id, &guard)?;
+
+        match page.and_then(|(_, node, _)| node.prev) {
+            Some(prev) => Self::open(self.context.clone(), prev, &guard),
+            None => Ok(None),
+        }
+    }
+
+    /// Start a new writable block on top of this one
+    pub fn fork(&self) -> IResult<TreeBlock> {
+        let guard = pin();
+
+        TreeBlock::new(self.context.clone(), Some(self.id), &guard)
+    }
+
+    /// Get value
+    pub fn get(&self, key: impl AsRef<[u8]>) -> DBResult<Value> {
+        lookup(&self.context, self.id, key.as_ref(), &pin())
+    }
+
+    /// Everything needed to check the content of this block against its hash
+    pub fn proof(&self) -> IResult<Proof> {
+        let guard = pin();
+        let node = self.node(&guard)?;
+
+        let prev = match node.prev {
+            Some(prev) => self
+                .context
+                .get(prev, &guard)?
+                .and_then(|(_, node, _)| node.hash),
+            None => None,
+        };
+
+        let entries = node
+            .inner
+            .iter()
+            .map(|(k, v)| match v {
+                Entry::Value { value } => (k.clone(), Some(value.clone())),
+                Entry::Deletion => (k.clone(), None),
+            })
+            .collect();
+
+        Ok(Proof {
+            prev: prev.map(Into::into),
+            entries,
+        })
+    }
+
+    /// Check the stored content of this block against its hash
+    pub fn verify(&self) -> IResult<bool> {
+        Ok(self.proof()?.verify(&self.hash))
+    }
+
+    /// Iterator over database, which is just a (..) Range of db
+    pub fn iter(&self) -> Iter {
+        self.range(..)
+    }
+
+    /// Scan with some prefix
+    pub fn scan_prefix(&self, key: &Key) -> Iter {
+        let mut upper = key.to_vec();
+        while let Some(last) = upper.pop() {
+            if last < u8::MAX {
+                upper.push(last + 1);
+                return self.range(key..&upper);
+            }
+        }
+        self.range(key..)
+    }
+
+    /// Scan with key-range
+    pub fn range<R>(&self, range: R) -> Iter
+    where
+        R: RangeBounds<Key> + Clone,
+    {
+        Iter::new(self.context.clone(), self.id, None, range)
+    }
+
+    fn node<'g>(&self, guard: &'g Guard) -> IResult<&'g Node> {
+        match self.context.get(self.id, guard)? {
+            Some((_, node, _)) => Ok(node),
+            None => Err(Error::PCError(crate::pagecache::Error::ReportableBug(
+                format!("committed block pid {} is missing", self.id),
+            ))),
+        }
+    }
+}
+
+/// The changes made by a block together with the hash of its preceded
+/// block, which is exactly what the block hash is computed from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proof {
+    /// hash of the preceded block
+    pub prev: Option<Hash>,
+    /// values written by the block in key order, `None` for deletions
+    pub entries: Vec<(Key, Option<Value>)>,
+}
+
+impl Proof {
+    /// Whether this proof hashes to `hash`
+    pub fn verify(&self, hash: &Hash) -> bool {
+        let root =
+            crate::hasher::calc_root(self.prev, self.entries.iter().map(|(k, v)| (k, v.as_ref())));
+
+        &root == hash
+    }
+
+    /// The value of `key` written by the proven block
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.entries
+            .binary_search_by(|(k, _)| k.as_slice().cmp(key))
+            .ok()
+            .and_then(|idx| self.entries[idx].1.as_ref())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::sync::*;
+use crate::{pagecache::Storage, sync::*};
 /// Configuration of this K-V store
 use std::path::PathBuf;
 
@@ -7,7 +7,19 @@ pub struct Config(Arc<ConfigInner>);
 
 impl Config {
     pub fn new(path: Option<PathBuf>) -> Self {
-        let inner = ConfigInner { path };
+        let inner = ConfigInner {
+            path,
+            storage: None,
+        };
+        Self(Arc::new(inner))
+    }
+
+    /// Keep the data in `storage` instead of the files under a path
+    pub fn with_storage(storage: Arc<dyn crate::pagecache::StorageBackend>) -> Self {
+        let inner = ConfigInner {
+            path: None,
+            storage: Some(Storage(storage)),
+        };
         Self(Arc::new(inner))
     }
 }
@@ -23,10 +35,15 @@ impl std::ops::Deref for Config {
 pub struct ConfigInner {
     /// Path to data position
     pub path: Option<PathBuf>,
+    /// Storage used instead of the path
+    pub storage: Option<Storage>,
 }
 
 impl Default for ConfigInner {
     fn default() -> Self {
-        Self { path: None }
+        Self {
+            path: None,
+            storage: None,
+        }
     }
 }
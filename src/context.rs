@@ -1,10 +1,12 @@
 use crate::{
+    bucket::BucketState,
     config::*,
     node::Node,
     pagecache::{ConfigBuilder, PageCache},
     prelude::*,
+    sync::*,
 };
-use std::ops::Deref;
+use std::{collections::HashMap, ops::Deref, sync::Weak};
 
 #[derive(Clone)]
 pub struct Context {
@@ -12,6 +14,9 @@ pub struct Context {
     pub config: Config,
     /// Pagecache for persistence
     pub pagecache: PageCache<Node>,
+    /// In-memory state shared by every handle of the same bucket, it
+    /// holds indexes which hold a `Context`, so only handles keep it alive
+    pub(crate) buckets: Arc<Mutex<HashMap<Key, Weak<BucketState>>>>,
 }
 
 impl Deref for Context {
@@ -23,7 +28,9 @@ impl Deref for Context {
 
 impl Context {
     pub fn new(config: Config) -> IResult<Self> {
-        let pc = if let Some(ref path) = config.path {
+        let pc = if let Some(ref storage) = config.storage {
+            ConfigBuilder::new().storage(storage.0.clone()).build()
+        } else if let Some(ref path) = config.path {
             ConfigBuilder::new().path(path).build()
         } else {
             ConfigBuilder::new().temporary(true).build()
@@ -31,6 +38,10 @@ impl Context {
 
         let pagecache = PageCache::start(pc)?;
 
-        Ok(Self { config, pagecache })
+        Ok(Self {
+            config,
+            pagecache,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        })
     }
 }
@@ -1,10 +1,13 @@
 #![allow(unused)]
-use crate::{config::*, context::*, ds::stack::*, iter::*, pagecache::Meta, prelude::*, tree::*};
+use crate::{
+    committed::*, config::*, context::*, ds::stack::*, iter::*, pagecache::Meta, prelude::*,
+    tree::*,
+};
 /// K-V Store Implementation
 use std::{
     collections::{BTreeMap, HashMap},
     ops::RangeBounds,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
@@ -14,7 +17,7 @@ pub struct Database {
     /// Database Configuration
     config: Config,
     /// Context for Database
-    context: Context,
+    pub(crate) context: Context,
     // trees: Arc<RwLock<HashMap<Key, TreeBlock>>>,
 }
 
@@ -33,17 +36,22 @@ impl Database {
         Ok(Self { config, context })
     }
 
-    // This is used for common k-v store
-    pub fn open_bucket(&self, _keyspace: Key) -> DBResult<TreeBlock> {
-        todo!()
+    /// Open the bucket named `keyspace`, creating it if it does not exist.
+    /// This is used for common k-v store
+    pub fn open_bucket(&self, keyspace: Key) -> IResult<Bucket> {
+        let guard = pin();
+
+        Bucket::open(self.context.clone(), keyspace, &guard)
     }
 
-    pub fn open_block(&self, hash: &Hash) -> DBResult<TreeBlock> {
+    /// Open a committed block by its hash, use `CommittedBlock::fork` to
+    /// build a new block on top of it
+    pub fn open_block(&self, hash: &Hash) -> DBResult<CommittedBlock> {
         let guard = pin();
 
         let meta = self.context.meta(&guard)?;
         if let Some(id) = meta.get_block(hash.as_bytes()) {
-            TreeBlock::new(self.context.clone(), Some(id), &guard).map(Option::Some)
+            CommittedBlock::open(self.context.clone(), id, &guard)
         } else {
             Ok(None)
         }
@@ -58,6 +66,25 @@ impl Database {
     pub fn path(&self) -> PathBuf {
         self.context.path()
     }
+
+    /// Write a consistent copy of the database into `path` without
+    /// stopping writers. The copy opens like any other database directory.
+    pub fn backup_to<P: AsRef<Path>>(&self, path: P) -> IResult<()> {
+        self.context.checkpoint(path)?;
+        Ok(())
+    }
+
+    /// Make everything written so far durable, resolving once it is on
+    /// disk instead of blocking the calling thread.
+    #[cfg(feature = "async")]
+    pub fn flush_async(&self) -> impl std::future::Future<Output = IResult<()>> {
+        let flushed = self.context.flush_async();
+
+        async move {
+            flushed.await?;
+            Ok(())
+        }
+    }
 }
 
 impl Default for Database {
@@ -73,6 +100,8 @@ mod compile_time_assertions {
     #[allow(unreachable_code)]
     fn assert_database_send_sync() {
         _assert_send_sync::<TreeBlock>(unreachable!());
+        _assert_send_sync::<CommittedBlock>(unreachable!());
+        _assert_send_sync::<Bucket>(unreachable!());
         _assert_send_sync::<Database>(unreachable!());
     }
 
@@ -80,3 +109,211 @@ mod compile_time_assertions {
 
     fn _assert_send_sync<S: Send + Sync>(_: &S) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::{self, SimulatedDisk, StorageBackend};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    type Model = BTreeMap<Key, Value>;
+
+    // What the harness knows about a committed hash.
+    struct Committed {
+        state: Model,
+        // must resolve after any crash from here on
+        durable: bool,
+    }
+
+    enum Crash {
+        // lose power while this many log writes from now is in flight
+        OnWrite(usize),
+        // lose power between two operations
+        Now,
+        // cut the log at a random point past everything made durable
+        Truncate,
+    }
+
+    struct Torture {
+        seed: u64,
+        rng: StdRng,
+        disk: Arc<SimulatedDisk>,
+        db: Database,
+        // by the bytes of the hash, to keep the order deterministic
+        committed: BTreeMap<[u8; 32], Committed>,
+        // uncommitted blocks and the state they should hold
+        working: Vec<(TreeBlock, Model)>,
+        // the log is durable up to here, as of the last flush or synced
+        // commit. Recovery never moves what is below it.
+        durable_len: u64,
+    }
+
+    impl Torture {
+        fn new(seed: u64) -> Torture {
+            let disk = Arc::new(SimulatedDisk::new(seed));
+            let db = Database::new(Config::with_storage(disk.clone())).unwrap();
+            Torture {
+                seed,
+                rng: StdRng::seed_from_u64(seed),
+                disk,
+                db,
+                committed: BTreeMap::new(),
+                working: vec![],
+                durable_len: 0,
+            }
+        }
+
+        fn check(&self, hash: &Hash, state: &Model) {
+            let block = self.db.open_block(hash).unwrap().unwrap_or_else(|| {
+                panic!("seed {}: block {} is gone", self.seed, hash);
+            });
+            let found: Model = block.iter().map(|kv| kv.unwrap()).collect();
+            assert_eq!(&found, state, "seed {}: block {} differs", self.seed, hash);
+        }
+
+        fn random_commit(&mut self) -> Option<Hash> {
+            if self.committed.is_empty() {
+                return None;
+            }
+            let i = self.rng.gen_range(0..self.committed.len());
+            self.committed.keys().nth(i).map(|hash| Hash::from(*hash))
+        }
+
+        // runs one random operation against both the database and the model
+        fn step(&mut self) {
+            let op = self.rng.gen_range(0..100);
+            let key = vec![self.rng.gen_range(0..24u8)];
+
+            if self.working.is_empty() || op < 5 {
+                let block = self.db.genesis().unwrap();
+                self.working.push((block, Model::new()));
+            } else if op < 15 {
+                // open_block: committed state must be readable, and can be built on
+                if let Some(hash) = self.random_commit() {
+                    let state = self.committed[hash.as_bytes()].state.clone();
+                    self.check(&hash, &state);
+                    let block = self.db.open_block(&hash).unwrap().unwrap();
+                    self.working.push((block.fork().unwrap(), state));
+                }
+            } else if op < 55 {
+                let i = self.rng.gen_range(0..self.working.len());
+                let len = if self.rng.gen_ratio(1, 16) { 4096 } else { 64 };
+                let value: Value = (0..self.rng.gen_range(1..len))
+                    .map(|_| self.rng.gen())
+                    .collect();
+                let (block, model) = &mut self.working[i];
+                block.insert(key.clone(), value.clone()).unwrap();
+                model.insert(key.clone(), value);
+                assert_eq!(block.get(&key).unwrap().as_ref(), model.get(&key));
+            } else if op < 75 {
+                let i = self.rng.gen_range(0..self.working.len());
+                let (block, model) = &mut self.working[i];
+                block.delete(key.clone()).unwrap();
+                model.remove(&key);
+                assert_eq!(block.get(&key).unwrap(), None);
+            } else if op < 95 {
+                let i = self.rng.gen_range(0..self.working.len());
+                let (block, state) = self.working.swap_remove(i);
+                let durability = match self.rng.gen_range(0..3) {
+                    0 => Durability::None,
+                    1 => Durability::Buffered,
+                    _ => Durability::Synced,
+                };
+                let hash = block.commit_with(durability).unwrap().hash();
+                let durable = durability == Durability::Synced;
+                let committed = self.committed.entry(*hash.as_bytes()).or_insert(Committed {
+                    state: state.clone(),
+                    durable,
+                });
+                assert_eq!(committed.state, state, "seed {}: hash collision", self.seed);
+                committed.durable |= durable;
+                if durable {
+                    self.durable_len = self.disk.log_len().unwrap();
+                }
+            } else {
+                self.db.context.flush().unwrap();
+                for committed in self.committed.values_mut() {
+                    committed.durable = true;
+                }
+                self.durable_len = self.disk.log_len().unwrap();
+            }
+        }
+
+        // runs until the crash, then reopens and checks what survived
+        fn crash(&mut self, crash: Crash, ops: usize) {
+            if let Crash::OnWrite(n) = crash {
+                self.disk.crash_on_write(n);
+            }
+
+            let mut crashed = None;
+            for _ in 0..ops {
+                let durable: Vec<[u8; 32]> = self
+                    .committed
+                    .iter()
+                    .filter(|(_, committed)| committed.durable)
+                    .map(|(hash, _)| *hash)
+                    .collect();
+                self.step();
+                if let Some(disk) = self.disk.crashed() {
+                    // whatever this op made durable may not have been yet
+                    for committed in self.committed.values_mut() {
+                        committed.durable = false;
+                    }
+                    for hash in durable {
+                        self.committed.get_mut(&hash).unwrap().durable = true;
+                    }
+                    crashed = Some(Arc::new(disk));
+                    break;
+                }
+            }
+
+            self.working.clear();
+            let disk = match crash {
+                Crash::OnWrite(_) => crashed.unwrap_or_else(|| Arc::new(self.disk.crash())),
+                Crash::Now => Arc::new(self.disk.crash()),
+                Crash::Truncate => {
+                    let db = std::mem::take(&mut self.db);
+                    drop(db);
+
+                    let len = self.disk.log_len().unwrap();
+                    let cut = self.rng.gen_range(self.durable_len.min(len)..=len);
+                    let config = pagecache::ConfigBuilder::new()
+                        .storage(self.disk.clone())
+                        .build();
+                    config.truncate_corrupt(cut);
+                    self.disk.clone()
+                }
+            };
+            self.db = Database::new(Config::with_storage(disk.clone())).unwrap();
+            self.disk = disk;
+
+            let hashes: Vec<[u8; 32]> = self.committed.keys().copied().collect();
+            for bytes in hashes {
+                let hash = Hash::from(bytes);
+                let committed = &self.committed[&bytes];
+                if committed.durable || self.db.open_block(&hash).unwrap().is_some() {
+                    // all there, or not at all
+                    self.check(&hash, &committed.state);
+                } else {
+                    self.committed.remove(&bytes);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_crash_recovery_torture() {
+        for seed in 0..24 {
+            let mut torture = Torture::new(seed);
+            for round in 0..4 {
+                let crash = match (seed + round) % 3 {
+                    0 => Crash::OnWrite(torture.rng.gen_range(1..16)),
+                    1 => Crash::Now,
+                    _ => Crash::Truncate,
+                };
+                torture.crash(crash, 96);
+            }
+        }
+    }
+}
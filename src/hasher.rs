@@ -1,16 +1,29 @@
 use blake3::{Hash, Hasher};
 
+/// Hash of a block: the hash of its preceded block and every key it
+/// writes, with `None` standing for a deletion. Keys and values are
+/// length prefixed so that neither can be shifted into the other.
 pub fn calc_root<'a>(
     prev: Option<Hash>,
-    kvs: impl Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>,
+    kvs: impl Iterator<Item = (&'a Vec<u8>, Option<&'a Vec<u8>>)>,
 ) -> Hash {
     let mut hasher = Hasher::new();
     if let Some(hash) = prev {
         hasher.update(hash.as_bytes());
     }
     for (key, value) in kvs {
-        hasher.update(&key);
-        hasher.update(&value);
+        hasher.update(&(key.len() as u64).to_le_bytes());
+        hasher.update(key);
+        match value {
+            Some(value) => {
+                hasher.update(&[1]);
+                hasher.update(&(value.len() as u64).to_le_bytes());
+                hasher.update(value);
+            }
+            None => {
+                hasher.update(&[0]);
+            }
+        }
     }
     hasher.finalize()
 }
@@ -1,11 +1,18 @@
 // Iterator over kv-store
-use crate::{prelude::*, tree::*};
+use crate::{context::Context, pagecache::PageId, prelude::*, sync::*, tree::*};
 use binary_heap_plus::{BinaryHeap, MinComparator};
-use std::ops::{Bound, RangeBounds};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+};
 
 /// An iterator over keys and values in this K-V store
 pub struct Iter {
-    pub(crate) block: TreeBlock,
+    pub(crate) context: Context,
+    /// the newest block of the chain
+    pub(crate) id: PageId,
+    /// uncommitted changes, committed blocks have none
+    pub(crate) cookie: Option<Arc<RwLock<BTreeMap<Key, Entry>>>>,
     pub(crate) heap: BinaryHeap<Key, MinComparator>,
     prev: Option<Key>,
     pub(crate) hi: Bound<Key>,
@@ -14,11 +21,15 @@ pub struct Iter {
 }
 
 impl Iter {
-    pub fn new<R: Clone>(block: TreeBlock, range: R) -> Self
+    pub(crate) fn new<R: Clone>(
+        context: Context,
+        id: PageId,
+        cookie: Option<Arc<RwLock<BTreeMap<Key, Entry>>>>,
+        range: R,
+    ) -> Self
     where
         R: RangeBounds<Key>,
     {
-        let id = block.id;
         let lo = range.start_bound().cloned();
         let hi = range.end_bound().cloned();
         let guard = pin();
@@ -27,20 +38,21 @@ impl Iter {
         let mut heap = BinaryHeap::<Key, MinComparator>::new_min();
 
         // #1. from cookie
-        let cookie = block.cookie.read();
-        for (k, _) in cookie.range(range.clone()) {
-            heap.push(k.clone());
+        if let Some(ref cookie) = cookie {
+            for (k, _) in cookie.read().range(range.clone()) {
+                heap.push(k.clone());
+            }
         }
 
         // TODO: check the boundary of block to enhance this Iterator
         // #2. from chainned block
-        let (_, mut node, _) = block.context.get(id, &guard).unwrap().unwrap();
+        let (_, mut node, _) = context.get(id, &guard).unwrap().unwrap();
         for (k, _) in node.inner.range(range.clone()) {
             heap.push(k.clone());
         }
 
         while let Some(prev) = node.prev {
-            let (_, prev_node, _) = block.context.get(prev, &guard).unwrap().unwrap();
+            let (_, prev_node, _) = context.get(prev, &guard).unwrap().unwrap();
             for (k, _) in prev_node.inner.range(range.clone()) {
                 heap.push(k.clone());
             }
@@ -49,7 +61,9 @@ impl Iter {
         }
 
         Iter {
-            block: block.clone(),
+            context,
+            id,
+            cookie,
             heap,
             prev: None,
             lo,
@@ -58,6 +72,18 @@ impl Iter {
         }
     }
 
+    fn get(&self, key: &[u8]) -> DBResult<Value> {
+        if let Some(ref cookie) = self.cookie {
+            match cookie.read().get(key) {
+                Some(Entry::Value { value }) => return Ok(Some(value.clone())),
+                Some(Entry::Deletion) => return Ok(None),
+                None => {}
+            }
+        }
+
+        lookup(&self.context, self.id, key, &self.guard)
+    }
+
     fn bounds_collapsed(&self) -> bool {
         match (&self.lo, &self.hi) {
             (Bound::Included(ref start), Bound::Included(ref end))
@@ -86,7 +112,7 @@ impl Iter {
 
             self.prev.replace(key.clone());
 
-            match self.block.get(&key) {
+            match self.get(&key) {
                 Ok(Some(value)) => return Some(Ok((key, value))),
                 // maybe a Deletion
                 Ok(None) => {}
@@ -106,10 +132,7 @@ impl Iter {
         }
 
         if let Some(key) = self.prev.take() {
-            self.block
-                .get(&key)
-                .map(|v| v.map(|v| (key, v)))
-                .transpose()
+            self.get(&key).map(|v| v.map(|v| (key, v))).transpose()
         } else {
             None
         }
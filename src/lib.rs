@@ -9,7 +9,14 @@ pub mod prelude {
     pub use super::Error;
     pub use blake3::{Hash, Hasher};
 
-    pub use super::{config::Config, tree::TreeBlock};
+    pub use super::{
+        bucket::{Batch, Bucket, Index, IndexIter},
+        committed::{CommittedBlock, Proof},
+        config::Config,
+        pagecache::Durability,
+        tree::{Commit, TreeBlock},
+        typed::{TypedBlock, TypedBucket, TypedIter},
+    };
 
     pub use crossbeam_epoch::{
         pin, unprotected, Atomic, Collector, Guard, LocalHandle, Owned, Shared,
@@ -49,6 +56,9 @@ mod atomic {
 }
 
 mod block;
+mod bucket;
+pub mod codec;
+mod committed;
 mod config;
 mod context;
 mod database;
@@ -58,7 +68,10 @@ mod iter;
 mod lock;
 mod node;
 pub mod pagecache;
+#[cfg(any(feature = "async", test))]
+mod pool;
 mod tree;
+mod typed;
 
 pub use database::Database;
 
@@ -66,6 +79,8 @@ pub use database::Database;
 pub enum Error {
     IOError(#[from] std::io::Error),
     PCError(#[from] pagecache::Error),
+    SerdeError(#[from] bincode::Error),
+    KeyError(#[from] codec::Error),
     CommitedState,
 }
 
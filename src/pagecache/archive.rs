@@ -0,0 +1,348 @@
+//! Incremental archives of the log.
+//!
+//! An archive is a sequence of frames, each holding a bincode encoded
+//! item prefixed by its length and followed by its crc32. The first
+//! frame is an `ArchiveHeader`, the last one an `Entry::End`, and
+//! every message in between carries its full payload, including the
+//! contents of blobs.
+use std::io::{self, Read, Write};
+
+use super::{checkpoint::PauseRewriting, *};
+
+const ARCHIVE_MAGIC: [u8; 8] = *b"CLYSTLOG";
+const ARCHIVE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveHeader {
+    magic: [u8; 8],
+    version: u32,
+    since: Lsn,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum ArchivedKind {
+    Replace,
+    Append,
+    Free,
+}
+
+impl From<ArchivedKind> for LogKind {
+    fn from(kind: ArchivedKind) -> Self {
+        match kind {
+            ArchivedKind::Replace => LogKind::Replace,
+            ArchivedKind::Append => LogKind::Append,
+            ArchivedKind::Free => LogKind::Free,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Message {
+        kind: ArchivedKind,
+        pid: PageId,
+        lsn: Lsn,
+        data: Vec<u8>,
+    },
+    End {
+        last_lsn: Lsn,
+        messages: u64,
+    },
+}
+
+/// Write every message of `log` after `since` into `writer`. Returns
+/// the Lsn of the last message written, to be passed as `since` to the
+/// next export.
+pub(super) fn export<W: Write>(log: &Log, since: Lsn, mut writer: W) -> Result<Lsn> {
+    log.flush()?;
+
+    // segments are read straight from the file, keep them in place
+    let _paused = PauseRewriting::new(&log.iobufs);
+
+    write_frame(
+        &mut writer,
+        &ArchiveHeader {
+            magic: ARCHIVE_MAGIC,
+            version: ARCHIVE_VERSION,
+            since,
+        },
+    )?;
+
+    // `since` may point anywhere into a message, so start at the
+    // beginning of its segment and skip what was exported before.
+    let segment_len = log.config.io_buf_size as Lsn;
+    let start = std::cmp::max(since, 0) / segment_len * segment_len;
+
+    let mut last_lsn = since;
+    let mut messages = 0;
+
+    for (log_kind, pid, lsn, ptr, _) in log.iter_from(start) {
+        if lsn <= since {
+            continue;
+        }
+
+        let kind = match log_kind {
+            LogKind::Replace => ArchivedKind::Replace,
+            LogKind::Append => ArchivedKind::Append,
+            LogKind::Free => ArchivedKind::Free,
+            LogKind::Skip => continue,
+            LogKind::Corrupted => return Err(Error::Corruption { at: ptr }),
+        };
+
+        let data = match log.read(pid, lsn, ptr) {
+            Ok(LogRead::Inline(_, buf, _)) | Ok(LogRead::Blob(_, buf, _)) => buf,
+            // blobs are only removed once a later message replaces
+            // or frees their page, and that one is exported too.
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+            Ok(other) => {
+                return Err(Error::ReportableBug(format!(
+                    "unexpected {:?} while exporting lsn {}",
+                    other, lsn
+                )));
+            }
+        };
+
+        write_frame(
+            &mut writer,
+            &Entry::Message {
+                kind,
+                pid,
+                lsn,
+                data,
+            },
+        )?;
+
+        last_lsn = lsn;
+        messages += 1;
+    }
+
+    write_frame(&mut writer, &Entry::End { last_lsn, messages })?;
+    writer.flush()?;
+
+    Ok(last_lsn)
+}
+
+/// An archive read back into memory.
+pub(super) struct Archive {
+    /// The Lsn the archive was exported since.
+    pub(super) since: Lsn,
+    /// The archived messages, in log order.
+    pub(super) messages: Vec<(LogKind, PageId, Lsn, Vec<u8>)>,
+    /// The Lsn to export from next.
+    pub(super) last_lsn: Lsn,
+}
+
+/// Read a whole archive, making sure that it is complete.
+pub(super) fn read_archive<R: Read>(mut reader: R) -> Result<Archive> {
+    let since = read_header(&mut reader)?;
+
+    let mut messages = vec![];
+    loop {
+        match read_frame(&mut reader)? {
+            Entry::Message {
+                kind,
+                pid,
+                lsn,
+                data,
+            } => messages.push((kind.into(), pid, lsn, data)),
+            Entry::End {
+                last_lsn,
+                messages: count,
+            } => {
+                if count != messages.len() as u64 {
+                    return Err(invalid_data("archive lost some of its messages").into());
+                }
+
+                return Ok(Archive {
+                    since,
+                    messages,
+                    last_lsn,
+                });
+            }
+        }
+    }
+}
+
+/// Append the messages of an archive to the log of the (closed)
+/// pagecache at `config`. Returns the Lsn to export from next.
+///
+/// The archive is applied as one write batch: if it turns out to be
+/// truncated or corrupt, none of it is recovered.
+pub(super) fn import<R: Read>(config: &Config, mut reader: R) -> Result<Lsn> {
+    read_header(&mut reader)?;
+
+    let snapshot = read_snapshot_or_default(config)?;
+    let log = Log::start(config.clone(), snapshot)?;
+
+    let mut batch = log.reserve(
+        LogKind::Skip,
+        BATCH_MANIFEST_PID,
+        &[0; std::mem::size_of::<Lsn>()],
+    )?;
+
+    let res = apply_entries(&log, &mut reader);
+
+    // a failed import leaves a manifest that recovery can never reach
+    // the end of, which discards everything written after it.
+    let batch_lsn = match res {
+        Ok(_) => log.iobufs.max_reserved_lsn.load(Acquire),
+        Err(_) => Lsn::MAX,
+    };
+    batch.mark_writebatch(batch_lsn);
+    batch.complete()?;
+    log.flush()?;
+
+    res
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<Lsn> {
+    let header: ArchiveHeader = read_frame(reader)?;
+    if header.magic != ARCHIVE_MAGIC || header.version != ARCHIVE_VERSION {
+        return Err(Error::Unsupported(
+            "not a log archive, or one written by an \
+             incompatible version"
+                .into(),
+        ));
+    }
+
+    Ok(header.since)
+}
+
+fn apply_entries<R: Read>(log: &Log, reader: &mut R) -> Result<Lsn> {
+    let mut applied = 0;
+
+    loop {
+        match read_frame(reader)? {
+            Entry::Message {
+                kind, pid, data, ..
+            } => {
+                log.reserve(kind.into(), pid, &data)?.complete()?;
+                applied += 1;
+            }
+            Entry::End { last_lsn, messages } => {
+                if messages != applied {
+                    return Err(invalid_data("archive lost some of its messages").into());
+                }
+
+                return Ok(last_lsn);
+            }
+        }
+    }
+}
+
+fn write_frame<W: Write, T: Serialize>(writer: &mut W, item: &T) -> Result<()> {
+    let buf = serialize(item).unwrap();
+
+    writer.write_all(&u64_to_arr(buf.len() as u64))?;
+    writer.write_all(&buf)?;
+    writer.write_all(&u32_to_arr(crc32(&buf)))?;
+
+    Ok(())
+}
+
+fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<T> {
+    let mut len_buf = [0; 8];
+    reader.read_exact(&mut len_buf)?;
+    let len = arr_to_u64(&len_buf);
+
+    // don't trust the length with an allocation before the crc is checked
+    let mut buf = vec![];
+    reader.by_ref().take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    let mut crc_buf = [0; 4];
+    reader.read_exact(&mut crc_buf)?;
+    if arr_to_u32(&crc_buf) != crc32(&buf) {
+        return Err(invalid_data("archive frame failed its crc check").into());
+    }
+
+    deserialize(&buf).map_err(|e| invalid_data(&e.to_string()).into())
+}
+
+fn invalid_data(why: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    fn page(key: &[u8], len: usize) -> Page {
+        let mut page = BTreeMap::new();
+        page.insert(key.to_vec(), vec![key[0]; len]);
+        page
+    }
+
+    #[test]
+    fn test_incremental_export_import() {
+        let base = std::env::temp_dir().join(format!("cloyster.archive.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let config = |dir: &str| {
+            ConfigBuilder::new()
+                .path(base.join(dir))
+                .io_buf_size(4096)
+                .build()
+        };
+
+        let pc = PageCache::<Page>::start(config("src")).unwrap();
+        let guard = pin();
+        let (kept, _) = pc.allocate(page(b"a", 8), &guard).unwrap();
+        let (freed, _) = pc.allocate(page(b"b", 8), &guard).unwrap();
+        let cursor = pc.checkpoint(base.join("dst")).unwrap();
+
+        // an update, a large page stored as a blob, and a free
+        let (ptr, _, _) = pc.get(kept, &guard).unwrap().unwrap();
+        pc.link(kept, ptr, page(b"c", 8), &guard).unwrap().unwrap();
+        let (blob, _) = pc.allocate(page(b"d", 2048), &guard).unwrap();
+        let (ptr, _, _) = pc.get(freed, &guard).unwrap().unwrap();
+        pc.free(freed, ptr, &guard).unwrap().unwrap();
+
+        let mut first = vec![];
+        let cursor = pc.export_log_since(cursor, &mut first).unwrap();
+
+        let (ptr, _, _) = pc.get(blob, &guard).unwrap().unwrap();
+        pc.replace(blob, ptr, page(b"e", 8), &guard)
+            .unwrap()
+            .unwrap();
+
+        let mut second = vec![];
+        pc.export_log_since(cursor, &mut second).unwrap();
+        drop(guard);
+        drop(pc);
+
+        // a truncated archive is rejected and leaves no trace
+        let truncated = &first[..first.len() - 1];
+        assert!(PageCache::<Page>::import(config("dst"), truncated).is_err());
+
+        let restored = PageCache::<Page>::start(config("dst")).unwrap();
+        let guard = pin();
+        let (_, kept_page, _) = restored.get(kept, &guard).unwrap().unwrap();
+        assert_eq!(kept_page, &page(b"a", 8));
+        drop(guard);
+        drop(restored);
+
+        PageCache::<Page>::import(config("dst"), &first[..]).unwrap();
+        PageCache::<Page>::import(config("dst"), &second[..]).unwrap();
+
+        let restored = PageCache::<Page>::start(config("dst")).unwrap();
+        let guard = pin();
+        let (_, kept_page, _) = restored.get(kept, &guard).unwrap().unwrap();
+        let mut expected = page(b"a", 8);
+        expected.extend(page(b"c", 8));
+        assert_eq!(kept_page, &expected);
+        assert!(restored.get(freed, &guard).unwrap().is_none());
+        let (_, blob_page, _) = restored.get(blob, &guard).unwrap().unwrap();
+        assert_eq!(blob_page, &page(b"e", 8));
+        drop(guard);
+        drop(restored);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
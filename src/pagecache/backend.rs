@@ -0,0 +1,440 @@
+//! Where a `PageCache` keeps its bytes.
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use parking_lot::{Mutex, RwLock};
+
+use super::*;
+
+/// The storage underneath a `PageCache`: the log, the blobs holding
+/// values too large to be inlined into it, and the page table snapshots.
+///
+/// By default a `PageCache` uses the file layout under
+/// `ConfigBuilder::path`, and temporary ones are kept in a
+/// `MemoryBackend`. Anything else can be plugged in with
+/// `ConfigBuilder::storage`.
+pub trait StorageBackend: Debug + Send + Sync {
+    /// Reads exactly `buf.len()` bytes of the log at `offset`.
+    fn read_log(&self, buf: &mut [u8], offset: LogId) -> io::Result<()>;
+
+    /// Writes all of `buf` to the log at `offset`.
+    fn write_log(&self, buf: &[u8], offset: LogId) -> io::Result<()>;
+
+    /// Makes every log write so far durable.
+    fn sync_log(&self) -> io::Result<()>;
+
+    /// The length of the log in bytes.
+    fn log_len(&self) -> io::Result<u64>;
+
+    /// Cuts the log off at `len` bytes.
+    fn truncate_log(&self, len: u64) -> io::Result<()>;
+
+    /// A hint that `len` bytes of the log at `offset` are about to be
+    /// read in sequence.
+    fn will_read_log(&self, _offset: LogId, _len: u64) {}
+
+    /// The file descriptor of the log, if it is a plain file that IO
+    /// buffers can be written to through io_uring instead of
+    /// `write_log`.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    fn log_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+
+    /// Stores a blob, which is never written twice.
+    fn put_blob(&self, lsn: Lsn, data: &[u8]) -> io::Result<()>;
+
+    /// Reads a blob back, failing with `NotFound` if there is none.
+    fn get_blob(&self, lsn: Lsn) -> io::Result<Vec<u8>>;
+
+    /// Removes a blob.
+    fn delete_blob(&self, lsn: Lsn) -> io::Result<()>;
+
+    /// The blobs stored, with their sizes in bytes.
+    fn list_blobs(&self) -> io::Result<Vec<(Lsn, u64)>>;
+
+    /// Stores a snapshot. It must not show up in `list_snapshots`
+    /// until it has been stored completely.
+    fn put_snapshot(&self, lsn: Lsn, data: &[u8]) -> io::Result<()>;
+
+    /// Reads a snapshot back, failing with `NotFound` if there is none.
+    fn get_snapshot(&self, lsn: Lsn) -> io::Result<Vec<u8>>;
+
+    /// Removes a snapshot.
+    fn delete_snapshot(&self, lsn: Lsn) -> io::Result<()>;
+
+    /// The snapshots stored, with their sizes in bytes.
+    fn list_snapshots(&self) -> io::Result<Vec<(Lsn, u64)>>;
+}
+
+/// A `StorageBackend` shared between `ConfigBuilder`s, compared by
+/// identity.
+#[derive(Clone, Debug)]
+pub struct Storage(pub Arc<dyn StorageBackend>);
+
+impl PartialEq for Storage {
+    fn eq(&self, other: &Storage) -> bool {
+        Arc::as_ptr(&self.0) as *const u8 == Arc::as_ptr(&other.0) as *const u8
+    }
+}
+
+pub(crate) fn snapshot_file_name(lsn: Lsn) -> String {
+    format!("snap.{:016X}", lsn)
+}
+
+pub(super) fn not_found(what: &str, lsn: Lsn) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("no {} at lsn {}", what, lsn),
+    )
+}
+
+/// The log in `<path>/db`, blobs in `<path>/blobs/<lsn>` and snapshots
+/// in `<snapshot prefix>/snap.<lsn as hex>`.
+#[derive(Debug)]
+pub(crate) struct FileBackend {
+    file: File,
+    log_writer: Option<LogWriter>,
+    dsync: bool,
+    blob_dir: PathBuf,
+    snapshot_dir: PathBuf,
+}
+
+impl FileBackend {
+    pub(crate) fn new(
+        file: File,
+        log_writer: Option<LogWriter>,
+        config: &ConfigBuilder,
+    ) -> FileBackend {
+        FileBackend {
+            file,
+            log_writer,
+            dsync: config.dsync_io,
+            blob_dir: config.get_path().join("blobs"),
+            snapshot_dir: config
+                .snapshot_path
+                .clone()
+                .unwrap_or_else(|| config.get_path()),
+        }
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn read_log(&self, buf: &mut [u8], offset: LogId) -> io::Result<()> {
+        match &self.log_writer {
+            Some(writer) => writer.pread_exact(buf, offset),
+            None => self.file.pread_exact(buf, offset),
+        }
+    }
+
+    fn write_log(&self, buf: &[u8], offset: LogId) -> io::Result<()> {
+        match &self.log_writer {
+            Some(writer) => writer.pwrite_all(buf, offset),
+            None => self.file.pwrite_all(buf, offset),
+        }
+    }
+
+    fn sync_log(&self) -> io::Result<()> {
+        // O_DSYNC writes are durable once they return
+        if self.dsync {
+            return Ok(());
+        }
+        // a growing file's length is synced along with its data,
+        // only the timestamps are left behind
+        self.file.sync_data()
+    }
+
+    fn log_len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn truncate_log(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn will_read_log(&self, offset: LogId, len: u64) {
+        use std::os::unix::io::AsRawFd;
+
+        let ret = unsafe {
+            libc::posix_fadvise(
+                self.file.as_raw_fd(),
+                libc::off_t::try_from(offset).unwrap(),
+                libc::off_t::try_from(len).unwrap(),
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if ret != 0 {
+            panic!(
+                "failed to call fadvise: {}",
+                io::Error::from_raw_os_error(ret)
+            );
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    fn log_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.log_writer.as_ref().and_then(LogWriter::uring_fd)
+    }
+
+    fn put_blob(&self, lsn: Lsn, data: &[u8]) -> io::Result<()> {
+        let path = self.blob_dir.join(lsn.to_string());
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        f.write_all(data)?;
+        f.sync_all()
+    }
+
+    fn get_blob(&self, lsn: Lsn) -> io::Result<Vec<u8>> {
+        fs::read(self.blob_dir.join(lsn.to_string()))
+    }
+
+    fn delete_blob(&self, lsn: Lsn) -> io::Result<()> {
+        fs::remove_file(self.blob_dir.join(lsn.to_string()))
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<(Lsn, u64)>> {
+        let mut blobs = vec![];
+        for entry in fs::read_dir(&self.blob_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let lsn = name.to_str().and_then(|name| name.parse().ok());
+            match lsn {
+                Some(lsn) => blobs.push((lsn, entry.metadata()?.len())),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("blobs directory contains unparsable path {:?}", name),
+                    ))
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    fn put_snapshot(&self, lsn: Lsn, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.snapshot_dir)?;
+
+        let path = self.snapshot_dir.join(snapshot_file_name(lsn));
+        let generating = self
+            .snapshot_dir
+            .join(format!("{}.generating", snapshot_file_name(lsn)));
+
+        // leftovers of snapshots interrupted by a crash
+        for entry in fs::read_dir(&self.snapshot_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("snap.") && name.ends_with(".generating") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&generating)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        drop(f);
+
+        trace!("wrote snapshot to {:?}", generating);
+        fs::rename(&generating, &path)
+    }
+
+    fn get_snapshot(&self, lsn: Lsn) -> io::Result<Vec<u8>> {
+        fs::read(self.snapshot_dir.join(snapshot_file_name(lsn)))
+    }
+
+    fn delete_snapshot(&self, lsn: Lsn) -> io::Result<()> {
+        fs::remove_file(self.snapshot_dir.join(snapshot_file_name(lsn)))
+    }
+
+    fn list_snapshots(&self) -> io::Result<Vec<(Lsn, u64)>> {
+        let entries = match fs::read_dir(&self.snapshot_dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut snapshots = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let lsn = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("snap."))
+                .and_then(|hex| Lsn::from_str_radix(hex, 16).ok());
+            if let Some(lsn) = lsn {
+                match entry.metadata() {
+                    Ok(metadata) => snapshots.push((lsn, metadata.len())),
+                    // removed since listing the directory
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(snapshots)
+    }
+}
+
+/// Keeps everything in memory, so nothing survives the process. Used
+/// for temporary `PageCache`s.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    log: RwLock<Vec<u8>>,
+    blobs: Mutex<BTreeMap<Lsn, Vec<u8>>>,
+    snapshots: Mutex<BTreeMap<Lsn, Vec<u8>>>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read_log(&self, buf: &mut [u8], offset: LogId) -> io::Result<()> {
+        let log = self.log.read();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        match log.get(start..).and_then(|tail| tail.get(..buf.len())) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            )),
+        }
+    }
+
+    fn write_log(&self, buf: &[u8], offset: LogId) -> io::Result<()> {
+        let mut log = self.log.write();
+        let start = assert_usize(offset);
+        let end = start + buf.len();
+        if log.len() < end {
+            log.resize(end, 0);
+        }
+        log[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn sync_log(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn log_len(&self) -> io::Result<u64> {
+        Ok(self.log.read().len() as u64)
+    }
+
+    fn truncate_log(&self, len: u64) -> io::Result<()> {
+        self.log.write().resize(assert_usize(len), 0);
+        Ok(())
+    }
+
+    fn put_blob(&self, lsn: Lsn, data: &[u8]) -> io::Result<()> {
+        let mut blobs = self.blobs.lock();
+        if blobs.contains_key(&lsn) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("blob at lsn {} already exists", lsn),
+            ));
+        }
+        blobs.insert(lsn, data.to_vec());
+        Ok(())
+    }
+
+    fn get_blob(&self, lsn: Lsn) -> io::Result<Vec<u8>> {
+        self.blobs
+            .lock()
+            .get(&lsn)
+            .cloned()
+            .ok_or_else(|| not_found("blob", lsn))
+    }
+
+    fn delete_blob(&self, lsn: Lsn) -> io::Result<()> {
+        self.blobs
+            .lock()
+            .remove(&lsn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("blob", lsn))
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<(Lsn, u64)>> {
+        let blobs = self.blobs.lock();
+        Ok(blobs
+            .iter()
+            .map(|(lsn, blob)| (*lsn, blob.len() as u64))
+            .collect())
+    }
+
+    fn put_snapshot(&self, lsn: Lsn, data: &[u8]) -> io::Result<()> {
+        self.snapshots.lock().insert(lsn, data.to_vec());
+        Ok(())
+    }
+
+    fn get_snapshot(&self, lsn: Lsn) -> io::Result<Vec<u8>> {
+        self.snapshots
+            .lock()
+            .get(&lsn)
+            .cloned()
+            .ok_or_else(|| not_found("snapshot", lsn))
+    }
+
+    fn delete_snapshot(&self, lsn: Lsn) -> io::Result<()> {
+        self.snapshots
+            .lock()
+            .remove(&lsn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("snapshot", lsn))
+    }
+
+    fn list_snapshots(&self) -> io::Result<Vec<(Lsn, u64)>> {
+        let snapshots = self.snapshots.lock();
+        Ok(snapshots
+            .iter()
+            .map(|(lsn, snapshot)| (*lsn, snapshot.len() as u64))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+
+    type Page = BTreeMap<Key, Key>;
+
+    #[test]
+    fn test_memory_backend_restart() {
+        let path = std::env::temp_dir().join(format!("cloyster.memory.{}", std::process::id()));
+        let storage = Arc::new(MemoryBackend::default());
+        let config = || ConfigBuilder::new().path(&path).io_buf_size(8192);
+
+        // large enough to be stored as a blob
+        let mut page = BTreeMap::new();
+        page.insert(b"k".to_vec(), vec![7; 8192]);
+
+        let pc = PageCache::<Page>::start_with_storage(config(), storage.clone()).unwrap();
+        let guard = pin();
+        let (pid, _) = pc.allocate(page.clone(), &guard).unwrap();
+        pc.flush().unwrap();
+        drop(guard);
+        drop(pc);
+
+        assert!(!storage.list_blobs().unwrap().is_empty());
+
+        // restarting takes a snapshot
+        let pc = PageCache::<Page>::start_with_storage(config(), storage.clone()).unwrap();
+        let guard = pin();
+        assert_eq!(pc.get(pid, &guard).unwrap().unwrap().1, &page);
+        drop(guard);
+        drop(pc);
+
+        assert!(!storage.list_snapshots().unwrap().is_empty());
+        assert!(!path.exists());
+    }
+}
@@ -1,60 +1,48 @@
-use std::io::{Read, Write};
-
 use super::*;
 
 pub(crate) fn read_blob(blob_ptr: Lsn, config: &Config) -> Result<(MessageKind, Vec<u8>)> {
-    let path = config.blob_path(blob_ptr);
-    let f_res = std::fs::OpenOptions::new().read(true).open(&path);
-
-    if let Err(e) = &f_res {
-        debug!("failed to open file for blob read at {}: {:?}", blob_ptr, e);
-    }
-
-    let mut f = f_res?;
-
-    let mut crc_expected_bytes = [0_u8; std::mem::size_of::<u32>()];
-
-    if let Err(e) = f.read_exact(&mut crc_expected_bytes) {
-        debug!(
-            "failed to read the initial CRC bytes in the blob at {}: {:?}",
-            blob_ptr, e,
-        );
-        return Err(e.into());
-    }
-
-    let crc_expected = arr_to_u32(&crc_expected_bytes);
-
-    let mut kind_byte = [0_u8];
-
-    if let Err(e) = f.read_exact(&mut kind_byte) {
-        debug!(
-            "failed to read the initial CRC bytes in the blob at {}: {:?}",
-            blob_ptr, e,
-        );
-        return Err(e.into());
+    let mut buf = match config.storage.get_blob(blob_ptr) {
+        Ok(buf) => buf,
+        Err(e) => {
+            debug!("failed to read blob at {}: {:?}", blob_ptr, e);
+            return Err(e.into());
+        }
+    };
+
+    // the checksum bytes, followed by the kind byte
+    let checksum_len = config.checksum.len();
+    let header_len = checksum_len + 1;
+    if buf.len() < header_len {
+        warn!("blob {} is too short to hold its header", blob_ptr);
+        return Err(Error::Corruption {
+            at: DiskPtr::Blob(0, blob_ptr),
+        });
     }
 
-    let mut buf = vec![];
-    if let Err(e) = f.read_to_end(&mut buf) {
-        debug!(
-            "failed to read data after the CRC bytes in blob at {}: {:?}",
-            blob_ptr, e,
-        );
-        return Err(e.into());
-    }
+    let mut digest_expected = Digest::default();
+    digest_expected[..checksum_len].copy_from_slice(&buf[..checksum_len]);
+    let kind_byte = buf[header_len - 1];
+    let buf = buf.split_off(header_len);
 
-    let mut hasher = crc32fast::Hasher::new();
-    hasher.update(&kind_byte);
+    let mut hasher = config.checksum.hasher();
+    hasher.update(&[kind_byte]);
     hasher.update(&buf);
-    let crc_actual = hasher.finalize();
 
-    if crc_expected == crc_actual {
+    if digest_expected == hasher.finalize() {
+        let buf = match &config.keys {
+            Some(keys) => keys
+                .open(Domain::Blob, blob_ptr, &[kind_byte], buf)?
+                .ok_or(Error::Corruption {
+                    at: DiskPtr::Blob(0, blob_ptr),
+                })?,
+            None => buf,
+        };
         let buf = if config.use_compression {
             maybe_decompress(buf)?
         } else {
             buf
         };
-        Ok((MessageKind::from(kind_byte[0]), buf))
+        Ok((MessageKind::from(kind_byte), buf))
     } else {
         warn!("blob {} failed crc check!", blob_ptr);
 
@@ -64,62 +52,67 @@ pub(crate) fn read_blob(blob_ptr: Lsn, config: &Config) -> Result<(MessageKind,
     }
 }
 
-pub(crate) fn write_blob(config: &Config, kind: MessageKind, id: Lsn, data: &[u8]) -> Result<()> {
-    let path = config.blob_path(id);
-    let mut f = std::fs::OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&path)?;
+/// The id of the key the blob was sealed with, if encrypted.
+pub(crate) fn blob_key_id(blob_ptr: Lsn, config: &Config) -> Result<Option<KeyId>> {
+    if config.keys.is_none() {
+        return Ok(None);
+    }
+    let buf = config.storage.get_blob(blob_ptr)?;
 
+    // the key id follows the checksum bytes and the kind byte
+    let start = config.checksum.len() + 1;
+    match buf.get(start..start + KEY_ID_LEN) {
+        Some(id) => Ok(Some(arr_to_u32(id))),
+        None => Err(Error::Corruption {
+            at: DiskPtr::Blob(0, blob_ptr),
+        }),
+    }
+}
+
+pub(crate) fn write_blob(config: &Config, kind: MessageKind, id: Lsn, data: &[u8]) -> Result<()> {
     let kind_buf = &[kind.into()];
 
-    let mut hasher = crc32fast::Hasher::new();
+    let sealed;
+    let data = match &config.keys {
+        Some(keys) => {
+            sealed = keys.seal(Domain::Blob, id, kind_buf, data)?;
+            &sealed[..]
+        }
+        None => data,
+    };
+
+    let mut hasher = config.checksum.hasher();
     hasher.update(kind_buf);
     hasher.update(data);
-    let crc = u32_to_arr(hasher.finalize());
-
-    f.write_all(&crc)
-        .and_then(|_| f.write_all(kind_buf))
-        .and_then(|_| f.write_all(data))
-        .map(|r| {
-            trace!("successfully wrote blob at {:?}", path);
-            r
-        })
-        .map_err(|e| e.into())
+    let digest = hasher.finalize();
+    let digest = &digest[..config.checksum.len()];
+
+    let mut blob = Vec::with_capacity(digest.len() + kind_buf.len() + data.len());
+    blob.extend_from_slice(digest);
+    blob.extend_from_slice(kind_buf);
+    blob.extend_from_slice(data);
+
+    config.storage.put_blob(id, &blob)?;
+    trace!("successfully wrote blob at {}", id);
+    Ok(())
 }
 
 pub(crate) fn gc_blobs(config: &Config, stable_lsn: Lsn) -> Result<()> {
-    let stable = config.blob_path(stable_lsn);
-    let blob_dir = stable.parent().unwrap();
-    let blobs = std::fs::read_dir(blob_dir)?;
+    let blobs = config.storage.list_blobs()?;
 
     debug!(
         "gc_blobs removing any blob with an lsn above {}",
         stable_lsn
     );
 
-    for blob in blobs {
-        let path = blob?.path();
-        let lsn_str = path.file_name().unwrap().to_str().unwrap();
-        let lsn_res: std::result::Result<Lsn, _> = lsn_str.parse();
-
-        if let Err(e) = lsn_res {
-            return Err(Error::Unsupported(format!(
-                "blobs directory contains \
-                 unparsable path ({:?}): {}",
-                path, e
-            )));
-        }
-
-        let lsn = lsn_res.unwrap();
-
+    for (lsn, _) in blobs {
         if lsn > stable_lsn {
             warn!(
-                "removing blob {:?} that has \
-                 a higher lsn than our stable log: {:?}",
-                path, stable
+                "removing blob {} that has \
+                 a higher lsn than our stable log: {}",
+                lsn, stable_lsn
             );
-            std::fs::remove_file(&path)?;
+            config.storage.delete_blob(lsn)?;
         }
     }
 
@@ -127,12 +120,10 @@ pub(crate) fn gc_blobs(config: &Config, stable_lsn: Lsn) -> Result<()> {
 }
 
 pub(crate) fn remove_blob(id: Lsn, config: &Config) -> Result<()> {
-    let path = config.blob_path(id);
-
-    if let Err(e) = std::fs::remove_file(&path) {
-        debug!("removing blob at {:?} failed: {}", path, e);
+    if let Err(e) = config.storage.delete_blob(id) {
+        debug!("removing blob at {} failed: {}", id, e);
     } else {
-        trace!("successfully removed blob at {:?}", path);
+        trace!("successfully removed blob at {}", id);
     }
 
     // TODO return a future
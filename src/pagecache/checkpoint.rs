@@ -0,0 +1,179 @@
+//! Online copies of a database directory.
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use super::*;
+
+/// Keeps segment rewriting paused while alive, so the log only grows at
+/// its tip and the existing segments can be copied without tearing.
+pub(super) struct PauseRewriting<'a>(&'a IoBufs);
+
+impl<'a> PauseRewriting<'a> {
+    pub(super) fn new(iobufs: &'a IoBufs) -> Self {
+        iobufs.with_sa(SegmentAccountant::pause_rewriting);
+        PauseRewriting(iobufs)
+    }
+}
+
+impl Drop for PauseRewriting<'_> {
+    fn drop(&mut self) {
+        self.0.with_sa(SegmentAccountant::resume_rewriting);
+    }
+}
+
+/// Copy the database of `config` into `dir`, which must be empty or not
+/// exist, in the file layout of the default storage. The caller must keep
+/// snapshots from being written while this runs. Returns the stable Lsn
+/// the copy is guaranteed to contain.
+///
+/// Writes may continue meanwhile: with rewriting paused they only land in
+/// the segments being filled or at the end of the log, and anything torn
+/// by the copy is newer than the returned Lsn and is cut off by recovery.
+pub(super) fn checkpoint(config: &Config, iobufs: &Arc<IoBufs>, dir: &Path) -> Result<Lsn> {
+    if dir.exists() && dir.read_dir()?.next().is_some() {
+        return Err(Error::Unsupported(format!(
+            "checkpoint directory {:?} is not empty",
+            dir
+        )));
+    }
+
+    let blob_dir = dir.join("blobs");
+    fs::create_dir_all(&blob_dir)?;
+
+    iobuf::flush(iobufs)?;
+    let stable = iobufs.stable();
+
+    let _paused = PauseRewriting::new(iobufs);
+
+    config.write_config_at(&dir.join("config"))?;
+
+    let storage = &config.storage;
+    for (lsn, _) in storage.list_snapshots()? {
+        match storage.get_snapshot(lsn) {
+            Ok(snapshot) => write_file(&dir.join(snapshot_file_name(lsn)), &snapshot)?,
+            // removed since listing them
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // blobs are immutable, but may be removed once the pages using them
+    // are rewritten. copy them before copying the log so the ones it
+    // refers to are still around, and once more afterwards for the blobs
+    // written in the meantime.
+    copy_blobs(&**storage, &blob_dir)?;
+    copy_log(&**storage, &dir.join("db"), config.io_buf_size)?;
+    copy_blobs(&**storage, &blob_dir)?;
+
+    File::open(&blob_dir)?.sync_all()?;
+    File::open(dir)?.sync_all()?;
+
+    Ok(stable)
+}
+
+fn write_file(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+
+    f.write_all(buf)?;
+    f.sync_all()
+}
+
+fn copy_log(storage: &dyn StorageBackend, to: &Path, chunk: usize) -> io::Result<()> {
+    let mut dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+
+    let len = storage.log_len()?;
+    let mut buf = vec![0; chunk];
+    let mut offset = 0;
+    while offset < len {
+        let n = std::cmp::min(chunk as u64, len - offset) as usize;
+        storage.read_log(&mut buf[..n], offset)?;
+        dst.write_all(&buf[..n])?;
+        offset += n as u64;
+    }
+
+    dst.sync_all()
+}
+
+fn copy_blobs(storage: &dyn StorageBackend, to: &Path) -> io::Result<()> {
+    for (lsn, _) in storage.list_blobs()? {
+        let target = to.join(lsn.to_string());
+        if target.exists() {
+            continue;
+        }
+
+        match storage.get_blob(lsn) {
+            Ok(blob) => write_file(&target, &blob)?,
+            // removed since listing them
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_checkpoint_opens_independently() {
+        let base = std::env::temp_dir().join(format!("cloyster.checkpoint.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let backup = base.join("backup");
+
+        let config = ConfigBuilder::new()
+            .path(base.join("db"))
+            .io_buf_size(4096)
+            .build();
+        let pc = PageCache::<BTreeMap<Key, Key>>::start(config).unwrap();
+
+        let guard = pin();
+        let mut pids = vec![];
+        for i in 0..64_u8 {
+            let mut page = BTreeMap::new();
+            // a few of them are large enough to be stored as blobs
+            page.insert(vec![i], vec![i; 1 + (i as usize % 8) * 512]);
+            pids.push(pc.allocate(page, &guard).unwrap().0);
+        }
+
+        let stable = pc.checkpoint(&backup).unwrap();
+        assert!(stable > 0);
+        assert!(pc.checkpoint(&backup).is_err(), "backup dir is not empty");
+
+        // keeps accepting writes after the checkpoint
+        let mut page = BTreeMap::new();
+        page.insert(b"after".to_vec(), vec![]);
+        pc.allocate(page, &guard).unwrap();
+        drop(guard);
+        drop(pc);
+
+        let config = ConfigBuilder::new().path(&backup).io_buf_size(4096).build();
+        let restored = PageCache::<BTreeMap<Key, Key>>::start(config).unwrap();
+        let guard = pin();
+        for (i, pid) in pids.into_iter().enumerate() {
+            let (_, page, _) = restored.get(pid, &guard).unwrap().unwrap();
+            let i = i as u8;
+            assert_eq!(
+                page.get(&vec![i]),
+                Some(&vec![i; 1 + (i as usize % 8) * 512])
+            );
+        }
+        drop(guard);
+        drop(restored);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+//! The checksums protecting log messages and blobs.
+use super::*;
+
+/// The most bytes of checksum any `Checksum` stores.
+pub(crate) const MAX_DIGEST_LEN: usize = 16;
+
+/// The longest message header of any `Checksum`.
+pub(crate) const MAX_MSG_HEADER_LEN: usize = DIGEST_OFFSET + MAX_DIGEST_LEN;
+
+/// Where a message header keeps its checksum, after the kind, pid,
+/// lsn and length.
+pub(crate) const DIGEST_OFFSET: usize = MSG_HEADER_LEN - std::mem::size_of::<u32>();
+
+/// A checksum as stored, padded with zeroes past its `Checksum::len`.
+pub(crate) type Digest = [u8; MAX_DIGEST_LEN];
+
+/// How log messages and blobs are checksummed. This is part of the
+/// on-disk format, so it can't be changed once a database is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Checksum {
+    /// A crc32, 4 bytes per message and blob.
+    Crc32,
+    /// The first 128 bits of a blake3 hash, 16 bytes per message and
+    /// blob. Catches everything a crc32 lets slip through on large
+    /// logs, at some cost in space and CPU.
+    Blake3,
+}
+
+impl Checksum {
+    pub(crate) const ALL: [Checksum; 2] = [Checksum::Crc32, Checksum::Blake3];
+
+    /// What the crc of each segment header is xored with, which
+    /// records the checksum of the log in every segment without
+    /// making the header longer.
+    pub(crate) const fn seg_header_mask(self) -> u32 {
+        match self {
+            Checksum::Crc32 => 0xFFFF_FFFF,
+            Checksum::Blake3 => 0xB1A4_E3FF,
+        }
+    }
+
+    /// The number of bytes of checksum stored with each message.
+    pub(crate) const fn len(self) -> usize {
+        match self {
+            Checksum::Crc32 => std::mem::size_of::<u32>(),
+            Checksum::Blake3 => MAX_DIGEST_LEN,
+        }
+    }
+
+    /// The length of a message header holding this checksum.
+    pub(crate) const fn msg_header_len(self) -> usize {
+        DIGEST_OFFSET + self.len()
+    }
+
+    pub(crate) fn hasher(self) -> ChecksumHasher {
+        match self {
+            Checksum::Crc32 => ChecksumHasher::Crc32(crc32fast::Hasher::new()),
+            Checksum::Blake3 => ChecksumHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+/// Computes a `Checksum` over some bytes fed to it piece by piece.
+pub(crate) enum ChecksumHasher {
+    Crc32(crc32fast::Hasher),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChecksumHasher {
+    pub(crate) fn update(&mut self, buf: &[u8]) {
+        match self {
+            ChecksumHasher::Crc32(hasher) => hasher.update(buf),
+            ChecksumHasher::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Digest {
+        let mut digest = [0; MAX_DIGEST_LEN];
+        match self {
+            ChecksumHasher::Crc32(hasher) => {
+                digest[..std::mem::size_of::<u32>()].copy_from_slice(&u32_to_arr(hasher.finalize()))
+            }
+            ChecksumHasher::Blake3(hasher) => {
+                digest.copy_from_slice(&hasher.finalize().as_bytes()[..MAX_DIGEST_LEN])
+            }
+        }
+        digest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    #[test]
+    fn test_blake3_checksums() {
+        let path = std::env::temp_dir().join(format!("cloyster.checksum.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let builder = |checksum| {
+            ConfigBuilder::new()
+                .path(&path)
+                .io_buf_size(64 * 1024)
+                .checksum(checksum)
+        };
+        let config = |checksum| builder(checksum).build();
+
+        let pc = PageCache::<Page>::start(config(Checksum::Blake3)).unwrap();
+        let guard = pin();
+        let mut pids = vec![];
+        for (i, len) in [8, 100, 32 * 1024].iter().enumerate() {
+            let mut page = BTreeMap::new();
+            page.insert(vec![i as u8], vec![i as u8; *len]);
+            pids.push((pc.allocate(page, &guard).unwrap().0, i as u8, *len));
+        }
+        drop(guard);
+        pc.flush().unwrap();
+        assert!(pc.scrub(None).unwrap().corrupt.is_empty());
+        drop(pc);
+
+        // the checksum is part of the format, so building a config
+        // with another one refuses to open the database
+        assert!(std::panic::catch_unwind(|| config(Checksum::Crc32)).is_err());
+
+        // a config file that can't be read is not written over
+        let config_path = config(Checksum::Blake3).config_path();
+        let config_file = std::fs::read(&config_path).unwrap();
+        let mut unreadable = config_file.clone();
+        unreadable.truncate(unreadable.len() - 12);
+        unreadable.extend_from_slice(&[0xFF; 8]);
+        std::fs::write(&config_path, &unreadable).unwrap();
+        assert!(builder(Checksum::Blake3).try_build().is_err());
+        assert_eq!(std::fs::read(&config_path).unwrap(), unreadable);
+
+        // and without one, the segments still record the checksum
+        std::fs::remove_file(&config_path).unwrap();
+        match PageCache::<Page>::start(config(Checksum::Crc32)) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("opened with another checksum: {:?}", other.map(|_| ())),
+        }
+        std::fs::write(&config_path, &config_file).unwrap();
+
+        // recovered from the log, blobs included
+        let pc = PageCache::<Page>::start(config(Checksum::Blake3)).unwrap();
+        let guard = pin();
+        for (pid, i, len) in pids {
+            let (_, page, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert_eq!(page.get(&vec![i]), Some(&vec![i; len]));
+        }
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_config_from_before_the_checksum() {
+        // the fields the config file held before any were added to it
+        #[derive(Serialize)]
+        struct OriginalConfig {
+            cache_capacity: u64,
+            flush_every_ms: Option<u64>,
+            io_buf_size: usize,
+            page_consolidation_threshold: usize,
+            path: std::path::PathBuf,
+            read_only: bool,
+            segment_cleanup_threshold: f64,
+            segment_cleanup_skew: usize,
+            segment_mode: SegmentMode,
+            snapshot_after_ops: u64,
+            snapshot_path: Option<std::path::PathBuf>,
+            temporary: bool,
+            use_compression: bool,
+            compression_factor: i32,
+            print_profile_on_drop: bool,
+            idgen_persist_interval: u64,
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("cloyster.checksum_config.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let builder = ConfigBuilder::new()
+            .path(&path)
+            .io_buf_size(64 * 1024)
+            .segment_cleanup_skew(20);
+
+        let mut bytes = bincode::serialize(&OriginalConfig {
+            cache_capacity: builder.cache_capacity,
+            flush_every_ms: builder.flush_every_ms,
+            io_buf_size: builder.io_buf_size,
+            page_consolidation_threshold: builder.page_consolidation_threshold,
+            path: builder.path.clone(),
+            read_only: builder.read_only,
+            segment_cleanup_threshold: builder.segment_cleanup_threshold,
+            segment_cleanup_skew: builder.segment_cleanup_skew,
+            segment_mode: builder.segment_mode.clone(),
+            snapshot_after_ops: builder.snapshot_after_ops,
+            snapshot_path: builder.snapshot_path.clone(),
+            temporary: builder.temporary,
+            use_compression: builder.use_compression,
+            compression_factor: builder.compression_factor,
+            print_profile_on_drop: builder.print_profile_on_drop,
+            idgen_persist_interval: builder.idgen_persist_interval,
+        })
+        .unwrap();
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&u32_to_arr(crc));
+        std::fs::write(path.join("config"), bytes).unwrap();
+
+        // the fields it does hold are still checked
+        assert!(builder.clone().io_buf_size(128 * 1024).try_build().is_err());
+        let config = builder.build();
+        assert_eq!(config.checksum, Checksum::Crc32);
+        drop(PageCache::<Page>::start(config).unwrap());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -10,12 +10,15 @@ use std::{
     },
 };
 
-use bincode::{deserialize, serialize};
+use bincode::{serialize, Options};
 
 #[cfg(any(windows, target_os = "linux", target_os = "macos"))]
 use fs2::FileExt;
 
-use serde::Serialize;
+use serde::{
+    de::{DeserializeSeed, IntoDeserializer, MapAccess, Visitor},
+    Serialize,
+};
 
 // explicitly bring LogReader in to be tool-friendly
 use super::{LogReader, *};
@@ -42,7 +45,10 @@ impl Deref for ConfigInner {
 
 /// Top-level configuration for the system.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ConfigBuilder {
+    // NB the config file holds these fields by position, so new ones
+    // are only ever added after the last persisted one
     #[doc(hidden)]
     pub cache_capacity: u64,
     #[doc(hidden)]
@@ -75,6 +81,30 @@ pub struct ConfigBuilder {
     pub print_profile_on_drop: bool,
     #[doc(hidden)]
     pub idgen_persist_interval: u64,
+    #[doc(hidden)]
+    pub history_window: u64,
+    #[doc(hidden)]
+    pub recover_to_lsn: Option<Lsn>,
+    #[doc(hidden)]
+    pub recover_to_time: Option<u64>,
+    #[doc(hidden)]
+    pub direct_io: bool,
+    #[doc(hidden)]
+    pub dsync_io: bool,
+    #[doc(hidden)]
+    pub sync_every_write: bool,
+    #[doc(hidden)]
+    pub checksum: Checksum,
+    #[doc(hidden)]
+    pub key_check: Option<(KeyId, Vec<u8>)>,
+    #[doc(hidden)]
+    pub snapshot_retention: usize,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub encryption: Option<Encryption>,
+    #[doc(hidden)]
+    #[serde(skip)]
+    pub storage: Option<Storage>,
 }
 
 unsafe impl Send for ConfigBuilder {}
@@ -92,12 +122,23 @@ impl Default for ConfigBuilder {
             flush_every_ms: Some(500),
             snapshot_after_ops: 1_000_000,
             snapshot_path: None,
+            snapshot_retention: 2,
             segment_cleanup_threshold: 0.40,
             segment_cleanup_skew: 10,
             temporary: false,
             segment_mode: SegmentMode::Gc,
             print_profile_on_drop: false,
             idgen_persist_interval: 1_000_000,
+            history_window: 0,
+            recover_to_lsn: None,
+            recover_to_time: None,
+            direct_io: false,
+            dsync_io: false,
+            sync_every_write: false,
+            checksum: Checksum::Crc32,
+            key_check: None,
+            encryption: None,
+            storage: None,
         }
     }
 }
@@ -141,9 +182,19 @@ impl ConfigBuilder {
     /// to open the files for performing database IO,
     /// or if the provided configuration fails some
     /// basic sanity checks.
-    pub fn build(mut self) -> Config {
+    pub fn build(self) -> Config {
+        let path = self.db_path();
+        self.try_build().unwrap_or_else(|e| {
+            panic!("open file at {:?}: {}", path, e);
+        })
+    }
+
+    /// Finalize the configuration, failing instead of panicking if
+    /// the database can't be opened with it, for instance because it
+    /// was created with another encryption key.
+    pub fn try_build(mut self) -> Result<Config> {
         // only validate, setup directory, and open file once
-        self.validate().unwrap();
+        self.validate()?;
 
         if self.temporary {
             self.path = Self::gen_temp_path();
@@ -151,18 +202,30 @@ impl ConfigBuilder {
 
         self.limit_cache_max_memory();
 
-        let file = self.open_file().unwrap_or_else(|e| {
-            panic!("open file at {:?}: {}", self.db_path(), e);
-        });
+        let keys = match &self.encryption {
+            Some(encryption) => {
+                let keys = Keyring::new(encryption.0.clone());
+                let (id, cipher) = keys.current()?;
+                self.key_check = Some((id, cipher.key_check()));
+                Some(keys)
+            }
+            None => {
+                self.key_check = None;
+                None
+            }
+        };
+
+        let storage = self.open_storage()?;
 
         // seal config in a Config
-        Config(Arc::new(ConfigInner {
+        Ok(Config(Arc::new(ConfigInner {
             inner: self,
-            file,
+            storage,
+            keys,
             global_error: Atomic::default(),
             #[cfg(feature = "event_log")]
             event_log: crate::event_log::EventLog::default(),
-        }))
+        })))
     }
 
     fn gen_temp_path() -> PathBuf {
@@ -204,10 +267,29 @@ impl ConfigBuilder {
         }
     }
 
+    /// Keep the log, blobs and snapshots in `storage` instead of the
+    /// files under `path`. The config is not persisted with it, so
+    /// nothing stops a restart with a different `io_buf_size`.
+    pub fn storage(mut self, storage: Arc<dyn StorageBackend>) -> Self {
+        self.storage = Some(Storage(storage));
+        self
+    }
+
+    /// Encrypt the log, blobs and snapshots with the keys `keys`
+    /// provides. A database is either encrypted from its creation on
+    /// or never, and only opens if `keys` still has the key it was
+    /// last opened with. In a `storage` of its own there is no config
+    /// file to check the key against, so a wrong key is only caught
+    /// once recovery reaches a message that fails to decrypt.
+    pub fn encryption(mut self, keys: Arc<dyn KeyProvider>) -> Self {
+        self.encryption = Some(Encryption(keys));
+        self
+    }
+
     builder!(
         (io_buf_size, usize, "size of each io flush buffer. MUST be multiple of 512!"),
         (page_consolidation_threshold, usize, "page consolidation threshold"),
-        (temporary, bool, "deletes the database after drop. if no path is set, uses /dev/shm on linux"),
+        (temporary, bool, "keeps the database in memory, so it is gone once dropped"),
         (read_only, bool, "whether to run in read-only mode"),
         (cache_capacity, u64, "maximum size for the system page cache"),
         (use_compression, bool, "whether to use zstd compression"),
@@ -218,8 +300,16 @@ impl ConfigBuilder {
         (segment_cleanup_skew, usize, "the cleanup threshold skew in percentage points between the first and last segments"),
         (segment_mode, SegmentMode, "the file segment selection mode"),
         (snapshot_path, Option<PathBuf>, "snapshot file location"),
+        (snapshot_retention, usize, "number of snapshots kept around to fall back to if the newest is corrupt, as long as what they point at was not reused since"),
         (print_profile_on_drop, bool, "print a performance profile when the Config is dropped"),
-        (idgen_persist_interval, u64, "generated IDs are persisted at this interval. during recovery we skip twice this number")
+        (idgen_persist_interval, u64, "generated IDs are persisted at this interval. during recovery we skip twice this number"),
+        (history_window, u64, "bytes of log history kept around for point-in-time recovery, only with the default file storage"),
+        (recover_to_lsn, Option<Lsn>, "recover the state as of this Lsn instead of the tip of the log, requires read_only"),
+        (recover_to_time, Option<u64>, "recover the state as of this unix time in ms instead of the tip of the log, requires read_only"),
+        (direct_io, bool, "write and read the log with O_DIRECT, bypassing the OS page cache. linux only"),
+        (dsync_io, bool, "write IO buffers with O_DSYNC instead of syncing the file separately. sync_file_range is not offered, as it neither flushes the drive's cache nor the file's metadata"),
+        (sync_every_write, bool, "sync the log after writing each IO buffer, instead of once for everything written before a flush or a synced commit"),
+        (checksum, Checksum, "the checksum protecting each log message and blob, fixed once the database is created")
     );
 
     // panics if config options are outside of advised range
@@ -262,10 +352,46 @@ impl ConfigBuilder {
             self.compression_factor <= 22,
             "compression_factor must be <= 22"
         );
+        supported!(
+            self.snapshot_retention >= 1,
+            "snapshot_retention must be at least 1"
+        );
         supported!(
             self.idgen_persist_interval > 0,
             "idgen_persist_interval must be above 0"
         );
+        supported!(
+            self.read_only || (self.recover_to_lsn.is_none() && self.recover_to_time.is_none()),
+            "point-in-time recovery requires read_only"
+        );
+        supported!(
+            self.recover_to_lsn.is_none() || self.recover_to_time.is_none(),
+            "recover to either an Lsn or a time, not both"
+        );
+        supported!(
+            !self.direct_io || cfg!(target_os = "linux"),
+            "direct_io is only supported on linux"
+        );
+        supported!(
+            !self.direct_io || self.io_buf_size & (DIRECT_IO_ALIGN - 1) == 0,
+            "direct_io requires io_buf_size to be a multiple of 512"
+        );
+        supported!(
+            !self.dsync_io || cfg!(unix),
+            "dsync_io is only supported on unix"
+        );
+        supported!(
+            self.storage.is_none() || !(self.direct_io || self.dsync_io),
+            "direct_io and dsync_io only apply to the default file storage"
+        );
+        // the timeline and the history floor are files next to the log
+        supported!(
+            self.file_storage()
+                || (self.history_window == 0
+                    && self.recover_to_lsn.is_none()
+                    && self.recover_to_time.is_none()),
+            "history_window and point-in-time recovery only apply to the default file storage"
+        );
         Ok(())
     }
 
@@ -301,9 +427,9 @@ impl ConfigBuilder {
 
         // open the data file
         let mut options = fs::OpenOptions::new();
-        options.create(true);
         options.read(true);
         if !self.read_only {
+            options.create(true);
             options.write(true);
         }
 
@@ -313,13 +439,40 @@ impl ConfigBuilder {
         }
     }
 
+    fn open_storage(&mut self) -> Result<Arc<dyn StorageBackend>> {
+        if let Some(storage) = &self.storage {
+            return Ok(storage.0.clone());
+        }
+
+        if self.temporary {
+            return Ok(Arc::new(MemoryBackend::default()));
+        }
+
+        let file = self.open_file()?;
+        let log_writer = self.open_log_writer()?;
+        Ok(Arc::new(FileBackend::new(file, log_writer, self)))
+    }
+
+    // the data file is already there and locked by `open_file`
+    fn open_log_writer(&self) -> Result<Option<LogWriter>> {
+        // the kernel lets go of files written through io_uring lazily,
+        // that must not hold up releasing the lock on the data file
+        let uring = cfg!(all(target_os = "linux", feature = "io_uring"));
+        if self.read_only || !(self.direct_io || self.dsync_io || uring) {
+            return Ok(None);
+        }
+
+        let writer = LogWriter::open(&self.db_path(), self.direct_io, self.dsync_io)?;
+        Ok(Some(writer))
+    }
+
     fn try_lock(&self, file: File) -> Result<File> {
         #[cfg(any(windows, target_os = "linux", target_os = "macos"))]
         {
             let try_lock = if self.read_only {
-                file.try_lock_shared()
+                FileExt::try_lock_shared(&file)
             } else {
-                file.try_lock_exclusive()
+                FileExt::try_lock_exclusive(&file)
             };
 
             if let Err(e) = try_lock {
@@ -348,6 +501,33 @@ impl ConfigBuilder {
                     )
                 );
 
+                supported!(
+                    self.checksum == old.checksum,
+                    format!(
+                        "cannot change the checksum across restarts. \
+                         please change it back to {:?}",
+                        old.checksum
+                    )
+                );
+
+                // the key the config file was last written with may
+                // since have been rotated away from, but not dropped
+                let key_matches = match (&old.key_check, &self.encryption) {
+                    (Some((id, check)), Some(encryption)) => {
+                        Cipher::new(&encryption.0.key(*id)?).is_key_check(check)
+                    }
+                    (None, None) => true,
+                    _ => false,
+                };
+                supported!(
+                    key_matches,
+                    match (&old.key_check, &self.key_check) {
+                        (None, _) => "cannot encrypt a database created without encryption",
+                        (_, None) => "the database is encrypted, but no key was given",
+                        _ => "the database is encrypted with another key",
+                    }
+                );
+
                 supported!(
                     self.io_buf_size == old.io_buf_size,
                     format!(
@@ -357,27 +537,47 @@ impl ConfigBuilder {
                     )
                 );
 
+                let key_id =
+                    |key_check: &Option<(KeyId, Vec<u8>)>| key_check.as_ref().map(|(id, _)| *id);
+                if key_id(&self.key_check) != key_id(&old.key_check) && !self.read_only {
+                    // remember the current key to check the next start
+                    // against, in case older ones are dropped by then
+                    self.write_config()?;
+                }
+
                 Ok(())
             }
+            Ok(None) if self.read_only => Ok(()),
             Ok(None) => self.write_config(),
             Err(e) => Err(e.into()),
         }
     }
 
     fn write_config(&self) -> Result<()> {
+        self.write_config_at(&self.config_path())
+    }
+
+    pub(super) fn write_config_at(&self, path: &Path) -> Result<()> {
         let bytes = serialize(&*self).unwrap();
         let crc: u32 = crc32(&*bytes);
         let crc_arr = u32_to_arr(crc);
 
-        let path = self.config_path();
-
-        let mut f = fs::OpenOptions::new().write(true).create(true).open(path)?;
+        // a crash midway leaves the config file as it was
+        let generating = path.with_extension("generating");
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&generating)?;
 
         maybe_fail!("write_config bytes");
         f.write_all(&*bytes)?;
         maybe_fail!("write_config crc");
         f.write_all(&crc_arr)?;
+        f.sync_all()?;
+        drop(f);
         maybe_fail!("write_config post");
+        fs::rename(&generating, path)?;
         Ok(())
     }
 
@@ -392,9 +592,13 @@ impl ConfigBuilder {
             Ok(f) => f,
         };
 
+        // NB the config file is never overwritten when it can't be
+        // read, since the settings it holds are part of the format
         if f.metadata()?.len() <= 8 {
-            warn!("empty/corrupt configuration file found");
-            return Ok(None);
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("the config file {:?} is empty or truncated", path),
+            ));
         }
 
         let mut buf = vec![];
@@ -417,7 +621,23 @@ impl ConfigBuilder {
             );
         }
 
-        Ok(deserialize::<Self>(&*buf).ok())
+        match deserialize_config(&buf) {
+            Ok(config) => Ok(Some(config)),
+            Err(e) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "cannot parse the config file {:?}, which may have \
+                     been written by an incompatible version: {}",
+                    path, e
+                ),
+            )),
+        }
+    }
+
+    /// Whether the log, blobs and snapshots are kept in the files
+    /// under `path`, rather than in a `storage` of their own.
+    pub(crate) fn file_storage(&self) -> bool {
+        self.storage.is_none() && !self.temporary
     }
 
     // Get the path of the database
@@ -426,24 +646,128 @@ impl ConfigBuilder {
         self.path.clone()
     }
 
-    pub(crate) fn blob_path(&self, id: Lsn) -> PathBuf {
-        let mut path = self.get_path();
-        path.push("blobs");
-        path.push(format!("{}", id));
-        path
+    /// The length of the header of each log message, which depends on
+    /// the `Checksum`.
+    pub(crate) fn msg_header_len(&self) -> usize {
+        self.checksum.msg_header_len()
+    }
+
+    /// The length of the header of each segment, which records the
+    /// key its messages are sealed with when encrypted.
+    pub(crate) fn seg_header_len(&self) -> usize {
+        if self.encryption.is_some() {
+            SEG_HEADER_LEN + KEY_ID_LEN
+        } else {
+            SEG_HEADER_LEN
+        }
     }
 
-    fn db_path(&self) -> PathBuf {
+    pub(crate) fn db_path(&self) -> PathBuf {
         let mut path = self.get_path();
         path.push("db");
         path
     }
 
-    fn config_path(&self) -> PathBuf {
+    pub(crate) fn config_path(&self) -> PathBuf {
         let mut path = self.get_path();
         path.push("config");
         path
     }
+
+    pub(crate) fn timeline_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("timeline");
+        path
+    }
+
+    pub(crate) fn history_floor_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("history_floor");
+        path
+    }
+
+    pub(crate) fn migration_marker_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("migrating");
+        path
+    }
+
+    pub(crate) fn replication_path(&self) -> PathBuf {
+        let mut path = self.get_path();
+        path.push("replication");
+        path
+    }
+}
+
+/// Reads back what `write_config` wrote, taking the defaults for the
+/// fields that were added after it was written. The fields are found
+/// by position, so this only works as long as new ones are appended.
+fn deserialize_config(buf: &[u8]) -> bincode::Result<ConfigBuilder> {
+    ConfigBuilder::deserialize(Fields(buf))
+}
+
+/// Hands the fields of a struct that `bincode::serialize` wrote to its
+/// visitor by name, and ends the struct where the input ends, so the
+/// visitor fills in the rest from `Default`.
+struct Fields<'a>(&'a [u8]);
+
+impl<'de, 'a> serde::Deserializer<'de> for Fields<'a> {
+    type Error = bincode::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> bincode::Result<V::Value> {
+        Err(serde::de::Error::custom(
+            "only structs are read field by field",
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> bincode::Result<V::Value> {
+        visitor.visit_map(FieldsAccess {
+            buf: self.0,
+            names: fields.iter(),
+        })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct FieldsAccess<'a> {
+    buf: &'a [u8],
+    names: std::slice::Iter<'static, &'static str>,
+}
+
+impl<'de, 'a> MapAccess<'de> for FieldsAccess<'a> {
+    type Error = bincode::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> bincode::Result<Option<K::Value>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        match self.names.next() {
+            Some(name) => seed.deserialize(name.into_deserializer()).map(Some),
+            None => Err(serde::de::Error::custom("more fields than the struct has")),
+        }
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> bincode::Result<T::Value> {
+        // the options `bincode::serialize` uses
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes();
+        let mut de = bincode::Deserializer::with_reader(&mut self.buf, options);
+        seed.deserialize(&mut de)
+    }
 }
 
 /// A finalized `ConfigBuilder` that can be use multiple times
@@ -462,7 +786,8 @@ impl Deref for Config {
 #[derive(Debug)]
 pub struct ConfigInner {
     inner: ConfigBuilder,
-    pub(crate) file: File,
+    pub(crate) storage: Arc<dyn StorageBackend>,
+    pub(crate) keys: Option<Keyring>,
     pub(crate) global_error: Atomic<Error>,
     #[cfg(feature = "event_log")]
     /// an event log for concurrent debugging
@@ -504,6 +829,24 @@ impl Config {
         }
     }
 
+    /// Records in the config file that nothing is sealed with keys
+    /// other than `id` anymore, so that the next start checks the
+    /// key against that one, and older ones can be dropped.
+    pub(crate) fn record_key(&self, id: KeyId) -> Result<()> {
+        let keys = match &self.keys {
+            Some(keys) => keys,
+            None => return Ok(()),
+        };
+        let has_config_file = !(self.read_only || self.temporary || self.inner.storage.is_some());
+        if !has_config_file || self.key_check.as_ref().map(|(old, _)| *old) == Some(id) {
+            return Ok(());
+        }
+
+        let mut inner = self.inner.clone();
+        inner.key_check = Some((id, keys.get(id)?.key_check()));
+        inner.write_config()
+    }
+
     pub(crate) fn reset_global_error(&self) {
         let guard = pin();
         let old = self
@@ -582,14 +925,20 @@ impl Config {
         Ok(snap_dir.read_dir()?.filter_map(filter).collect())
     }
 
+    /// List the snapshots on disk, newest first, with the Lsn each was
+    /// taken at, its size, and whether it passes its crc check.
+    pub fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>> {
+        snapshot::list_snapshots(self)
+    }
+
     #[doc(hidden)]
     pub fn verify_snapshot(&self) -> Result<()> {
         debug!("generating incremental snapshot");
 
         let incremental = read_snapshot_or_default(&self)?;
 
-        for snapshot_path in self.get_snapshot_files()? {
-            fs::remove_file(snapshot_path)?;
+        for (lsn, _) in self.storage.list_snapshots()? {
+            self.storage.delete_snapshot(lsn)?;
         }
 
         debug!("generating snapshot without the previous one");
@@ -597,7 +946,7 @@ impl Config {
 
         let verify_messages = |k: &PageId, v: &PageState| {
             for (lsn, ptr, _sz) in v.iter() {
-                if let Err(e) = self.file.read_message(ptr.lid(), lsn, &self) {
+                if let Err(e) = self.storage.read_message(ptr.lid(), lsn, &self) {
                     panic!(
                         "could not read log data for \
                          pid {} at lsn {} ptr {}: {}",
@@ -648,8 +997,8 @@ impl Config {
     #[doc(hidden)]
     // truncate the underlying file for corruption testing purposes.
     pub fn truncate_corrupt(&self, new_len: u64) {
-        self.file
-            .set_len(new_len)
+        self.storage
+            .truncate_log(new_len)
             .expect("should be able to truncate");
     }
 }
@@ -8,7 +8,8 @@ use super::*;
 /// Log messages have a header of this length.
 pub const MSG_HEADER_LEN: usize = 25;
 
-/// Log segments have a header of this length.
+/// Log segments have a header of this length, followed by
+/// the id of the key they are sealed with when encrypted.
 pub const SEG_HEADER_LEN: usize = 20;
 
 /// Log messages that are stored as external blobs
@@ -32,3 +33,16 @@ pub(crate) const META_PID: PageId = 0;
 pub(crate) const COUNTER_PID: PageId = 1;
 pub(crate) const CONFIG_PID: PageId = 2;
 pub(crate) const BATCH_MANIFEST_PID: PageId = PageId::max_value() - 666;
+/// Followers log their replication position under this pid, in the
+/// same batch as the round it was reached with.
+pub(crate) const REPLICATION_PID: PageId = PageId::max_value() - 667;
+
+/// Whether `pid` is one of the pages the system keeps for itself, which
+/// hold no page fragments and can't be read or freed through the API.
+pub(crate) fn is_reserved_pid(pid: PageId) -> bool {
+    pid == COUNTER_PID
+        || pid == META_PID
+        || pid == CONFIG_PID
+        || pid == BATCH_MANIFEST_PID
+        || pid == REPLICATION_PID
+}
@@ -0,0 +1,540 @@
+//! Encryption at rest of log messages, blobs and snapshots.
+//!
+//! Everything is sealed with XChaCha20-Poly1305 under a random nonce,
+//! which is stored after its tag, along with the id of the key that
+//! sealed it. What is being sealed and the Lsn it is stored at are
+//! authenticated with it, so nothing can be passed off as something
+//! else, and an Lsn reused after a crash never reuses a nonce.
+//!
+//! Keys are rotated by making another one current: new segments, blobs
+//! and snapshots are sealed with it, while segment cleaning relocates
+//! the pages still sealed with older keys until none are left.
+use chacha20poly1305::{
+    aead::{AeadCore, AeadInPlace, KeyInit, OsRng},
+    Key, Tag, XChaCha20Poly1305, XNonce,
+};
+
+use super::*;
+use crate::sync::{Arc, RwLock};
+
+const TAG_LEN: usize = 16;
+
+const NONCE_LEN: usize = 24;
+
+/// The number of bytes sealing adds to what it encrypts.
+pub(crate) const SEAL_LEN: usize = TAG_LEN + NONCE_LEN;
+
+/// The number of bytes a `KeyId` takes on disk.
+pub(crate) const KEY_ID_LEN: usize = 4;
+
+/// A 256 bit key.
+pub type EncryptionKey = [u8; 32];
+
+/// Identifies one of the keys a `KeyProvider` hands out.
+pub type KeyId = u32;
+
+/// Supplies the keys a database is encrypted with.
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key new data is sealed with. Making another id
+    /// current rotates the key, after which the keys of older ids
+    /// must stay available until `PageCache::key_rotation` reports
+    /// nothing is left under them.
+    fn current(&self) -> KeyId;
+
+    /// The key with the given id, fetched once per `Config`.
+    fn key(&self, id: KeyId) -> Result<EncryptionKey>;
+}
+
+impl KeyProvider for EncryptionKey {
+    fn current(&self) -> KeyId {
+        0
+    }
+
+    fn key(&self, id: KeyId) -> Result<EncryptionKey> {
+        if id == 0 {
+            Ok(*self)
+        } else {
+            Err(Error::Unsupported(format!("no key with id {}", id)))
+        }
+    }
+}
+
+/// How much is still sealed with keys other than the current one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRotation {
+    /// The id of the key everything is being moved to.
+    pub current: KeyId,
+    /// Segments of the log sealed with older keys.
+    pub segments: usize,
+    /// Pages with data in those segments.
+    pub pages: usize,
+    /// Stored blobs sealed with older keys, which may be pointed at
+    /// from segments sealed with the current one.
+    pub blobs: usize,
+}
+
+impl KeyRotation {
+    /// Whether everything is sealed with the current key, after which
+    /// older keys can be dropped. Snapshots taken before are only
+    /// replaced as new ones get taken.
+    pub fn is_done(&self) -> bool {
+        self.segments == 0 && self.blobs == 0
+    }
+}
+
+/// A `KeyProvider` shared between `ConfigBuilder`s, compared by
+/// identity.
+#[derive(Clone)]
+pub struct Encryption(pub Arc<dyn KeyProvider>);
+
+impl PartialEq for Encryption {
+    fn eq(&self, other: &Encryption) -> bool {
+        Arc::as_ptr(&self.0) as *const u8 == Arc::as_ptr(&other.0) as *const u8
+    }
+}
+
+impl Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Encryption(..)")
+    }
+}
+
+/// What is being sealed, so that a blob can't be passed off as the
+/// message pointing to it.
+#[derive(Clone, Copy)]
+pub(crate) enum Domain {
+    KeyCheck = 0,
+    Message = 1,
+    Blob = 2,
+    Snapshot = 3,
+}
+
+pub(crate) struct Cipher(XChaCha20Poly1305);
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+impl Cipher {
+    pub(crate) fn new(key: &EncryptionKey) -> Self {
+        Cipher(XChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    fn aad(domain: Domain, lsn: Lsn, aad: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(9 + aad.len());
+        buf.push(domain as u8);
+        buf.extend_from_slice(&u64_to_arr(lsn as u64));
+        buf.extend_from_slice(aad);
+        buf
+    }
+
+    /// Encrypts all but the last `SEAL_LEN` bytes of `buf` in place,
+    /// and puts the tag and nonce there.
+    pub(crate) fn seal_in_place(&self, domain: Domain, lsn: Lsn, aad: &[u8], buf: &mut [u8]) {
+        let (data, trailer) = buf.split_at_mut(buf.len() - SEAL_LEN);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt_in_place_detached(&nonce, &Self::aad(domain, lsn, aad), data)
+            .expect("the log never seals more than 256GB at once");
+        trailer[..TAG_LEN].copy_from_slice(&sealed);
+        trailer[TAG_LEN..].copy_from_slice(&nonce);
+    }
+
+    pub(crate) fn seal(&self, domain: Domain, lsn: Lsn, aad: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(data.len() + SEAL_LEN);
+        buf.extend_from_slice(data);
+        buf.resize(data.len() + SEAL_LEN, 0);
+        self.seal_in_place(domain, lsn, aad, &mut buf);
+        buf
+    }
+
+    /// Decrypts what `seal` returned, or `None` if it was sealed with
+    /// another key or tampered with.
+    pub(crate) fn open(
+        &self,
+        domain: Domain,
+        lsn: Lsn,
+        aad: &[u8],
+        mut buf: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        let data_len = buf.len().checked_sub(SEAL_LEN)?;
+        let tag = Tag::clone_from_slice(&buf[data_len..data_len + TAG_LEN]);
+        let nonce = XNonce::clone_from_slice(&buf[data_len + TAG_LEN..]);
+        buf.truncate(data_len);
+        self.0
+            .decrypt_in_place_detached(&nonce, &Self::aad(domain, lsn, aad), &mut buf, &tag)
+            .ok()?;
+        Some(buf)
+    }
+
+    /// A fixed plaintext sealed under the key, stored in the config
+    /// file to recognize the key by.
+    pub(crate) fn key_check(&self) -> Vec<u8> {
+        self.seal(Domain::KeyCheck, 0, &[], &[0; TAG_LEN])
+    }
+
+    /// Whether `check` was returned by `key_check` under this key.
+    pub(crate) fn is_key_check(&self, check: &[u8]) -> bool {
+        self.open(Domain::KeyCheck, 0, &[], check.to_vec()) == Some(vec![0; TAG_LEN])
+    }
+}
+
+/// The ciphers of every key in use, fetched from the `KeyProvider` the
+/// first time each is needed.
+pub(crate) struct Keyring {
+    provider: Arc<dyn KeyProvider>,
+    ciphers: RwLock<FastMap4<KeyId, Arc<Cipher>>>,
+}
+
+impl Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Keyring(..)")
+    }
+}
+
+impl Keyring {
+    pub(crate) fn new(provider: Arc<dyn KeyProvider>) -> Keyring {
+        Keyring {
+            provider,
+            ciphers: RwLock::new(FastMap4::default()),
+        }
+    }
+
+    pub(crate) fn current_id(&self) -> KeyId {
+        self.provider.current()
+    }
+
+    pub(crate) fn current(&self) -> Result<(KeyId, Arc<Cipher>)> {
+        let id = self.current_id();
+        Ok((id, self.get(id)?))
+    }
+
+    pub(crate) fn get(&self, id: KeyId) -> Result<Arc<Cipher>> {
+        if let Some(cipher) = self.ciphers.read().get(&id) {
+            return Ok(cipher.clone());
+        }
+        let cipher = Arc::new(Cipher::new(&self.provider.key(id)?));
+        self.ciphers.write().insert(id, cipher.clone());
+        Ok(cipher)
+    }
+
+    /// Seals `data` with the current key, prefixed by its id.
+    pub(crate) fn seal(
+        &self,
+        domain: Domain,
+        lsn: Lsn,
+        aad: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>> {
+        let (id, cipher) = self.current()?;
+        let mut buf = Vec::with_capacity(KEY_ID_LEN + data.len() + SEAL_LEN);
+        buf.extend_from_slice(&u32_to_arr(id));
+        buf.extend_from_slice(&cipher.seal(domain, lsn, aad, data));
+        Ok(buf)
+    }
+
+    /// Decrypts what `seal` returned with whichever key sealed it, or
+    /// returns `None` if it was tampered with.
+    pub(crate) fn open(
+        &self,
+        domain: Domain,
+        lsn: Lsn,
+        aad: &[u8],
+        mut buf: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        if buf.len() < KEY_ID_LEN {
+            return Ok(None);
+        }
+        let id = arr_to_u32(&buf[..KEY_ID_LEN]);
+        let sealed = buf.split_off(KEY_ID_LEN);
+        Ok(self.get(id)?.open(domain, lsn, aad, sealed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pool::block_on, prelude::Key, sync::Mutex};
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    const SECRET: &[u8] = b"the eagle lands at midnight";
+
+    fn files_under(dir: &std::path::Path, out: &mut Vec<Vec<u8>>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files_under(&path, out);
+            } else {
+                out.push(std::fs::read(&path).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_reused_lsn_reuses_no_nonce() {
+        let cipher = Cipher::new(&[1; 32]);
+        let first = cipher.seal(Domain::Message, 42, b"pid", SECRET);
+        let second = cipher.seal(Domain::Message, 42, b"pid", SECRET);
+        assert_ne!(first, second);
+        assert_eq!(first.len(), SECRET.len() + SEAL_LEN);
+
+        assert_eq!(
+            cipher.open(Domain::Message, 42, b"pid", second.clone()),
+            Some(SECRET.to_vec())
+        );
+        // what it was sealed as and where is authenticated
+        assert_eq!(cipher.open(Domain::Blob, 42, b"pid", second.clone()), None);
+        assert_eq!(cipher.open(Domain::Message, 43, b"pid", second), None);
+
+        assert!(cipher.is_key_check(&cipher.key_check()));
+        assert!(!Cipher::new(&[2; 32]).is_key_check(&cipher.key_check()));
+    }
+
+    #[test]
+    fn test_encryption_at_rest() {
+        let path = std::env::temp_dir().join(format!("cloyster.encryption.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = |key: Option<EncryptionKey>| {
+            let config = ConfigBuilder::new().path(&path).io_buf_size(64 * 1024);
+            match key {
+                Some(key) => config.encryption(Arc::new(key)),
+                None => config,
+            }
+        };
+
+        let pc = PageCache::<Page>::start(config(Some([1; 32])).build()).unwrap();
+        let guard = pin();
+        let mut pids = vec![];
+        for (i, copies) in [1, 1000].iter().enumerate() {
+            let mut page = BTreeMap::new();
+            page.insert(vec![i as u8], SECRET.repeat(*copies));
+            pids.push((pc.allocate(page, &guard).unwrap().0, i as u8, *copies));
+        }
+        drop(guard);
+        pc.flush().unwrap();
+        block_on(pc.snapshot_now()).unwrap();
+        drop(pc);
+
+        // nothing on disk gives the secret away, blobs included
+        let mut files = vec![];
+        files_under(&path, &mut files);
+        assert!(files.len() > 3);
+        for file in files {
+            assert!(!file.windows(SECRET.len()).any(|w| w == SECRET));
+        }
+
+        // another key, or none, is turned away before anything is read
+        for key in [Some([2; 32]), None] {
+            match config(key).try_build() {
+                Err(Error::Unsupported(_)) => {}
+                other => panic!("opened with the wrong key: {:?}", other.map(|_| ())),
+            }
+        }
+
+        let pc = PageCache::<Page>::start(config(Some([1; 32])).build()).unwrap();
+        let guard = pin();
+        for (pid, i, copies) in pids {
+            let (_, page, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert_eq!(page.get(&vec![i]), Some(&SECRET.repeat(copies)));
+        }
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
+
+        // without a config file, recovery stops at the first message
+        // that won't decrypt instead of writing over the rest
+        let storage = Arc::new(MemoryBackend::default());
+        let config = |key: EncryptionKey| {
+            ConfigBuilder::new()
+                .storage(storage.clone())
+                .encryption(Arc::new(key))
+                .build()
+        };
+        let pc = PageCache::<Page>::start(config([1; 32])).unwrap();
+        pc.flush().unwrap();
+        drop(pc);
+        assert!(matches!(
+            PageCache::<Page>::start(config([2; 32])),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    /// Hands out the keys still held, by their index, the last one
+    /// being current.
+    struct Keys(Mutex<Vec<Option<EncryptionKey>>>);
+
+    impl KeyProvider for Keys {
+        fn current(&self) -> KeyId {
+            self.0.lock().len() as KeyId - 1
+        }
+
+        fn key(&self, id: KeyId) -> Result<EncryptionKey> {
+            self.0.lock()[id as usize]
+                .ok_or_else(|| Error::Unsupported(format!("key {} was dropped", id)))
+        }
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        // with a history window, freed segments keep the old key
+        // until they fall out of it
+        for history_window in [0, 4 * 64 * 1024] {
+            key_rotation(history_window);
+        }
+    }
+
+    fn key_rotation(history_window: u64) {
+        let path = std::env::temp_dir().join(format!(
+            "cloyster.rotation.{}.{}",
+            history_window,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        let keys = Arc::new(Keys(Mutex::new(vec![Some([1; 32])])));
+        let config = || {
+            ConfigBuilder::new()
+                .path(&path)
+                .io_buf_size(64 * 1024)
+                .history_window(history_window)
+                .encryption(keys.clone())
+                .build()
+        };
+
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        let mut pids = vec![];
+        for i in 0..200_u8 {
+            // a few blobs among them
+            let copies = if i % 50 == 0 { 1000 } else { 8 };
+            let mut page = BTreeMap::new();
+            page.insert(vec![i], SECRET.repeat(copies));
+            pids.push((pc.allocate(page, &guard).unwrap().0, i, copies));
+        }
+        let (filler, _) = pc.allocate(BTreeMap::new(), &guard).unwrap();
+        drop(guard);
+        pc.flush().unwrap();
+
+        keys.0.lock().push(Some([2; 32]));
+        let rotation = pc.key_rotation().unwrap();
+        assert_eq!(rotation.current, 1);
+        assert!(rotation.segments > 0 && rotation.pages >= pids.len());
+
+        // other writes keep the log moving while the old segments
+        // are rewritten
+        let mut rounds = 0;
+        while !pc.key_rotation().unwrap().is_done() {
+            rounds += 1;
+            assert!(rounds < 10_000, "{:?}", pc.key_rotation());
+            if !pc.attempt_gc().unwrap() {
+                let guard = pin();
+                let (key, _, _) = pc.get(filler, &guard).unwrap().unwrap();
+                let mut page = BTreeMap::new();
+                page.insert(vec![], vec![rounds as u8; 4096]);
+                let _ = pc.replace(filler, key, page, &guard).unwrap();
+            }
+            pc.flush().unwrap();
+        }
+        drop(pc);
+
+        // the old key is no longer needed
+        keys.0.lock()[0] = None;
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        for (pid, i, copies) in pids {
+            let (_, page, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert_eq!(page.get(&vec![i]), Some(&SECRET.repeat(copies)));
+        }
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    /// Hands out two keys, either of which can be made current, and
+    /// the first of which can be dropped.
+    #[derive(Default)]
+    struct Switch {
+        current: Mutex<KeyId>,
+        first_dropped: Mutex<bool>,
+    }
+
+    impl KeyProvider for Switch {
+        fn current(&self) -> KeyId {
+            *self.current.lock()
+        }
+
+        fn key(&self, id: KeyId) -> Result<EncryptionKey> {
+            if id == 0 && *self.first_dropped.lock() {
+                return Err(Error::Unsupported("key 0 was dropped".into()));
+            }
+            Ok([id as u8 + 1; 32])
+        }
+    }
+
+    #[test]
+    fn test_key_rotation_of_blob_in_current_segment() {
+        let path =
+            std::env::temp_dir().join(format!("cloyster.rotation_blob.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let keys = Arc::new(Switch::default());
+        *keys.current.lock() = 1;
+        let config = || {
+            ConfigBuilder::new()
+                .path(&path)
+                .io_buf_size(64 * 1024)
+                .encryption(keys.clone())
+                .build()
+        };
+
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        pc.allocate(BTreeMap::new(), &guard).unwrap();
+
+        // a blob sealed with the older key, pointed at from the
+        // segment that was already sealed with the current one
+        *keys.current.lock() = 0;
+        let mut page = BTreeMap::new();
+        page.insert(vec![], SECRET.repeat(1000));
+        let (pid, _) = pc.allocate(page.clone(), &guard).unwrap();
+        *keys.current.lock() = 1;
+        drop(guard);
+        pc.flush().unwrap();
+
+        let rotation = pc.key_rotation().unwrap();
+        assert_eq!((rotation.segments, rotation.blobs), (0, 1));
+        assert!(!rotation.is_done());
+
+        // the page is rewritten right away, the old blob is removed
+        // once nothing in the log that is read back points at it
+        assert!(pc.attempt_gc().unwrap());
+        assert!(!pc.attempt_gc().unwrap());
+        let mut rounds = 0;
+        while !pc.key_rotation().unwrap().is_done() {
+            rounds += 1;
+            assert!(rounds < 1000, "{:?}", pc.key_rotation());
+            let mut filler = BTreeMap::new();
+            filler.insert(vec![], vec![rounds as u8; 4096]);
+            let guard = pin();
+            pc.allocate(filler, &guard).unwrap();
+            drop(guard);
+            pc.flush().unwrap();
+        }
+        drop(pc);
+
+        *keys.first_dropped.lock() = true;
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        let (_, read, _) = pc.get(pid, &guard).unwrap().unwrap();
+        assert_eq!(read, &page);
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
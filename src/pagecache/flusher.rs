@@ -0,0 +1,224 @@
+use std::thread;
+
+use parking_lot::{Condvar, Mutex};
+
+use super::*;
+use crate::sync::Arc;
+
+/// How far a write has to get before the call making it returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Kept in the IO buffers, it reaches the disk whenever they
+    /// fill up or something else flushes them. Lost on a crash.
+    None,
+    /// Written out to the log file, leaving it to the OS when it
+    /// reaches the disk, or to the next flush or synced write.
+    /// Survives the process crashing, but not the machine.
+    Buffered,
+    /// Synced to disk through the group commit flusher.
+    Synced,
+}
+
+#[derive(Debug)]
+struct Requests {
+    // the highest Lsn any caller is waiting on
+    lsn: Lsn,
+    shutdown: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    requests: Mutex<Requests>,
+    requested: Condvar,
+}
+
+/// Group commit: callers of `request` say which Lsn they need on
+/// disk, and a single background thread makes the highest one
+/// requested so far stable. Everybody who asked while the previous
+/// write was in flight is covered by the next one, so N concurrent
+/// committers share a single fsync instead of each sealing and
+/// syncing their own IO buffer.
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    shared: Arc<Shared>,
+    join_handle: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Flusher {
+    /// Spawns the flusher thread for these IO buffers.
+    pub(crate) fn new(name: &str, iobufs: Arc<IoBufs>) -> Result<Flusher> {
+        let shared = Arc::new(Shared {
+            requests: Mutex::new(Requests {
+                lsn: -1,
+                shutdown: false,
+            }),
+            requested: Condvar::new(),
+        });
+
+        let join_handle = thread::Builder::new().name(name.to_owned()).spawn({
+            let shared = shared.clone();
+            move || run(&shared, &iobufs)
+        })?;
+
+        Ok(Flusher {
+            shared,
+            join_handle: Mutex::new(Some(join_handle)),
+        })
+    }
+
+    /// Asks the flusher to make `lsn` stable. Does not wait for it.
+    pub(crate) fn request(&self, lsn: Lsn) {
+        let mut requests = self.shared.requests.lock();
+        if lsn > requests.lsn {
+            requests.lsn = lsn;
+            self.shared.requested.notify_one();
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        {
+            let mut requests = self.shared.requests.lock();
+            requests.shutdown = true;
+            self.shared.requested.notify_one();
+        }
+
+        if let Some(join_handle) = self.join_handle.lock().take() {
+            if join_handle.join().is_err() {
+                error!("error joining the group commit flusher");
+            }
+        }
+    }
+}
+
+fn run(shared: &Shared, iobufs: &Arc<IoBufs>) {
+    let mut requests = shared.requests.lock();
+
+    loop {
+        if requests.lsn > iobufs.stable() {
+            let lsn = requests.lsn;
+
+            // let new requests pile up while this batch is written,
+            // they will all be picked up by the next round
+            let res = parking_lot::MutexGuard::unlocked(&mut requests, || {
+                iobuf::make_stable(iobufs, lsn)
+            });
+
+            if let Err(e) = res {
+                error!("group commit flusher failed: {:?}", e);
+
+                // committers waiting on this batch check the global
+                // error, make sure it is set before waking them up
+                iobufs.config.set_global_error(e);
+                let _lock = iobufs.intervals.lock();
+                iobufs.notify_interval_updated();
+                return;
+            }
+
+            continue;
+        }
+
+        if requests.shutdown {
+            return;
+        }
+
+        shared.requested.wait(&mut requests);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::SimulatedDisk;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    #[test]
+    fn test_concurrent_commits_are_stable() {
+        let path =
+            std::env::temp_dir().join(format!("cloyster.group_commit.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = ConfigBuilder::new().path(&path).build();
+
+        let pc = Arc::new(PageCache::<Page>::start(config.clone()).unwrap());
+        let threads: Vec<_> = (0..8u8)
+            .map(|i| {
+                let pc = pc.clone();
+                thread::spawn(move || {
+                    let mut pids = vec![];
+                    for j in 0..16u8 {
+                        let guard = pin();
+                        let mut page = BTreeMap::new();
+                        page.insert(vec![i, j], vec![j; 8]);
+                        let (pid, key) = pc.allocate(page, &guard).unwrap();
+                        pc.make_stable(key.last_lsn()).unwrap();
+                        assert!(pc.stable_lsn() >= key.last_lsn());
+                        pids.push((pid, vec![i, j]));
+                    }
+                    pids
+                })
+            })
+            .collect();
+
+        let pids: Vec<_> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        drop(pc);
+
+        let pc = PageCache::<Page>::start(config).unwrap();
+        let guard = pin();
+        for (pid, key) in pids {
+            let (_, page, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert!(page.contains_key(&key));
+        }
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_buffered_writes_are_synced_later() {
+        for &sync_every_write in &[false, true] {
+            let disk = Arc::new(SimulatedDisk::new(0));
+            let config = ConfigBuilder::new()
+                .storage(disk.clone())
+                .flush_every_ms(None)
+                .sync_every_write(sync_every_write)
+                .build();
+            let pc = PageCache::<Page>::start(config).unwrap();
+
+            let guard = pin();
+            let mut page = BTreeMap::new();
+            page.insert(b"k".to_vec(), b"v".to_vec());
+            let (pid, key) = pc.allocate(page, &guard).unwrap();
+            let lsn = key.last_lsn();
+
+            // written out, and readable, but only synced if every
+            // write is
+            pc.make_durable(lsn, Durability::Buffered).unwrap();
+            assert_eq!(pc.stable_lsn() >= lsn, sync_every_write);
+            let lost = disk.crash();
+
+            // otherwise it is up to the synced commit
+            let synced = pc.make_durable(lsn, Durability::Synced).unwrap();
+            assert_eq!(synced > 0, !sync_every_write);
+            assert!(pc.stable_lsn() >= lsn);
+            let kept = disk.crash();
+            drop(guard);
+            drop(pc);
+
+            for (disk, survives) in [(lost, sync_every_write), (kept, true)] {
+                let config = ConfigBuilder::new().storage(Arc::new(disk)).build();
+                let pc = PageCache::<Page>::start(config).unwrap();
+                let guard = pin();
+                let found = pc.get(pid, &guard).unwrap().is_some();
+                assert!(found || !survives);
+            }
+        }
+    }
+}
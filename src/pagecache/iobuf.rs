@@ -1,4 +1,10 @@
 use abyss_promise::Promise;
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
 
 use parking_lot::{Condvar, Mutex, RwLock};
 
@@ -20,8 +26,8 @@ macro_rules! io_fail {
         fail_point!($e, |_| {
             $self.config.set_global_error(Error::FailPoint);
             // wake up any waiting threads so they don't stall forever
-            let _ = $self.intervals.lock();
-            $self.interval_updated.notify_all();
+            let _lock = $self.intervals.lock();
+            $self.notify_interval_updated();
             Err(Error::FailPoint)
         });
     };
@@ -36,6 +42,9 @@ pub struct IoBuf {
     maxed: AtomicBool,
     linearizer: Mutex<()>,
     stored_max_stable_lsn: Lsn,
+    /// The key messages in this buffer are sealed with, that of the
+    /// segment it is part of.
+    pub(super) key: Option<(KeyId, Arc<Cipher>)>,
 }
 
 unsafe impl Sync for IoBuf {}
@@ -45,20 +54,41 @@ pub struct IoBufs {
 
     pub(crate) iobuf: RwLock<Arc<IoBuf>>,
 
-    // Pending intervals that have been written to stable storage, but may be
-    // higher than the current value of `stable` due to interesting thread
+    // Pending intervals that have been written to the log, but may be
+    // higher than the current value of `written` due to interesting thread
     // interleavings.
     pub(crate) intervals: Mutex<Vec<(Lsn, Lsn)>>,
     pub(super) interval_updated: Condvar,
 
+    // futures waiting for an Lsn to become stable, woken along with
+    // the threads waiting on interval_updated once it is written
+    #[cfg(feature = "async")]
+    stable_waiters: Mutex<Vec<(Lsn, Waker)>>,
+
     // The highest CONTIGUOUS log sequence number that has been written to
-    // stable storage. This may be lower than the length of the underlying
-    // file, and there may be buffers that have been written out-of-order
-    // to stable storage due to interesting thread interleavings.
+    // the log. This may be lower than the length of the underlying file,
+    // and there may be buffers that have been written out-of-order due to
+    // interesting thread interleavings.
+    pub(crate) written_lsn: AtomicLsn,
+
+    // The highest log sequence number known to be synced to stable
+    // storage, never above `written_lsn`. Unless `sync_every_write` is
+    // set, it only moves when somebody asks for it in `make_stable`.
     pub(crate) stable_lsn: AtomicLsn,
+
+    // held while syncing the log, so that callers arriving in the
+    // meantime wait for it and then find their Lsn already synced
+    syncing: Mutex<()>,
     pub(crate) max_reserved_lsn: AtomicLsn,
     pub(crate) max_header_stable_lsn: Arc<AtomicLsn>,
     pub(crate) segment_accountant: Mutex<SegmentAccountant>,
+
+    // when each stable Lsn was reached, for point-in-time recovery
+    timeline: Mutex<Timeline>,
+
+    // writes sealed buffers out instead of the thread sealing them
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub(crate) uring: Option<uring::Uring>,
 }
 
 /// `IoBufs` is a set of lock-free buffers for coordinating
@@ -66,7 +96,7 @@ pub struct IoBufs {
 impl IoBufs {
     pub(crate) fn start(config: Config, snapshot: Snapshot) -> Result<Self> {
         // open file for writing
-        let file = &config.file;
+        let file = &*config.storage;
 
         let io_buf_size = config.io_buf_size;
 
@@ -82,10 +112,10 @@ impl IoBufs {
         } else {
             let width = match file.read_message(snapshot_last_lid, snapshot_last_lsn, &config) {
                 Ok(LogRead::Failed(_, len)) | Ok(LogRead::Inline(_, _, len)) => {
-                    len + MSG_HEADER_LEN as u32
+                    len + config.msg_header_len() as u32
                 }
                 Ok(LogRead::Blob(_header, _buf, _blob_ptr)) => {
-                    (BLOB_INLINE_LEN + MSG_HEADER_LEN) as u32
+                    (BLOB_INLINE_LEN + config.msg_header_len()) as u32
                 }
                 other => {
                     // we can overwrite this non-flush
@@ -122,14 +152,21 @@ impl IoBufs {
             if next_lsn == 0 {
                 assert_eq!(next_lid, 0);
             }
-            let lid = segment_accountant.next(next_lsn)?;
+            // nothing is ever written when read-only, and the file may
+            // extend well past a recovered point in time
+            let key = segment_key(&config, None)?;
+            let lid = if config.read_only {
+                next_lid
+            } else {
+                segment_accountant.next(next_lsn, key.as_ref().map(|(id, _)| *id))?
+            };
             if next_lsn == 0 {
                 assert_eq!(0, lid);
             }
 
             iobuf.lid = lid;
             iobuf.capacity = io_buf_size;
-            iobuf.store_segment_header(0, next_lsn, stable);
+            iobuf.store_segment_header(0, next_lsn, stable, key, config.checksum);
 
             debug!(
                 "starting log at clean offset {}, recovered lsn {}",
@@ -142,6 +179,18 @@ impl IoBufs {
             iobuf.capacity = io_buf_size - offset;
             iobuf.lsn = next_lsn;
 
+            // keep sealing with the key the segment started with
+            if config.keys.is_some() {
+                let segment_lid = next_lid - offset as LogId;
+                let segment_header = file.read_segment_header(segment_lid, &config)?;
+                if !segment_header.ok || segment_header.key_id.is_none() {
+                    return Err(Error::Corruption {
+                        at: DiskPtr::Inline(segment_lid),
+                    });
+                }
+                iobuf.key = segment_key(&config, segment_header.key_id)?;
+            }
+
             debug!(
                 "starting log at split offset {}, recovered lsn {}",
                 next_lid, next_lsn
@@ -149,7 +198,12 @@ impl IoBufs {
         }
 
         // remove all blob files larger than our stable offset
-        gc_blobs(&config, stable)?;
+        if !config.read_only {
+            gc_blobs(&config, stable)?;
+        }
+
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        let uring = uring::Uring::new(&config);
 
         Ok(Self {
             config,
@@ -158,11 +212,18 @@ impl IoBufs {
 
             intervals: Mutex::new(vec![]),
             interval_updated: Condvar::new(),
+            #[cfg(feature = "async")]
+            stable_waiters: Mutex::new(vec![]),
 
+            written_lsn: AtomicLsn::new(stable),
             stable_lsn: AtomicLsn::new(stable),
+            syncing: Mutex::new(()),
             max_reserved_lsn: AtomicLsn::new(stable),
             max_header_stable_lsn: Arc::new(AtomicLsn::new(snapshot_max_header_stable_lsn)),
             segment_accountant: Mutex::new(segment_accountant),
+            timeline: Mutex::new(Timeline::default()),
+            #[cfg(all(target_os = "linux", feature = "io_uring"))]
+            uring,
         })
     }
 
@@ -216,7 +277,7 @@ impl IoBufs {
         trace!("iterating from lsn {}", lsn);
         let io_buf_size = self.config.io_buf_size;
         let segment_base_lsn = lsn / io_buf_size as Lsn * io_buf_size as Lsn;
-        let min_lsn = segment_base_lsn + SEG_HEADER_LEN as Lsn;
+        let min_lsn = segment_base_lsn + self.config.seg_header_len() as Lsn;
 
         // corrected_lsn accounts for the segment header length
         let corrected_lsn = std::cmp::max(lsn, min_lsn);
@@ -228,6 +289,7 @@ impl IoBufs {
             max_lsn: self.stable(),
             cur_lsn: corrected_lsn,
             segment_base: None,
+            undecryptable: None,
             segment_iter,
         }
     }
@@ -237,6 +299,35 @@ impl IoBufs {
         self.stable_lsn.load(SeqCst) as Lsn
     }
 
+    /// Returns the last offset written to the log, which may not be
+    /// stable yet.
+    pub(super) fn written(&self) -> Lsn {
+        self.written_lsn.load(SeqCst) as Lsn
+    }
+
+    /// Syncs the log, making everything written to it so far stable.
+    pub(crate) fn sync_written(&self) -> Result<()> {
+        let _syncing = self.syncing.lock();
+
+        // everything written before a sync that finished while we
+        // were waiting for the lock is stable already
+        let written = self.written();
+        if self.stable() >= written {
+            return Ok(());
+        }
+
+        io_fail!(self, "log sync");
+
+        trace!("syncing the log up to lsn {}", written);
+        self.config.storage.sync_log()?;
+
+        let _intervals = self.intervals.lock();
+        self.stable_lsn.fetch_max(written, SeqCst);
+        self.notify_interval_updated();
+
+        Ok(())
+    }
+
     // Adds a header to the front of the buffer
     pub(crate) fn encapsulate(
         &self,
@@ -262,37 +353,30 @@ impl IoBufs {
             in_buf
         };
 
-        assert_eq!(out_buf.len(), to_reserve.len() + MSG_HEADER_LEN);
+        // the caller seals page data with the key of its IO buffer
+        let seal = self.config.keys.is_some() && kind.is_inline_data();
+        let header_len = self.config.msg_header_len();
+        let data_len = to_reserve.len() + if seal { SEAL_LEN } else { 0 };
+        assert_eq!(out_buf.len(), data_len + header_len);
 
         let header = MessageHeader {
             kind,
             pid,
             lsn,
-            len: u32::try_from(to_reserve.len()).unwrap(),
-            crc32: 0,
+            len: u32::try_from(data_len).unwrap(),
+            digest: Digest::default(),
         };
 
-        let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
-
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                header_bytes.as_ptr(),
-                out_buf.as_mut_ptr(),
-                MSG_HEADER_LEN,
-            );
-            std::ptr::copy_nonoverlapping(
-                to_reserve.as_ptr(),
-                out_buf.as_mut_ptr().add(MSG_HEADER_LEN),
-                to_reserve.len(),
-            );
-        }
+        let (header_bytes, data) = out_buf.split_at_mut(header_len);
+        header.write(header_bytes);
+        data[..to_reserve.len()].copy_from_slice(to_reserve);
 
         Ok(())
     }
 
     // Write an IO buffer's data to stable storage and set up the
     // next IO buffer for writing.
-    pub(crate) fn write_to_log(&self, iobuf: &IoBuf) -> Result<()> {
+    pub(crate) fn write_to_log(&self, iobuf: &Arc<IoBuf>) -> Result<()> {
         let _measure = Measure::new(&M.write_to_log);
         let header = iobuf.get_header();
         let lid = iobuf.lid;
@@ -325,69 +409,71 @@ impl IoBufs {
 
         let maxed = iobuf.linearized(|| iobuf.get_maxed());
         let unused_space = capacity - bytes_to_write;
-        let should_pad = maxed && unused_space >= MSG_HEADER_LEN;
+        let header_len = self.config.msg_header_len();
+        let should_pad = maxed && unused_space >= header_len;
 
         // a pad is a null message written to the end of a buffer
         // to signify that nothing else will be written into it
         if should_pad {
             let data = unsafe { (*iobuf.buf.get()).as_mut_slice() };
-            let pad_len = capacity - bytes_to_write - MSG_HEADER_LEN;
-
-            // take the crc of the random bytes already after where we
-            // would place our header.
-            let padding_bytes = vec![MessageKind::Corrupted.into(); pad_len];
+            let pad_len = capacity - bytes_to_write - header_len;
 
             let header = MessageHeader {
                 kind: MessageKind::Pad,
                 pid: PageId::max_value(),
                 lsn: base_lsn + bytes_to_write as Lsn,
                 len: u32::try_from(pad_len).unwrap(),
-                crc32: 0,
+                digest: Digest::default(),
             };
 
-            let header_bytes: [u8; MSG_HEADER_LEN] = header.into();
-
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    header_bytes.as_ptr(),
-                    data.as_mut_ptr().add(bytes_to_write),
-                    MSG_HEADER_LEN,
-                );
-                std::ptr::copy_nonoverlapping(
-                    padding_bytes.as_ptr(),
-                    data.as_mut_ptr().add(bytes_to_write + MSG_HEADER_LEN),
-                    pad_len,
-                );
+            let (header_bytes, padding_bytes) =
+                data[bytes_to_write..capacity].split_at_mut(header_len);
+            header.write(header_bytes);
+            for byte in padding_bytes.iter_mut() {
+                *byte = MessageKind::Corrupted.into();
             }
 
-            let mut hasher = crc32fast::Hasher::new();
-            hasher.update(&padding_bytes);
-            hasher.update(&header_bytes);
-            let crc32 = hasher.finalize();
-            let crc32_arr = u32_to_arr(crc32 ^ 0xFFFF_FFFF);
-
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    crc32_arr.as_ptr(),
-                    data.as_mut_ptr()
-                        .add(bytes_to_write + MSG_HEADER_LEN - std::mem::size_of::<u32>()),
-                    std::mem::size_of::<u32>(),
-                );
-            }
+            let mut hasher = self.config.checksum.hasher();
+            hasher.update(padding_bytes);
+            hasher.update(header_bytes);
+            write_digest(&hasher.finalize(), header_bytes);
         }
 
         let total_len = if maxed { capacity } else { bytes_to_write };
 
+        io_fail!(self, "buffer write");
+
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        {
+            if let Some(uring) = &self.uring {
+                if total_len > 0 {
+                    // finish_write is called once it completes
+                    return uring.submit(iobuf, total_len);
+                }
+            }
+        }
+
         let data = unsafe { (*iobuf.buf.get()).as_mut_slice() };
 
-        let f = &self.config.file;
-        io_fail!(self, "buffer write");
-        f.pwrite_all(&data[..total_len], lid)?;
-        if !self.config.temporary {
-            f.sync_all()?;
+        let f = &self.config.storage;
+        f.write_log(&data[..total_len], lid)?;
+        if self.config.sync_every_write {
+            f.sync_log()?;
         }
+
+        self.finish_write(iobuf, total_len)
+    }
+
+    // Mark the first `total_len` bytes of an IO buffer as written
+    // out, and as stable too if `sync_every_write` synced them.
+    pub(crate) fn finish_write(&self, iobuf: &IoBuf, total_len: usize) -> Result<()> {
         io_fail!(self, "buffer write post");
 
+        let lid = iobuf.lid;
+        let base_lsn = iobuf.lsn;
+        let io_buf_size = self.config.io_buf_size;
+        let maxed = iobuf.linearized(|| iobuf.get_maxed());
+
         if total_len > 0 {
             let complete_len = if maxed {
                 let lsn_idx = base_lsn / io_buf_size as Lsn;
@@ -407,6 +493,10 @@ impl IoBufs {
                 complete_len
             );
             self.mark_interval(base_lsn, complete_len);
+
+            if self.config.history_window > 0 {
+                self.timeline.lock().record(&self.config, self.written())?;
+            }
         }
 
         M.written_bytes.measure(total_len as f64);
@@ -471,21 +561,24 @@ impl IoBufs {
 
         while let Some(&(low, high)) = intervals.last() {
             assert!(low <= high);
-            let cur_stable = self.stable_lsn.load(SeqCst);
+            let cur_written = self.written_lsn.load(SeqCst);
             assert!(
-                low > cur_stable,
-                "somehow, we marked offset {} stable while \
+                low > cur_written,
+                "somehow, we marked offset {} written while \
                  interval {}-{} had not yet been applied!",
-                cur_stable,
+                cur_written,
                 low,
                 high
             );
-            if cur_stable + 1 == low {
-                let old = self.stable_lsn.swap(high, SeqCst);
+            if cur_written + 1 == low {
+                let old = self.written_lsn.swap(high, SeqCst);
                 assert_eq!(
-                    old, cur_stable,
-                    "concurrent stable offset modification detected"
+                    old, cur_written,
+                    "concurrent written offset modification detected"
                 );
+                if self.config.sync_every_write {
+                    self.stable_lsn.fetch_max(high, SeqCst);
+                }
                 debug!("new highest interval: {} - {}", low, high);
                 intervals.pop();
                 updated = true;
@@ -500,7 +593,31 @@ impl IoBufs {
 
         if updated {
             // safe because self.intervals mutex is already held
-            self.interval_updated.notify_all();
+            self.notify_interval_updated();
+        }
+    }
+
+    /// Wake everything waiting for the written or stable Lsn to change:
+    /// threads blocked in `make_stable` and, with the `async` feature,
+    /// the futures whose Lsn is now written, or all of them after an
+    /// error. Those go on to sync it themselves.
+    pub(super) fn notify_interval_updated(&self) {
+        self.interval_updated.notify_all();
+
+        #[cfg(feature = "async")]
+        {
+            let written = self.written();
+            let failed = self.config.global_error().is_err();
+            let mut waiters = self.stable_waiters.lock();
+            let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut *waiters)
+                .into_iter()
+                .partition(|(lsn, _)| failed || *lsn <= written);
+            *waiters = waiting;
+            drop(waiters);
+
+            for (_, waker) in ready {
+                waker.wake();
+            }
         }
     }
 
@@ -521,36 +638,37 @@ pub(crate) fn make_stable(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Result<usize> {
         return Ok(0);
     }
 
-    let mut stable = first_stable;
+    make_written(iobufs, lsn)?;
 
-    while stable < lsn {
-        if let Err(e) = iobufs.config.global_error() {
-            let _lock = iobufs.intervals.lock();
-            iobufs.interval_updated.notify_all();
-            return Err(e);
-        }
+    // a single sync covers everything written by now, whoever
+    // wrote it
+    if iobufs.stable() < lsn {
+        iobufs.sync_written()?;
+    }
 
-        let iobuf = iobufs.current_iobuf();
-        let header = iobuf.get_header();
-        if offset(header) == 0 || is_sealed(header) || iobuf.lsn > lsn {
-            // nothing to write, don't bother sealing
-            // current IO buffer.
-        } else {
-            maybe_seal_and_write_iobuf(iobufs, &iobuf, header, false)?;
-            stable = iobufs.stable();
-            // NB we have to continue here to possibly clear
-            // the next io buffer, which may have dirty
-            // data we need to flush (and maybe no other
-            // thread is still alive to do so)
-            continue;
-        }
+    Ok(assert_usize(iobufs.stable() - first_stable))
+}
+
+/// Blocks until the specified log sequence number has
+/// been written to the log, without syncing it. Returns
+/// the number of bytes written.
+pub(crate) fn make_written(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Result<usize> {
+    let first_written = iobufs.written();
+    if first_written >= lsn {
+        return Ok(0);
+    }
+
+    let mut written = first_written;
 
-        // block until another thread updates the stable lsn
+    while written < lsn {
+        seal_up_to(iobufs, lsn)?;
+
+        // block until another thread updates the written lsn
         let mut waiter = iobufs.intervals.lock();
 
-        stable = iobufs.stable();
-        if stable < lsn {
-            trace!("waiting on cond var for make_stable({})", lsn);
+        written = iobufs.written();
+        if written < lsn {
+            trace!("waiting on cond var for make_written({})", lsn);
 
             if cfg!(feature = "event_log") {
                 let timeout = iobufs
@@ -564,12 +682,12 @@ pub(crate) fn make_stable(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Result<usize> {
                             .to_owned()
                     }
                     panic!(
-                        "{} failed to make_stable after 30 seconds. \
-                         waiting to stabilize lsn {}, current stable {} \
+                        "{} failed to make_written after 30 seconds. \
+                         waiting to write lsn {}, current written {} \
                          intervals: {:?}",
                         tn(),
                         lsn,
-                        iobufs.stable(),
+                        iobufs.written(),
                         waiter
                     );
                 }
@@ -577,12 +695,154 @@ pub(crate) fn make_stable(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Result<usize> {
                 iobufs.interval_updated.wait(&mut waiter);
             }
         } else {
-            trace!("make_stable({}) returning", lsn);
+            trace!("make_written({}) returning", lsn);
+            break;
+        }
+    }
+
+    Ok(assert_usize(written - first_written))
+}
+
+/// Seal and write out the IO buffers holding data up to `lsn`,
+/// without waiting for the ones other threads are responsible for.
+fn seal_up_to(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Result<()> {
+    while iobufs.written() < lsn {
+        if let Err(e) = iobufs.config.global_error() {
+            let _lock = iobufs.intervals.lock();
+            iobufs.notify_interval_updated();
+            return Err(e);
+        }
+
+        let iobuf = iobufs.current_iobuf();
+        let header = iobuf.get_header();
+        if offset(header) == 0 || is_sealed(header) || iobuf.lsn > lsn {
+            // nothing to write, don't bother sealing
+            // current IO buffer.
             break;
         }
+
+        // NB we have to keep going to possibly clear
+        // the next io buffer, which may have dirty
+        // data we need to flush (and maybe no other
+        // thread is still alive to do so)
+        maybe_seal_and_write_iobuf(iobufs, &iobuf, header, false)?;
     }
 
-    Ok(assert_usize(stable - first_stable))
+    Ok(())
+}
+
+/// Blocks until `lsn` is stable, leaving the sealing and writing
+/// to whoever was asked to do it (see `Flusher`). Returns the
+/// number of bytes written since `first_stable` was the stable Lsn,
+/// which has to be read before asking, as the flusher may be done
+/// by the time this is called.
+pub(crate) fn wait_for_stable(iobufs: &Arc<IoBufs>, first_stable: Lsn, lsn: Lsn) -> Result<usize> {
+    let mut waiter = iobufs.intervals.lock();
+    while iobufs.stable() < lsn {
+        iobufs.config.global_error()?;
+        iobufs.interval_updated.wait(&mut waiter);
+    }
+
+    Ok(assert_usize(iobufs.stable() - first_stable))
+}
+
+/// Like `make_stable`, but without blocking: the IO buffers are
+/// sealed on the IO thread pool, and the returned future is woken
+/// by `mark_interval` once `lsn` is written, to sync it there too.
+#[cfg(feature = "async")]
+pub(crate) fn make_stable_async(iobufs: &Arc<IoBufs>, lsn: Lsn) -> Stabilized {
+    let first_stable = iobufs.stable();
+    let sealing = if first_stable >= lsn {
+        None
+    } else {
+        let iobufs = iobufs.clone();
+        Some(Promise::new(move || seal_up_to(&iobufs, lsn)))
+    };
+
+    Stabilized {
+        iobufs: iobufs.clone(),
+        lsn,
+        first_stable,
+        sealing,
+        syncing: None,
+    }
+}
+
+/// Like `flush`, resolving to the number of bytes written.
+#[cfg(feature = "async")]
+pub(crate) fn flush_async(iobufs: &Arc<IoBufs>) -> Stabilized {
+    let max_reserved_lsn = iobufs.max_reserved_lsn.load(SeqCst) as Lsn;
+    make_stable_async(iobufs, max_reserved_lsn)
+}
+
+/// A future resolving once an Lsn is stable on disk, see
+/// `make_stable_async`.
+#[cfg(feature = "async")]
+pub(crate) struct Stabilized {
+    iobufs: Arc<IoBufs>,
+    lsn: Lsn,
+    first_stable: Lsn,
+    sealing: Option<Promise<Result<()>>>,
+    syncing: Option<Promise<Result<()>>>,
+}
+
+#[cfg(feature = "async")]
+impl Future for Stabilized {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Some(sealing) = this.sealing.as_mut() {
+            match Pin::new(sealing).poll(cx) {
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(_) => this.sealing = None,
+                Poll::Pending => {}
+            }
+        }
+
+        loop {
+            if let Some(syncing) = this.syncing.as_mut() {
+                match Pin::new(syncing).poll(cx) {
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                    Poll::Ready(Some(Ok(()))) => this.syncing = None,
+                    Poll::Ready(None) => {
+                        return Poll::Ready(Err(Error::ReportableBug(
+                            "syncing the log panicked".to_owned(),
+                        )))
+                    }
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            if let Err(e) = this.iobufs.config.global_error() {
+                return Poll::Ready(Err(e));
+            }
+
+            // the written and stable lsns only move under the intervals
+            // mutex, so they can't advance between these checks and
+            // registering the waker.
+            let intervals = this.iobufs.intervals.lock();
+            let stable = this.iobufs.stable();
+            if stable >= this.lsn {
+                return Poll::Ready(Ok(assert_usize(stable - this.first_stable)));
+            }
+
+            if this.iobufs.written() < this.lsn {
+                this.iobufs
+                    .stable_waiters
+                    .lock()
+                    .push((this.lsn, cx.waker().clone()));
+
+                return Poll::Pending;
+            }
+            drop(intervals);
+
+            // written, but nobody has synced it yet
+            let iobufs = this.iobufs.clone();
+            this.syncing = Some(Promise::new(move || iobufs.sync_written()));
+        }
+    }
 }
 
 /// Called by users who wish to force the current buffer
@@ -622,7 +882,14 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     let sealed = mk_sealed(header);
     let res_len = offset(sealed);
 
-    let maxed = from_reserve || capacity - res_len < MSG_HEADER_LEN;
+    // a segment sealed with a key that has been rotated away from is
+    // not written to any further than needed
+    let stale_key = match (&iobuf.key, &iobufs.config.keys) {
+        (Some((key_id, _)), Some(keys)) => *key_id != keys.current_id(),
+        _ => false,
+    };
+
+    let maxed = from_reserve || stale_key || capacity - res_len < iobufs.config.msg_header_len();
 
     let worked = iobuf.linearized(|| {
         if iobuf.cas_header(header, sealed).is_err() {
@@ -648,7 +915,7 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     }
 
     assert!(
-        capacity + SEG_HEADER_LEN >= res_len,
+        capacity + iobufs.config.seg_header_len() >= res_len,
         "res_len of {} higher than buffer capacity {}",
         res_len,
         capacity
@@ -668,6 +935,7 @@ pub(crate) fn maybe_seal_and_write_iobuf(
 
     let measure_assign_offset = Measure::new(&M.assign_offset);
 
+    let next_key;
     let next_offset = if maxed {
         // roll lsn to the next offset
         let lsn_idx = lsn / io_buf_size as Lsn;
@@ -680,12 +948,21 @@ pub(crate) fn maybe_seal_and_write_iobuf(
             lid + res_len as LogId,
         );
 
-        match iobufs.with_sa(|sa| sa.next(next_lsn)) {
-            Ok(ret) => ret,
+        // a new segment is sealed with whatever key is current now
+        let next = segment_key(&iobufs.config, None).and_then(|key| {
+            let key_id = key.as_ref().map(|(id, _)| *id);
+            let lid = iobufs.with_sa(|sa| sa.next(next_lsn, key_id))?;
+            Ok((lid, key))
+        });
+        match next {
+            Ok((lid, key)) => {
+                next_key = key;
+                lid
+            }
             Err(e) => {
                 iobufs.config.set_global_error(e.clone());
                 let _lock = iobufs.intervals.lock();
-                iobufs.interval_updated.notify_all();
+                iobufs.notify_interval_updated();
                 return Err(e);
             }
         }
@@ -696,6 +973,7 @@ pub(crate) fn maybe_seal_and_write_iobuf(
             lid + res_len as LogId
         );
         next_lsn += res_len as Lsn;
+        next_key = iobuf.key.clone();
 
         lid + res_len as LogId
     };
@@ -709,12 +987,19 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     // its entire life cycle as soon as we do that.
     if maxed {
         next_iobuf.capacity = io_buf_size;
-        next_iobuf.store_segment_header(sealed, next_lsn, iobufs.stable());
+        next_iobuf.store_segment_header(
+            sealed,
+            next_lsn,
+            iobufs.stable(),
+            next_key,
+            iobufs.config.checksum,
+        );
     } else {
         let new_cap = capacity - res_len;
         assert_ne!(new_cap, 0);
         next_iobuf.capacity = new_cap;
         next_iobuf.lsn = next_lsn;
+        next_iobuf.key = next_key;
         let last_salt = salt(sealed);
         let new_salt = bump_salt(last_salt);
         next_iobuf.set_header(new_salt);
@@ -727,7 +1012,7 @@ pub(crate) fn maybe_seal_and_write_iobuf(
     let mut mu = iobufs.iobuf.write();
     *mu = Arc::new(next_iobuf);
     drop(mu);
-    iobufs.interval_updated.notify_all();
+    iobufs.notify_interval_updated();
     drop(intervals);
 
     drop(measure_assign_offset);
@@ -744,9 +1029,10 @@ pub(crate) fn maybe_seal_and_write_iobuf(
         let _result = Promise::new(move || {
             if let Err(e) = iobufs.write_to_log(&iobuf) {
                 error!("hit error while writing iobuf with lsn {}: {:?}", lsn, e);
-                let _lock = iobufs.intervals.lock();
-                iobufs.interval_updated.notify_all();
+                // set the error first, so that whoever is woken sees it
                 iobufs.config.set_global_error(e);
+                let _lock = iobufs.intervals.lock();
+                iobufs.notify_interval_updated();
             }
         });
 
@@ -790,6 +1076,7 @@ impl IoBuf {
             maxed: AtomicBool::new(false),
             linearizer: Mutex::new(()),
             stored_max_stable_lsn: -1,
+            key: None,
         }
     }
 
@@ -807,9 +1094,17 @@ impl IoBuf {
     // We write a new segment header to the beginning of the buffer
     // for assistance during recovery. The caller is responsible
     // for ensuring that the IoBuf's capacity has been set properly.
-    pub(crate) fn store_segment_header(&mut self, last: Header, lsn: Lsn, max_stable_lsn: Lsn) {
+    pub(crate) fn store_segment_header(
+        &mut self,
+        last: Header,
+        lsn: Lsn,
+        max_stable_lsn: Lsn,
+        key: Option<(KeyId, Arc<Cipher>)>,
+        checksum: Checksum,
+    ) {
         debug!("storing lsn {} in beginning of buffer", lsn);
-        assert!(self.capacity >= SEG_HEADER_LEN);
+        let header_len = SEG_HEADER_LEN + if key.is_some() { KEY_ID_LEN } else { 0 };
+        assert!(self.capacity >= header_len);
 
         self.stored_max_stable_lsn = max_stable_lsn;
 
@@ -818,22 +1113,18 @@ impl IoBuf {
         let header = SegmentHeader {
             lsn,
             max_stable_lsn,
+            key_id: key.as_ref().map(|(id, _)| *id),
+            checksum,
             ok: true,
         };
-        let header_bytes: [u8; SEG_HEADER_LEN] = header.into();
+        header.write(&mut self.buf.get_mut()[..header_len]);
 
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                header_bytes.as_ptr(),
-                (*self.buf.get()).as_mut_ptr(),
-                SEG_HEADER_LEN,
-            );
-        }
+        self.key = key;
 
         // ensure writes to the buffer land after our header.
         let last_salt = salt(last);
         let new_salt = bump_salt(last_salt);
-        let bumped = bump_offset(new_salt, SEG_HEADER_LEN);
+        let bumped = bump_offset(new_salt, header_len);
         self.set_header(bumped);
     }
 
@@ -909,3 +1200,16 @@ pub(crate) const fn bump_salt(v: Header) -> Header {
 pub(crate) const fn salt(v: Header) -> Header {
     v >> 32 << 32
 }
+
+/// The key to seal a segment's messages with when encrypted: the one
+/// with `key_id`, or the current one for a new segment.
+fn segment_key(config: &Config, key_id: Option<KeyId>) -> Result<Option<(KeyId, Arc<Cipher>)>> {
+    let keys = match &config.keys {
+        Some(keys) => keys,
+        None => return Ok(None),
+    };
+    match key_id {
+        Some(id) => Ok(Some((id, keys.get(id)?))),
+        None => keys.current().map(Some),
+    }
+}
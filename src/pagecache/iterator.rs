@@ -10,6 +10,10 @@ pub struct LogIter {
     pub segment_base: Option<LogId>,
     pub max_lsn: Lsn,
     pub cur_lsn: Lsn,
+    /// Where the iterator stopped at a message that passed its
+    /// checksum but failed to decrypt, or was sealed with a key that
+    /// isn't provided.
+    pub undecryptable: Option<Lsn>,
 }
 
 impl Iterator for LogIter {
@@ -21,7 +25,7 @@ impl Iterator for LogIter {
         // return None if there are no more remaining segments.
         loop {
             let remaining_seg_too_small_for_msg =
-                !valid_entry_offset(self.cur_lsn as LogId, self.config.io_buf_size);
+                !valid_entry_offset(self.cur_lsn as LogId, &self.config);
 
             if self.segment_base.is_none() || remaining_seg_too_small_for_msg {
                 if let Some((next_lsn, next_lid)) = self.segment_iter.next() {
@@ -31,7 +35,6 @@ impl Iterator for LogIter {
                          that contain the initial cur_lsn value or higher"
                     );
 
-                    #[cfg(target_os = "linux")]
                     self.fadvise_willneed(next_lid);
 
                     if let Err(e) = self.read_segment(next_lsn, next_lid) {
@@ -57,12 +60,13 @@ impl Iterator for LogIter {
             let lid = self.segment_base.unwrap()
                 + (self.cur_lsn % self.config.io_buf_size as Lsn) as LogId;
 
-            let f = &self.config.file;
+            let f = &*self.config.storage;
+            let header_len = self.config.msg_header_len();
 
             match f.read_message(lid, self.cur_lsn, &self.config) {
                 Ok(LogRead::Blob(header, _buf, blob_ptr)) => {
                     trace!("read blob flush in LogIter::next");
-                    let sz = MSG_HEADER_LEN + BLOB_INLINE_LEN;
+                    let sz = header_len + BLOB_INLINE_LEN;
                     self.cur_lsn += sz as Lsn;
 
                     return Some((
@@ -75,7 +79,7 @@ impl Iterator for LogIter {
                 }
                 Ok(LogRead::Inline(header, _buf, on_disk_len)) => {
                     trace!("read inline flush in LogIter::next");
-                    let sz = MSG_HEADER_LEN + on_disk_len as usize;
+                    let sz = header_len + on_disk_len as usize;
                     self.cur_lsn += sz as Lsn;
 
                     return Some((
@@ -90,13 +94,13 @@ impl Iterator for LogIter {
                     if last_lsn_in_batch > self.max_lsn {
                         return None;
                     } else {
-                        self.cur_lsn += (MSG_HEADER_LEN + BATCH_MANIFEST_INLINE_LEN) as Lsn;
+                        self.cur_lsn += (header_len + BATCH_MANIFEST_INLINE_LEN) as Lsn;
                         continue;
                     }
                 }
                 Ok(LogRead::Failed(_, on_disk_len)) => {
                     trace!("read zeroed in LogIter::next");
-                    self.cur_lsn += Lsn::from(MSG_HEADER_LEN as u32 + on_disk_len);
+                    self.cur_lsn += Lsn::from(header_len as u32 + on_disk_len);
                 }
                 Ok(LogRead::Corrupted(_len)) => {
                     trace!(
@@ -117,7 +121,7 @@ impl Iterator for LogIter {
                          pointer at lsn {} ptr {}",
                         header.lsn, blob_ptr
                     );
-                    self.cur_lsn += (MSG_HEADER_LEN + BLOB_INLINE_LEN) as Lsn;
+                    self.cur_lsn += (header_len + BLOB_INLINE_LEN) as Lsn;
                     continue;
                 }
                 Err(e) => {
@@ -126,6 +130,14 @@ impl Iterator for LogIter {
                          with expected lsn {} during iteration: {}",
                         lid, self.cur_lsn, e
                     );
+                    // a key that is wrong, or no longer provided
+                    if let Error::Corruption {
+                        at: DiskPtr::Inline(_),
+                    }
+                    | Error::Unsupported(_) = e
+                    {
+                        self.undecryptable = Some(self.cur_lsn);
+                    }
                     return None;
                 }
             }
@@ -145,8 +157,8 @@ impl LogIter {
         // we add segment_len to this check because we may be getting the
         // initial segment that is a bit behind where we left off before.
         assert!(lsn + self.config.io_buf_size as Lsn >= self.cur_lsn);
-        let f = &self.config.file;
-        let segment_header = f.read_segment_header(offset)?;
+        let f = &*self.config.storage;
+        let segment_header = f.read_segment_header(offset, &self.config)?;
         if offset % self.config.io_buf_size as LogId != 0 {
             debug!("segment offset not divisible by segment length");
             return Err(Error::Corruption {
@@ -175,40 +187,26 @@ impl LogIter {
 
         trace!("read segment header {:?}", segment_header);
 
-        self.cur_lsn = segment_header.lsn + SEG_HEADER_LEN as Lsn;
+        self.cur_lsn = segment_header.lsn + self.config.seg_header_len() as Lsn;
         self.segment_base = Some(offset);
 
         Ok(())
     }
 
-    #[cfg(target_os = "linux")]
     fn fadvise_willneed(&self, lid: LogId) {
-        use std::os::unix::io::AsRawFd;
-
-        let f = &self.config.file;
-        let ret = unsafe {
-            libc::posix_fadvise(
-                f.as_raw_fd(),
-                libc::off_t::try_from(lid).unwrap(),
-                libc::off_t::try_from(self.config.io_buf_size).unwrap(),
-                libc::POSIX_FADV_WILLNEED,
-            )
-        };
-        if ret != 0 {
-            panic!(
-                "failed to call fadvise: {}",
-                std::io::Error::from_raw_os_error(ret)
-            );
-        }
+        self.config
+            .storage
+            .will_read_log(lid, self.config.io_buf_size as u64);
     }
 }
 
-fn valid_entry_offset(lid: LogId, segment_len: usize) -> bool {
+fn valid_entry_offset(lid: LogId, config: &Config) -> bool {
+    let segment_len = config.io_buf_size;
     let seg_start = lid / segment_len as LogId * segment_len as LogId;
 
-    let max_lid = seg_start + segment_len as LogId - MSG_HEADER_LEN as LogId;
+    let max_lid = seg_start + segment_len as LogId - config.msg_header_len() as LogId;
 
-    let min_lid = seg_start + SEG_HEADER_LEN as LogId;
+    let min_lid = seg_start + config.seg_header_len() as LogId;
 
     lid >= min_lid && lid <= max_lid
 }
@@ -219,7 +217,7 @@ fn scan_segment_lsns(min: Lsn, config: &Config) -> Result<(BTreeMap<Lsn, LogId>,
     fn fetch(idx: u64, min: Lsn, config: &Config) -> Option<(LogId, SegmentHeader)> {
         let segment_len = u64::try_from(config.io_buf_size).unwrap();
         let base_lid = idx * segment_len;
-        let segment = config.file.read_segment_header(base_lid).ok()?;
+        let segment = config.storage.read_segment_header(base_lid, config).ok()?;
         trace!(
             "SA scanned header at lid {} during startup: {:?}",
             base_lid,
@@ -242,10 +240,10 @@ fn scan_segment_lsns(min: Lsn, config: &Config) -> Result<(BTreeMap<Lsn, LogId>,
 
     let segment_len = LogId::try_from(config.io_buf_size).unwrap();
 
-    let f = &config.file;
-    let file_len = f.metadata()?.len();
+    let f = &*config.storage;
+    let file_len = f.log_len()?;
     let segments = (file_len / segment_len)
-        + if file_len % segment_len < LogId::try_from(SEG_HEADER_LEN).unwrap() {
+        + if file_len % segment_len < LogId::try_from(config.seg_header_len()).unwrap() {
             0
         } else {
             1
@@ -274,6 +272,18 @@ fn scan_segment_lsns(min: Lsn, config: &Config) -> Result<(BTreeMap<Lsn, LogId>,
         .filter_map(|p| p.resolve().unwrap())
         .collect();
 
+    // the config file is not all that records the checksum
+    if let Some((lid, header)) = headers
+        .iter()
+        .find(|(_, header)| header.checksum != config.checksum)
+    {
+        return Err(Error::Unsupported(format!(
+            "the segment at {} was written with the {:?} checksum, \
+             but the log is opened with {:?}",
+            lid, header.checksum, config.checksum
+        )));
+    }
+
     let mut ordering = BTreeMap::new();
     let mut max_header_stable_lsn = 0;
 
@@ -297,7 +307,7 @@ fn scan_segment_lsns(min: Lsn, config: &Config) -> Result<(BTreeMap<Lsn, LogId>,
 
     // Check that the segments above max_header_stable_lsn
     // properly link their previous segment pointers.
-    let ordering = clean_tail_tears(max_header_stable_lsn, ordering, &config, &f)?;
+    let ordering = clean_tail_tears(max_header_stable_lsn, ordering, &config, f)?;
 
     Ok((ordering, max_header_stable_lsn))
 }
@@ -311,7 +321,7 @@ fn clean_tail_tears(
     max_header_stable_lsn: Lsn,
     mut ordering: BTreeMap<Lsn, LogId>,
     config: &Config,
-    f: &std::fs::File,
+    f: &dyn StorageBackend,
 ) -> Result<BTreeMap<Lsn, LogId>> {
     let io_buf_size = config.io_buf_size as Lsn;
 
@@ -351,6 +361,7 @@ fn clean_tail_tears(
         config: config.clone(),
         segment_iter: Box::new(logical_tail.into_iter()),
         segment_base: None,
+        undecryptable: None,
         max_lsn: missing_item_in_tail.unwrap_or(Lsn::max_value()),
         cur_lsn: 0,
     };
@@ -376,13 +387,20 @@ fn clean_tail_tears(
     {
         debug!("zeroing torn segment with lsn {} at lid {}", lsn, lid);
 
+        // a read-only log never allocates segments, and must not be
+        // changed underneath whoever else has it open.
+        if config.read_only {
+            continue;
+        }
+
         // NB we intentionally corrupt this header to prevent any segment
         // from being allocated which would duplicate its LSN, messing
         // up recovery in the future.
-        f.pwrite_all(&*vec![MessageKind::Corrupted.into(); SEG_HEADER_LEN], *lid)?;
-        if !config.temporary {
-            f.sync_all()?;
-        }
+        f.write_log(
+            &*vec![MessageKind::Corrupted.into(); config.seg_header_len()],
+            *lid,
+        )?;
+        f.sync_log()?;
     }
 
     ordering = ordering
@@ -414,6 +432,7 @@ pub(super) fn raw_segment_iter_from(lsn: Lsn, config: &Config) -> Result<(LogIte
         max_lsn: Lsn::max_value(),
         cur_lsn: 0,
         segment_base: None,
+        undecryptable: None,
         segment_iter: tip_segment_iter,
     };
 
@@ -424,6 +443,16 @@ pub(super) fn raw_segment_iter_from(lsn: Lsn, config: &Config) -> Result<(LogIte
     // in the actual iterator.
     while let Some(_) = tip_iter.next() {}
 
+    // anything written after a message that was sealed with another
+    // key would overwrite the rest of the log
+    if let Some(lsn) = tip_iter.undecryptable {
+        return Err(Error::Unsupported(format!(
+            "the log message at lsn {} does not decrypt, \
+             it was written with another key or one that is no longer provided",
+            lsn
+        )));
+    }
+
     let tip = tip_iter.cur_lsn;
 
     trace!("found max stable tip: {}", tip);
@@ -446,6 +475,7 @@ pub(super) fn raw_segment_iter_from(lsn: Lsn, config: &Config) -> Result<(LogIte
             max_lsn: tip,
             cur_lsn: 0,
             segment_base: None,
+            undecryptable: None,
             segment_iter,
         },
         max_header_stable_lsn,
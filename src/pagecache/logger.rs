@@ -22,6 +22,13 @@ impl Log {
     pub fn start(config: Config, snapshot: Snapshot) -> Result<Self> {
         let iobufs = Arc::new(IoBufs::start(config.clone(), snapshot)?);
 
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        {
+            if let Some(uring) = &iobufs.uring {
+                uring.start(Arc::downgrade(&iobufs))?;
+            }
+        }
+
         Ok(Self { iobufs, config })
     }
 
@@ -51,10 +58,12 @@ impl Log {
     pub fn read(&self, pid: PageId, lsn: Lsn, ptr: DiskPtr) -> Result<LogRead> {
         trace!("reading log pid {} lsn {} ptr {}", pid, lsn, ptr);
 
-        self.make_stable(lsn)?;
+        // the page cache serves reads of whatever was written to the
+        // log, synced or not
+        self.make_written(lsn)?;
 
         if ptr.is_inline() {
-            let f = &self.config.file;
+            let f = &self.config.storage;
             f.read_message(ptr.lid(), lsn, &self.config)
         } else {
             // we short-circuit the inline read
@@ -62,12 +71,12 @@ impl Log {
             // exist in the inline log.
             let (_, blob_ptr) = ptr.blob();
             read_blob(blob_ptr, &self.config).map(|(kind, buf)| {
-                let sz = MSG_HEADER_LEN + BLOB_INLINE_LEN;
+                let sz = self.config.msg_header_len() + BLOB_INLINE_LEN;
                 let header = MessageHeader {
                     kind,
                     pid,
                     lsn,
-                    crc32: 0,
+                    digest: Digest::default(),
                     len: sz as u32,
                 };
                 LogRead::Blob(header, buf, blob_ptr)
@@ -87,6 +96,14 @@ impl Log {
         iobuf::make_stable(&self.iobufs, lsn)
     }
 
+    /// blocks until the specified log sequence number has
+    /// been written to the log, leaving it to the OS when
+    /// it reaches the disk. Returns the number of bytes
+    /// written during this call.
+    pub fn make_written(&self, lsn: Lsn) -> Result<usize> {
+        iobuf::make_written(&self.iobufs, lsn)
+    }
+
     // SegmentAccountant access for coordination with the `PageCache`
     pub(crate) fn with_sa<B, F>(&self, f: F) -> B
     where
@@ -149,18 +166,31 @@ impl Log {
     ) -> Result<Reservation<'_>> {
         let _measure = Measure::new(&M.reserve_lat);
 
-        let total_buf_len = MSG_HEADER_LEN + buf.len();
+        if self.config.read_only {
+            return Err(Error::Unsupported(
+                "nothing can be written to a read-only log".into(),
+            ));
+        }
+
+        // page data is sealed right into the IO buffer once its Lsn
+        // is known. blob pointers and batch manifests are left as they
+        // are, blobs are sealed as they are written.
+        let encrypt = self.config.keys.is_some() && !is_blob_rewrite && pid != BATCH_MANIFEST_PID;
+
+        let header_len = self.config.msg_header_len();
+        let total_buf_len = header_len + buf.len() + if encrypt { SEAL_LEN } else { 0 };
 
         M.reserve_sz.measure(total_buf_len as f64);
 
-        let max_buf_size = (self.config.io_buf_size / MINIMUM_ITEMS_PER_SEGMENT) - SEG_HEADER_LEN;
+        let max_buf_size =
+            (self.config.io_buf_size / MINIMUM_ITEMS_PER_SEGMENT) - self.config.seg_header_len();
 
         let over_blob_threshold = total_buf_len > max_buf_size;
 
         assert!(!(over_blob_threshold && is_blob_rewrite));
 
         let inline_buf_len = if over_blob_threshold {
-            MSG_HEADER_LEN + std::mem::size_of::<Lsn>()
+            header_len + std::mem::size_of::<Lsn>()
         } else {
             total_buf_len
         };
@@ -198,6 +228,10 @@ impl Log {
             ),
         };
 
+        // encapsulate leaves room for the tag by the kind of message
+        let seal = encrypt && !over_blob_threshold;
+        debug_assert_eq!(seal, self.config.keys.is_some() && kind.is_inline_data());
+
         loop {
             M.log_reservation_attempted();
 
@@ -205,7 +239,7 @@ impl Log {
             // has encountered an issue.
             if let Err(e) = self.config.global_error() {
                 let _lock = self.iobufs.intervals.lock();
-                self.iobufs.interval_updated.notify_all();
+                self.iobufs.notify_interval_updated();
                 return Err(e);
             }
 
@@ -312,6 +346,18 @@ impl Log {
                 over_blob_threshold,
             )?;
 
+            if seal {
+                // with the key of the segment, which rotation may have
+                // made older than the current one
+                let (_, cipher) = iobuf.key.as_ref().unwrap();
+                cipher.seal_in_place(
+                    Domain::Message,
+                    reservation_lsn,
+                    &u64_to_arr(pid),
+                    &mut destination[header_len..],
+                );
+            }
+
             M.log_reservation_success();
 
             let ptr = if over_blob_threshold {
@@ -361,7 +407,7 @@ impl Log {
         if iobuf::n_writers(header) == 0 && iobuf::is_sealed(header) {
             if let Err(e) = self.config.global_error() {
                 let _lock = self.iobufs.intervals.lock();
-                self.iobufs.interval_updated.notify_all();
+                self.iobufs.notify_interval_updated();
                 return Err(e);
             }
 
@@ -401,10 +447,15 @@ impl Drop for Log {
             error!("failed to flush from IoBufs::drop: {}", e);
         }
 
-        if !self.config.temporary {
-            self.config.file.sync_all().unwrap();
+        #[cfg(all(target_os = "linux", feature = "io_uring"))]
+        {
+            if let Some(uring) = &self.iobufs.uring {
+                uring.quiesce();
+            }
         }
 
+        self.config.storage.sync_log().unwrap();
+
         debug!("IoBufs dropped");
     }
 }
@@ -416,7 +467,7 @@ pub struct MessageHeader {
     pub(crate) lsn: Lsn,
     pub(crate) pid: PageId,
     pub(crate) len: u32,
-    pub(crate) crc32: u32,
+    pub(crate) digest: Digest,
 }
 
 /// A segment's header contains the new base LSN and a reference
@@ -425,6 +476,10 @@ pub struct MessageHeader {
 pub(crate) struct SegmentHeader {
     pub(crate) lsn: Lsn,
     pub(crate) max_stable_lsn: Lsn,
+    /// The key the segment's messages are sealed with, if encrypted.
+    pub(crate) key_id: Option<KeyId>,
+    /// How the segment's messages are checksummed.
+    pub(crate) checksum: Checksum,
     pub(crate) ok: bool,
 }
 
@@ -499,130 +554,106 @@ impl LogRead {
 }
 
 // NB we use a lot of xors below to differentiate between zeroed out
-// data on disk and an lsn or crc32 of 0
+// data on disk and an lsn or checksum of 0
 
-impl From<[u8; MSG_HEADER_LEN]> for MessageHeader {
-    fn from(buf: [u8; MSG_HEADER_LEN]) -> Self {
+impl MessageHeader {
+    /// Parses a header from `buf`, which must be exactly as long as a
+    /// header with the log's `Checksum`.
+    pub(crate) fn read(buf: &[u8]) -> Self {
         let kind = MessageKind::from(buf[0]);
 
-        unsafe {
-            let page_id = arr_to_u64(buf.get_unchecked(1..9));
-            let lsn = arr_to_u64(buf.get_unchecked(9..17)) as Lsn;
-            let length = arr_to_u32(buf.get_unchecked(17..21));
-            let crc32 = arr_to_u32(buf.get_unchecked(21..)) ^ 0xFFFF_FFFF;
+        let page_id = arr_to_u64(&buf[1..9]);
+        let lsn = arr_to_u64(&buf[9..17]) as Lsn;
+        let length = arr_to_u32(&buf[17..21]);
 
-            Self {
-                kind,
-                pid: page_id,
-                lsn,
-                len: length,
-                crc32,
-            }
+        let mut digest = Digest::default();
+        for (d, b) in digest.iter_mut().zip(&buf[DIGEST_OFFSET..]) {
+            *d = b ^ 0xFF;
+        }
+
+        Self {
+            kind,
+            pid: page_id,
+            lsn,
+            len: length,
+            digest,
         }
     }
-}
 
-impl Into<[u8; MSG_HEADER_LEN]> for MessageHeader {
-    fn into(self) -> [u8; MSG_HEADER_LEN] {
-        let mut buf = [0; MSG_HEADER_LEN];
+    /// Serializes the header into `buf`, which must be exactly as long
+    /// as a header with the log's `Checksum`.
+    pub(crate) fn write(&self, buf: &mut [u8]) {
         buf[0] = self.kind.into();
+        buf[1..9].copy_from_slice(&u64_to_arr(self.pid));
+        buf[9..17].copy_from_slice(&u64_to_arr(self.lsn as u64));
+        buf[17..21].copy_from_slice(&u32_to_arr(self.len as u32));
+        write_digest(&self.digest, buf);
+    }
+}
 
-        let pid_arr = u64_to_arr(self.pid);
-        let lsn_arr = u64_to_arr(self.lsn as u64);
-        let length_arr = u32_to_arr(self.len as u32);
-        let crc32_arr = u32_to_arr(self.crc32 ^ 0xFFFF_FFFF);
-
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                pid_arr.as_ptr(),
-                buf.as_mut_ptr().add(1),
-                std::mem::size_of::<u64>(),
-            );
-            std::ptr::copy_nonoverlapping(
-                lsn_arr.as_ptr(),
-                buf.as_mut_ptr().add(9),
-                std::mem::size_of::<u64>(),
-            );
-            std::ptr::copy_nonoverlapping(
-                length_arr.as_ptr(),
-                buf.as_mut_ptr().add(17),
-                std::mem::size_of::<u32>(),
-            );
-            std::ptr::copy_nonoverlapping(
-                crc32_arr.as_ptr(),
-                buf.as_mut_ptr().add(21),
-                std::mem::size_of::<u32>(),
-            );
-        }
-
-        buf
+/// Stores `digest` in the header at the start of `buf`.
+pub(crate) fn write_digest(digest: &Digest, buf: &mut [u8]) {
+    for (b, d) in buf[DIGEST_OFFSET..].iter_mut().zip(digest) {
+        *b = d ^ 0xFF;
     }
 }
 
-impl From<[u8; SEG_HEADER_LEN]> for SegmentHeader {
-    fn from(buf: [u8; SEG_HEADER_LEN]) -> Self {
-        unsafe {
-            let crc32_header = arr_to_u32(buf.get_unchecked(0..4)) ^ 0xFFFF_FFFF;
+impl SegmentHeader {
+    /// Parses a header from `buf`, which must be exactly as long as a
+    /// segment header of the log, longer when it records a key id.
+    pub(crate) fn read(buf: &[u8]) -> Self {
+        let crc32_header = arr_to_u32(&buf[0..4]);
 
-            let xor_lsn = arr_to_u64(buf.get_unchecked(4..12)) as Lsn;
-            let lsn = xor_lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
+        let xor_lsn = arr_to_u64(&buf[4..12]) as Lsn;
+        let lsn = xor_lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
 
-            let xor_max_stable_lsn = arr_to_u64(buf.get_unchecked(12..20)) as Lsn;
-            let max_stable_lsn = xor_max_stable_lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
+        let xor_max_stable_lsn = arr_to_u64(&buf[12..20]) as Lsn;
+        let max_stable_lsn = xor_max_stable_lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
 
-            let crc32_tested = crc32(&buf[4..20]);
+        let key_id = if buf.len() > SEG_HEADER_LEN {
+            Some(arr_to_u32(&buf[SEG_HEADER_LEN..]))
+        } else {
+            None
+        };
 
-            let ok = crc32_tested == crc32_header;
+        let crc32_tested = crc32(&buf[4..]);
 
-            if !ok {
-                debug!(
-                    "segment with lsn {} had computed crc {}, \
-                     but stored crc {}",
-                    lsn, crc32_tested, crc32_header
-                );
-            }
+        let checksum = Checksum::ALL
+            .iter()
+            .copied()
+            .find(|checksum| crc32_tested ^ checksum.seg_header_mask() == crc32_header);
+        let ok = checksum.is_some();
 
-            Self {
-                lsn,
-                max_stable_lsn,
-                ok,
-            }
+        if !ok {
+            debug!(
+                "segment with lsn {} had computed crc {}, \
+                 but stored crc {}",
+                lsn, crc32_tested, crc32_header
+            );
         }
-    }
-}
 
-impl Into<[u8; SEG_HEADER_LEN]> for SegmentHeader {
-    fn into(self) -> [u8; SEG_HEADER_LEN] {
-        let mut buf = [0; SEG_HEADER_LEN];
+        Self {
+            lsn,
+            max_stable_lsn,
+            key_id,
+            checksum: checksum.unwrap_or(Checksum::Crc32),
+            ok,
+        }
+    }
 
+    /// Serializes the header into `buf`, which must be exactly as long
+    /// as a segment header of the log.
+    pub(crate) fn write(&self, buf: &mut [u8]) {
         let xor_lsn = self.lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
         let xor_max_stable_lsn = self.max_stable_lsn ^ 0x7FFF_FFFF_FFFF_FFFF;
-        let lsn_arr = u64_to_arr(xor_lsn as u64);
-        let highest_stable_lsn_arr = u64_to_arr(xor_max_stable_lsn as u64);
-
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                lsn_arr.as_ptr(),
-                buf.as_mut_ptr().add(4),
-                std::mem::size_of::<u64>(),
-            );
-            std::ptr::copy_nonoverlapping(
-                highest_stable_lsn_arr.as_ptr(),
-                buf.as_mut_ptr().add(12),
-                std::mem::size_of::<u64>(),
-            );
-        }
+        buf[4..12].copy_from_slice(&u64_to_arr(xor_lsn as u64));
+        buf[12..20].copy_from_slice(&u64_to_arr(xor_max_stable_lsn as u64));
 
-        let crc32 = u32_to_arr(crc32(&buf[4..20]) ^ 0xFFFF_FFFF);
-
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                crc32.as_ptr(),
-                buf.as_mut_ptr(),
-                std::mem::size_of::<u32>(),
-            );
+        if let Some(key_id) = self.key_id {
+            buf[SEG_HEADER_LEN..].copy_from_slice(&u32_to_arr(key_id));
         }
 
-        buf
+        let crc32 = crc32(&buf[4..]) ^ self.checksum.seg_header_mask();
+        buf[0..4].copy_from_slice(&u32_to_arr(crc32));
     }
 }
@@ -12,13 +12,19 @@ macro_rules! maybe_fail {
     };
 }
 
+mod archive;
+mod backend;
 mod blob_io;
+mod checkpoint;
+mod checksum;
 mod config;
 mod constants;
 /// Debug helps test concurrent issues with random jitter and other
 /// instruments.
 mod diskptr;
 mod ds;
+mod encryption;
+mod flusher;
 mod histogram;
 mod iobuf;
 mod iterator;
@@ -29,10 +35,17 @@ mod metrics;
 mod pagecache;
 mod parallel_io;
 mod reader;
+mod replication;
 mod reservation;
 mod result;
+mod scrub;
 mod segment;
+#[cfg(any(test, feature = "failpoints"))]
+mod simulated;
 mod snapshot;
+mod timeline;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
 mod util;
 
 #[cfg(feature = "measure_allocs")]
@@ -58,27 +71,39 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use crate::atomic::*;
 
 #[doc(hidden)]
-use self::logger::{MessageHeader, SegmentHeader};
+use self::logger::{write_digest, MessageHeader, SegmentHeader};
 
 use self::{
-    blob_io::{gc_blobs, read_blob, remove_blob, write_blob},
+    backend::{snapshot_file_name, FileBackend},
+    blob_io::{blob_key_id, gc_blobs, read_blob, remove_blob, write_blob},
+    checksum::{Digest, DIGEST_OFFSET, MAX_MSG_HEADER_LEN},
     config::PersistedConfig,
-    constants::{BATCH_MANIFEST_PID, CONFIG_PID, COUNTER_PID, META_PID},
+    constants::{
+        is_reserved_pid, BATCH_MANIFEST_PID, CONFIG_PID, COUNTER_PID, META_PID, REPLICATION_PID,
+    },
+    encryption::{Cipher, Domain, Keyring, KEY_ID_LEN, SEAL_LEN},
+    flusher::Flusher,
     iobuf::{IoBuf, IoBufs},
     iterator::{raw_segment_iter_from, LogIter},
     metrics::{clock, measure},
     pagecache::Update,
-    parallel_io::Pio,
+    parallel_io::{LogWriter, Pio, DIRECT_IO_ALIGN},
     reader::LogReader,
+    scrub::Scrubber,
     segment::SegmentAccountant,
     snapshot::{advance_snapshot, PageState},
+    timeline::Timeline,
     util::{arr_to_u32, arr_to_u64, maybe_decompress, u32_to_arr, u64_to_arr},
 };
 
 pub use self::{
+    backend::{MemoryBackend, Storage, StorageBackend},
+    checksum::Checksum,
     config::{Config, ConfigBuilder},
     diskptr::DiskPtr,
     ds::{node_from_frag_vec, Lru, Node, PageTable, Stack, StackIter, VecSet},
+    encryption::{Encryption, EncryptionKey, KeyId, KeyProvider, KeyRotation},
+    flusher::Durability,
     histogram::Histogram,
     logger::{Log, LogRead},
     map::{FastMap1, FastMap4, FastMap8, FastSet1, FastSet4, FastSet8},
@@ -86,11 +111,16 @@ pub use self::{
     meta::Meta,
     metrics::M,
     pagecache::{PageCache, PagePtr, RecoveryGuard},
+    replication::Follower,
     reservation::Reservation,
     result::{CasResult, Error, Result},
+    scrub::{CorruptPtr, Scrub, ScrubReport},
     segment::SegmentMode,
 };
 
+#[cfg(any(test, feature = "failpoints"))]
+pub use self::simulated::SimulatedDisk;
+
 #[doc(hidden)]
 pub use self::{
     constants::{
@@ -99,7 +129,7 @@ pub use self::{
     },
     ds::PAGETABLE_NODE_SZ,
     metrics::Measure,
-    snapshot::{read_snapshot_or_default, Snapshot},
+    snapshot::{read_snapshot_or_default, Snapshot, SnapshotInfo},
 };
 
 /// An offset for a storage file segment.
@@ -163,6 +193,16 @@ impl MessageKind {
     pub(crate) const fn into(self) -> u8 {
         self as u8
     }
+
+    /// Whether the message holds page data inline, which is what gets
+    /// encrypted in the log.
+    pub(crate) const fn is_inline_data(self) -> bool {
+        use MessageKind::*;
+        matches!(
+            self,
+            InlineAppend | InlineReplace | InlineMeta | InlineConfig | Free | Counter
+        )
+    }
 }
 
 impl From<u8> for MessageKind {
@@ -1,5 +1,5 @@
 use binary_heap_plus::{BinaryHeap, MaxComparator};
-use std::{borrow::Cow, ops::Deref, sync::Arc};
+use std::{borrow::Cow, future::Future, ops::Deref, sync::Arc};
 
 use abyss_promise::Promise;
 use parking_lot::Mutex;
@@ -107,6 +107,15 @@ pub struct RecoveryGuard<'a> {
 }
 
 impl<'a> RecoveryGuard<'a> {
+    pub(super) fn new(log: &'a Log) -> Result<Self> {
+        let batch_res = log.reserve(
+            LogKind::Skip,
+            BATCH_MANIFEST_PID,
+            &[0; std::mem::size_of::<Lsn>()],
+        )?;
+        Ok(RecoveryGuard { batch_res })
+    }
+
     /// Writes the last LSN for a batch into an earlier
     /// reservation, releasing it.
     pub fn seal_batch(mut self) -> Result<()> {
@@ -135,6 +144,80 @@ where
         let inner = PageCacheInner::start(config)?;
         Ok(Self(Arc::new(inner)))
     }
+
+    /// Like `start`, but keeps the log, blobs and snapshots in `storage`
+    /// instead of the files under the configured path. It takes the
+    /// builder because a built `Config` has already opened its storage.
+    pub fn start_with_storage(
+        config: ConfigBuilder,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<Self> {
+        Self::start(config.storage(storage).try_build()?)
+    }
+
+    /// Apply an archive written by `export_log_since` to the pagecache
+    /// at `config`, which must not be running. The pagecache should be
+    /// a backup of the exporting one that already holds everything up
+    /// to the `since` the archive was exported with. Returns the Lsn to
+    /// export from next time.
+    pub fn import<R: std::io::Read>(config: Config, archive: R) -> Result<Lsn> {
+        archive::import(&config, archive)
+    }
+
+    /// Copy every page of the pagecache at `old` into a new, empty one
+    /// at `new`, keeping their ids, along with the `Meta` mapping and
+    /// the id generator. This is the way to change settings that are
+    /// fixed once a pagecache is created, like `io_buf_size`, the
+    /// checksum or the encryption. Neither may be running. Returns the
+    /// number of pages copied.
+    ///
+    /// The new pagecache is marked as migrating until every page is
+    /// copied and flushed, and refuses to start while it is, so one
+    /// that a crash left half copied has to be removed and migrated
+    /// again.
+    pub fn migrate(old: Config, new: Config) -> Result<usize> {
+        let from = PageCacheInner::<P>::start(old)?;
+        if !from.was_recovered() {
+            return Err(Error::Unsupported(format!(
+                "there is no pagecache to migrate at {:?}",
+                from.path()
+            )));
+        }
+
+        let to = PageCacheInner::<P>::start(new)?;
+        if to.was_recovered() {
+            return Err(Error::Unsupported(format!(
+                "the pagecache to migrate to at {:?} is not empty",
+                to.path()
+            )));
+        }
+
+        let marker = to.config.migration_marker_path();
+        std::fs::File::create(&marker)?.sync_all()?;
+        std::fs::File::open(to.path())?.sync_all()?;
+
+        let copied = from.copy_into(&to)?;
+        to.flush()?;
+
+        std::fs::remove_file(&marker)?;
+        std::fs::File::open(to.path())?.sync_all()?;
+
+        Ok(copied)
+    }
+
+    /// Like `scrub`, but on a thread of its own, which can be cancelled
+    /// through the returned `Scrub`. It keeps the pagecache open until
+    /// it is done.
+    pub fn scrub_in_background(&self, bytes_per_sec: Option<u64>) -> Result<Scrub> {
+        let pc = self.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let stop = cancelled.clone();
+        let thread = std::thread::Builder::new()
+            .name("cloyster-scrub".to_owned())
+            .spawn(move || pc.scrub_until(bytes_per_sec, &stop))?;
+
+        Ok(Scrub::new(cancelled, thread))
+    }
 }
 
 impl<P> Debug for PageCache<P>
@@ -214,6 +297,7 @@ where
     next_pid_to_allocate: AtomicU64,
     free: Arc<Mutex<BinaryHeap<PageId, MaxComparator>>>,
     log: Log,
+    flusher: Flusher,
     lru: Lru,
     updates: AtomicU64,
     last_snapshot: Arc<Mutex<Option<Snapshot>>>,
@@ -221,6 +305,17 @@ where
     idgen_persists: Arc<AtomicU64>,
     idgen_persist_mu: Arc<Mutex<()>>,
     was_recovered: bool,
+    blob_rotation: Mutex<BlobRotation>,
+}
+
+/// How far the pages pointing at blobs sealed with an old key have
+/// been rewritten under the key they are being rotated to.
+#[derive(Debug, Default)]
+struct BlobRotation {
+    to: Option<KeyId>,
+    // the pages before it were found pointing at no such blob
+    next_pid: PageId,
+    done: bool,
 }
 
 impl<P> PageCacheInner<P>
@@ -233,6 +328,14 @@ where
 
         config.reset_global_error();
 
+        if config.migration_marker_path().exists() {
+            return Err(Error::Unsupported(format!(
+                "the pagecache at {:?} was left behind by a migration that \
+                 did not finish, remove it and migrate again",
+                config.path
+            )));
+        }
+
         // try to pull any existing snapshot off disk, and
         // apply any new data to it to "catch-up" the
         // snapshot before loading it.
@@ -241,12 +344,16 @@ where
         let cache_capacity = config.cache_capacity;
         let lru = Lru::new(cache_capacity);
 
+        let log = Log::start(config.clone(), snapshot.clone())?;
+        let flusher = Flusher::new("cloyster-flusher", log.iobufs.clone())?;
+
         let mut pc = Self {
             config: config.clone(),
             inner: PageTable::default(),
             next_pid_to_allocate: AtomicU64::new(0),
             free: Arc::new(Mutex::new(BinaryHeap::new())),
-            log: Log::start(config, snapshot.clone())?,
+            log,
+            flusher,
             lru,
             updates: AtomicU64::new(0),
             last_snapshot: Arc::new(Mutex::new(Some(snapshot))),
@@ -254,6 +361,7 @@ where
             idgen: Arc::new(AtomicU64::new(0)),
             idgen_persists: Arc::new(AtomicU64::new(0)),
             was_recovered: false,
+            blob_rotation: Mutex::new(BlobRotation::default()),
         };
 
         // now we read it back in
@@ -376,6 +484,53 @@ where
         self.log.flush()
     }
 
+    /// Like `flush`, but runs on a background thread, so it can be
+    /// awaited without blocking the caller's thread.
+    #[cfg(not(feature = "async"))]
+    pub fn flush_async(&self) -> impl Future<Output = Result<usize>> {
+        let iobufs = self.log.iobufs.clone();
+        let promise = Promise::new(move || iobuf::flush(&iobufs));
+
+        async move { promise.await.unwrap_or_else(|| Err(dropped_task("flush"))) }
+    }
+
+    /// Like `flush`, but the writes happen on the IO thread pool, and
+    /// the returned future is woken once they are stable.
+    #[cfg(feature = "async")]
+    pub fn flush_async(&self) -> impl Future<Output = Result<usize>> {
+        iobuf::flush_async(&self.log.iobufs)
+    }
+
+    /// Advance the snapshot to the stable tip of the log in the
+    /// background, resolving to the Lsn it now covers. Waits for any
+    /// snapshot that is already in progress instead of skipping.
+    pub fn snapshot_now(&self) -> impl Future<Output = Result<Lsn>> {
+        let promise = self.spawn_snapshot(true);
+
+        async move {
+            promise?
+                .await
+                .unwrap_or_else(|| Err(dropped_task("snapshot")))
+        }
+    }
+
+    /// Write a consistent copy of the pagecache into `dir`, which must be
+    /// empty or not exist, while writes continue. The copy can be opened
+    /// on its own and contains everything up to the returned Lsn.
+    pub fn checkpoint<Q: AsRef<std::path::Path>>(&self, dir: Q) -> Result<Lsn> {
+        // no snapshot may be written while its files are being copied
+        let _snapshot = self.last_snapshot.lock();
+        checkpoint::checkpoint(&self.config, &self.log.iobufs, dir.as_ref())
+    }
+
+    /// Stream every message logged after `since` into `writer`, along
+    /// with the contents of the blobs they point to. Returns the Lsn of
+    /// the last message written, which is the `since` of the next
+    /// incremental export. Start from the Lsn returned by `checkpoint`.
+    pub fn export_log_since<W: std::io::Write>(&self, since: Lsn, writer: W) -> Result<Lsn> {
+        archive::export(&self.log, since, writer)
+    }
+
     /// Create a new page, trying to reuse old freed pages if possible
     /// to maximize underlying `PageTable` pointer density. Returns
     /// the page ID and its pointer for use in future atomic `replace`
@@ -399,12 +554,76 @@ where
         let ret = if let Some(to_clean) = to_clean {
             self.rewrite_page(to_clean, &guard).map(|_| true)
         } else {
-            Ok(false)
+            self.rotate_blob(&guard)
         };
         guard.flush();
         ret
     }
 
+    /// Rewrites a page pointing at a blob that is sealed with a key
+    /// other than the current one, as segment cleaning does not find
+    /// those once the pointer sits in a segment sealed with the
+    /// current key.
+    fn rotate_blob(&self, guard: &Guard) -> Result<bool> {
+        let current = match &self.config.keys {
+            Some(keys) => keys.current_id(),
+            None => return Ok(false),
+        };
+        let from = {
+            let mut rotation = self.blob_rotation.lock();
+            if rotation.to != Some(current) {
+                *rotation = BlobRotation {
+                    to: Some(current),
+                    ..BlobRotation::default()
+                };
+            }
+            if rotation.done {
+                return Ok(false);
+            }
+            rotation.next_pid
+        };
+
+        let found = self.stale_blob(current, from)?;
+
+        {
+            let mut rotation = self.blob_rotation.lock();
+            if rotation.to == Some(current) {
+                match found {
+                    // rewriting it leaves nothing stale there, but it is
+                    // looked at again in case that did not go through
+                    Some(pid) => rotation.next_pid = pid,
+                    None => rotation.done = true,
+                }
+            }
+        }
+
+        match found {
+            Some(pid) => self.rewrite_page(pid, guard).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The first page from `from` on pointing at a blob sealed with a
+    /// key other than `current`.
+    fn stale_blob(&self, current: KeyId, from: PageId) -> Result<Option<PageId>> {
+        for pid in from..self.next_pid_to_allocate.load(Acquire) {
+            let guard = pin();
+            let pte = match self.inner.get(pid, &guard) {
+                None => continue,
+                Some(pte) => pte,
+            };
+            let head = unsafe { pte.deref().head(&guard) };
+            for (_, cache_info) in StackIter::from_ptr(head, &guard) {
+                if cache_info.ptr.is_blob()
+                    && blob_key_id(cache_info.ptr.blob().1, &self.config)? != Some(current)
+                {
+                    return Ok(Some(pid));
+                }
+            }
+        }
+        Ok(None)
+    }
+
     /// Initiate an atomic sequence of writes to the
     /// underlying log. Returns a `RecoveryGuard` which,
     /// when dropped, will record the current max reserved
@@ -418,12 +637,7 @@ where
     /// combined with a concurrency control system in another
     /// component.
     pub fn pin_log(&self) -> Result<RecoveryGuard<'_>> {
-        let batch_res = self.log.reserve(
-            LogKind::Skip,
-            BATCH_MANIFEST_PID,
-            &[0; std::mem::size_of::<Lsn>()],
-        )?;
-        Ok(RecoveryGuard { batch_res })
+        RecoveryGuard::new(&self.log)
     }
 
     #[doc(hidden)]
@@ -435,7 +649,7 @@ where
             // wake up any waiting threads
             // so they don't stall forever
             let _lock = self.log.iobufs.intervals.lock();
-            self.log.iobufs.interval_updated.notify_all();
+            self.log.iobufs.notify_interval_updated();
         }
     }
 
@@ -513,6 +727,78 @@ where
         Ok((pid, new_ptr))
     }
 
+    /// Write the current state of every page into `to`, a freshly
+    /// created pagecache, under the same pids. Returns the number of
+    /// pages that were not free.
+    fn copy_into(&self, to: &Self) -> Result<usize> {
+        let guard = pin();
+
+        let meta = self.meta(&guard)?.clone();
+        let (meta_ptr, _) = to.get_meta(&guard)?;
+        if to
+            .cas_page(META_PID, meta_ptr, Update::Meta(meta), false, &guard)?
+            .is_err()
+        {
+            return Err(Error::ReportableBug(
+                "failed to replace the META page of a new pagecache".into(),
+            ));
+        }
+
+        // persisted counters must be a multiple of the interval, and
+        // recovery skips ahead of them, so no id is handed out twice
+        let interval = to.config.idgen_persist_interval;
+        let counter = self.idgen.load(Acquire).div_ceil(interval) * interval;
+        let (counter_ptr, _) = to.get_idgen(&guard)?;
+        if to
+            .cas_page(
+                COUNTER_PID,
+                counter_ptr,
+                Update::Counter(counter),
+                false,
+                &guard,
+            )?
+            .is_err()
+        {
+            return Err(Error::ReportableBug(
+                "failed to replace the idgen page of a new pagecache".into(),
+            ));
+        }
+        drop(guard);
+
+        // pids are handed out in order by a new pagecache, so allocating
+        // every one of ours, free or not, lines them up
+        let mut copied = 0;
+        let mut free = vec![];
+        for pid in CONFIG_PID + 1..self.next_pid_to_allocate.load(Acquire) {
+            let guard = pin();
+            let update = match self.get(pid, &guard)? {
+                Some((_, page, _)) => {
+                    copied += 1;
+                    Update::Compact(page.clone())
+                }
+                None => {
+                    free.push(pid);
+                    Update::Free
+                }
+            };
+
+            let (new_pid, _) = to.allocate_inner(update, &guard)?;
+            if new_pid != pid {
+                return Err(Error::ReportableBug(format!(
+                    "page {} was copied to page {}",
+                    pid, new_pid
+                )));
+            }
+        }
+
+        let mut to_free = to.free.lock();
+        for pid in free {
+            to_free.push(pid);
+        }
+
+        Ok(copied)
+    }
+
     /// Free a particular page.
     pub fn free<'g>(
         &self,
@@ -522,7 +808,7 @@ where
     ) -> Result<CasResult<'g, P, ()>> {
         trace!("attempting to free pid {}", pid);
 
-        if pid == COUNTER_PID || pid == META_PID || pid == CONFIG_PID || pid == BATCH_MANIFEST_PID {
+        if is_reserved_pid(pid) {
             return Err(Error::Unsupported(
                 "you are not able to free the first \
                  couple pages, which are allocated \
@@ -790,8 +1076,15 @@ where
         let stack_iter = StackIter::from_ptr(head, &guard);
         let cache_entries: Vec<_> = stack_iter.collect();
 
-        // if the page is just a single blob pointer, rewrite it.
-        if cache_entries.len() == 1 && cache_entries[0].1.ptr.is_blob() {
+        // if the page is just a single blob pointer, rewrite it, unless
+        // the blob needs to be sealed with the current key.
+        let single_blob = cache_entries.len() == 1 && cache_entries[0].1.ptr.is_blob();
+        let stale_blob = |ptr: DiskPtr| -> Result<bool> {
+            let current = self.config.keys.as_ref().map(|keys| keys.current_id());
+            Ok(self.log.with_sa(|sa| sa.has_stale_key(ptr.lid()))
+                || blob_key_id(ptr.blob().1, &self.config)? != current)
+        };
+        if single_blob && !stale_blob(cache_entries[0].1.ptr)? {
             trace!("rewriting blob with pid {}", pid);
             let blob_ptr = cache_entries[0].1.ptr.blob().1;
 
@@ -900,18 +1193,84 @@ where
         Ok(on_disk_bytes / (logical_size + discount))
     }
 
-    fn size_on_disk(&self) -> Result<u64> {
-        let mut size = self.config.file.metadata()?.len();
+    /// How much of the log is still sealed with keys other than the
+    /// one the `KeyProvider` currently hands out, or `None` if the
+    /// database isn't encrypted. Segment cleaning, driven by writes or
+    /// `attempt_gc`, rewrites what is left with the current key, and
+    /// `attempt_gc` also rewrites the pages whose blobs are left over.
+    /// This reads every stored blob.
+    pub fn key_rotation(&self) -> Option<KeyRotation> {
+        let mut rotation = self.log.with_sa(|sa| sa.key_rotation())?;
+        let stored = self.config.storage.list_blobs().map_err(Error::from);
+        let stale = stored.and_then(|blobs| {
+            let mut stale = 0;
+            for (lsn, _) in blobs {
+                if blob_key_id(lsn, &self.config)? != Some(rotation.current) {
+                    stale += 1;
+                }
+            }
+            Ok(stale)
+        });
+        rotation.blobs = match stale {
+            Ok(stale) => stale,
+            Err(e) => {
+                // better to report a blob too many than to let a key go
+                warn!("failed to check the keys blobs are sealed with: {:?}", e);
+                1
+            }
+        };
+        Some(rotation)
+    }
+
+    /// Read back every update the pages are made of and check it against
+    /// its checksum, along with the blobs they point to, reading at most
+    /// `bytes_per_sec` if given. Writes may go on meanwhile, updates not
+    /// written out when a page is reached are left for the next scrub.
+    pub fn scrub(&self, bytes_per_sec: Option<u64>) -> Result<ScrubReport> {
+        self.scrub_until(bytes_per_sec, &AtomicBool::new(false))
+    }
+
+    fn scrub_until(
+        &self,
+        bytes_per_sec: Option<u64>,
+        cancelled: &AtomicBool,
+    ) -> Result<ScrubReport> {
+        let mut scrubber = Scrubber::new(&self.config, bytes_per_sec);
 
-        let stable = self.config.blob_path(0);
-        let blob_dir = stable.parent().unwrap();
-        let blob_files = std::fs::read_dir(blob_dir)?;
+        let next_pid_to_allocate = self.next_pid_to_allocate.load(Acquire);
+        for pid in 0..next_pid_to_allocate {
+            if cancelled.load(Acquire) {
+                return Ok(scrubber.finish(false));
+            }
 
-        for blob_file in blob_files {
-            size += blob_file?.metadata()?.len();
+            // NB segments are only reused once every thread that was
+            // pinned when the page was moved off of them has unpinned,
+            // so its pointers stay valid until the guard is dropped.
+            let guard = pin();
+            let pte = match self.inner.get(pid, &guard) {
+                None => continue,
+                Some(pte) => pte,
+            };
+            let written = self.log.iobufs.written();
+            let head = unsafe { pte.deref().head(&guard) };
+            for (_, cache_info) in StackIter::from_ptr(head, &guard) {
+                if cache_info.lsn <= written {
+                    scrubber.check(pid, cache_info.lsn, cache_info.ptr)?;
+                }
+            }
+            drop(guard);
+
+            scrubber.page_done();
         }
 
-        Ok(size)
+        Ok(scrubber.finish(true))
+    }
+
+    fn size_on_disk(&self) -> Result<u64> {
+        let storage = &self.config.storage;
+        let blobs: u64 = storage.list_blobs()?.iter().map(|(_, len)| len).sum();
+
+        Ok(storage.log_len()? + blobs)
     }
 
     fn logical_size_of_all_pages(&self) -> Result<u64> {
@@ -1075,7 +1434,11 @@ where
                     cached_ptr: head,
                     ts: cache_info.ts,
                 };
-                let _ = self.cas_page(META_PID, ptr, update, false, guard)?;
+                if self.config.read_only {
+                    self.page_in_without_logging(META_PID, ptr, update, guard);
+                } else {
+                    let _ = self.cas_page(META_PID, ptr, update, false, guard)?;
+                }
                 self.get_meta(guard)
             }
             _ => Err(Error::ReportableBug(
@@ -1086,6 +1449,34 @@ where
         }
     }
 
+    /// Install an update pulled from the log at the head of a page
+    /// without logging it again, which is not possible when the log is
+    /// read-only. Losing a race to another thread paging it in is fine.
+    fn page_in_without_logging<'g>(
+        &self,
+        pid: PageId,
+        old: PagePtr<'g, P>,
+        update: Update<P>,
+        guard: &'g Guard,
+    ) {
+        let head_ptr = match self.inner.get(pid, guard) {
+            None => return,
+            Some(p) => p,
+        };
+
+        let mut frags: Vec<(Option<Update<P>>, CacheInfo)> =
+            StackIter::from_ptr(old.cached_ptr, guard)
+                .map(|(_, cache_info)| (None, *cache_info))
+                .collect();
+        if frags.is_empty() {
+            return;
+        }
+        frags[0].0 = Some(update);
+
+        let node = node_from_frag_vec(frags);
+        let _ = unsafe { head_ptr.deref().cas(old.cached_ptr, node, guard) };
+    }
+
     /// Retrieve the current meta page
     pub(crate) fn get_persisted_config<'g>(
         &self,
@@ -1120,7 +1511,11 @@ where
                     cached_ptr: head,
                     ts: cache_info.ts,
                 };
-                let _ = self.cas_page(CONFIG_PID, ptr, update, false, guard)?;
+                if self.config.read_only {
+                    self.page_in_without_logging(CONFIG_PID, ptr, update, guard);
+                } else {
+                    let _ = self.cas_page(CONFIG_PID, ptr, update, false, guard)?;
+                }
                 self.get_persisted_config(guard)
             }
             _ => Err(Error::ReportableBug(
@@ -1162,7 +1557,11 @@ where
                     cached_ptr: head,
                     ts: cache_info.ts,
                 };
-                let _ = self.cas_page(COUNTER_PID, ptr, update, false, guard)?;
+                if self.config.read_only {
+                    self.page_in_without_logging(COUNTER_PID, ptr, update, guard);
+                } else {
+                    let _ = self.cas_page(COUNTER_PID, ptr, update, false, guard)?;
+                }
                 self.get_idgen(guard)
             }
             _ => Err(Error::ReportableBug(
@@ -1182,7 +1581,7 @@ where
         trace!("getting page iterator for pid {}", pid);
         let _measure = Measure::new(&M.get_page);
 
-        if pid == COUNTER_PID || pid == META_PID || pid == CONFIG_PID || pid == BATCH_MANIFEST_PID {
+        if is_reserved_pid(pid) {
             return Err(Error::Unsupported(
                 "you are not able to iterate over \
                  the first couple pages, which are \
@@ -1342,12 +1741,38 @@ where
         self.log.stable_offset()
     }
 
-    /// Blocks until the provided Lsn is stable on disk,
-    /// triggering necessary flushes in the process.
+    /// Blocks until the provided Lsn is stable on disk.
+    /// The flush itself is left to the group commit flusher,
+    /// so concurrent callers share the same write and fsync.
     /// Returns the number of bytes written during
     /// this call.
     pub fn make_stable(&self, lsn: Lsn) -> Result<usize> {
-        self.log.make_stable(lsn)
+        let first_stable = self.stable_lsn();
+        if first_stable >= lsn {
+            return Ok(0);
+        }
+
+        self.flusher.request(lsn);
+        iobuf::wait_for_stable(&self.log.iobufs, first_stable, lsn)
+    }
+
+    /// Blocks until the provided Lsn has reached the given
+    /// `Durability`. Returns the number of bytes written during
+    /// this call.
+    pub fn make_durable(&self, lsn: Lsn, durability: Durability) -> Result<usize> {
+        match durability {
+            Durability::None => Ok(0),
+            Durability::Buffered => self.log.make_written(lsn),
+            Durability::Synced => self.make_stable(lsn),
+        }
+    }
+
+    /// Resolves once the provided Lsn is stable on disk, without
+    /// blocking the caller's thread. Returns the number of bytes
+    /// written.
+    #[cfg(feature = "async")]
+    pub fn make_stable_async(&self, lsn: Lsn) -> impl Future<Output = Result<usize>> {
+        iobuf::make_stable_async(&self.log.iobufs, lsn)
     }
 
     /// Returns `true` if the database was
@@ -1495,14 +1920,52 @@ where
         }
     }
 
+    /// Compare-and-swap the `Meta` mapping for a given
+    /// bucket identifier.
+    pub fn cas_bucket_in_meta<'g>(
+        &self,
+        name: &[u8],
+        old: Option<PageId>,
+        new: Option<PageId>,
+        guard: &'g Guard,
+    ) -> Result<std::result::Result<(), Option<PageId>>> {
+        loop {
+            let (meta_key, meta) = self.get_meta(guard)?;
+
+            let actual = meta.get_bucket(&name);
+            if actual != old {
+                return Ok(Err(actual));
+            }
+
+            let mut new_meta = (*meta).clone();
+            if let Some(new) = new {
+                new_meta.set_bucket(name.to_vec(), new);
+            } else {
+                new_meta.del_bucket(&name);
+            }
+
+            let new_meta_frag = Update::Meta(new_meta);
+
+            let res = self.cas_page(META_PID, meta_key.clone(), new_meta_frag, false, &guard)?;
+
+            match res {
+                Ok(_worked) => return Ok(Ok(())),
+                Err(Some((_current_ptr, _rejected))) => {}
+                Err(None) => {
+                    return Err(Error::ReportableBug(
+                        "replacing the META page has failed because \
+                         the pagecache does not think it currently exists."
+                            .into(),
+                    ))
+                }
+            }
+        }
+    }
+
     fn page_out(&self, to_evict: Vec<PageId>, guard: &Guard) -> Result<()> {
         let _measure = Measure::new(&M.page_out);
         'different_page_eviction: for pid in to_evict {
-            if pid == COUNTER_PID
-                || pid == META_PID
-                || pid == CONFIG_PID
-                || pid == BATCH_MANIFEST_PID
-            {
+            if is_reserved_pid(pid) {
                 // should not page these suckas out
                 continue;
             }
@@ -1542,94 +2005,43 @@ where
     }
 
     fn pull(&self, pid: PageId, lsn: Lsn, ptr: DiskPtr) -> Result<Update<P>> {
-        use MessageKind::*;
-
-        trace!("pulling lsn {} ptr {} from disk", lsn, ptr);
-        let _measure = Measure::new(&M.pull);
-        let (header, bytes) = match self.log.read(pid, lsn, ptr) {
-            Ok(LogRead::Inline(header, buf, _len)) => {
-                assert_eq!(
-                    header.pid, pid,
-                    "expected pid {} on pull of ptr {}, \
-                     but got {} instead",
-                    pid, ptr, header.pid
-                );
-                assert_eq!(
-                    header.lsn, lsn,
-                    "expected lsn {} on pull of ptr {}, \
-                     but got lsn {} instead",
-                    lsn, ptr, header.lsn
-                );
-                Ok((header, buf))
-            }
-            Ok(LogRead::Blob(header, buf, _blob_pointer)) => {
-                assert_eq!(
-                    header.pid, pid,
-                    "expected pid {} on pull of ptr {}, \
-                     but got {} instead",
-                    pid, ptr, header.pid
-                );
-                assert_eq!(
-                    header.lsn, lsn,
-                    "expected lsn {} on pull of ptr {}, \
-                     but got lsn {} instead",
-                    lsn, ptr, header.lsn
-                );
-
-                Ok((header, buf))
-            }
-            Ok(other) => {
-                debug!("read unexpected page: {:?}", other);
-                Err(Error::Corruption { at: ptr })
-            }
-            Err(e) => {
-                debug!("failed to read page: {:?}", e);
-                Err(e)
-            }
-        }?;
-
-        log::trace!("pid: {}, header: {:?}, bytes: {:?}", pid, header, bytes);
-
-        let deserialize_latency = Measure::new(&M.deserialize);
-        let update_res = match header.kind {
-            Counter => deserialize::<u64>(&bytes).map(Update::Counter),
-            BlobMeta | InlineMeta => deserialize::<Meta>(&bytes).map(Update::Meta),
-            BlobConfig | InlineConfig => deserialize::<PersistedConfig>(&bytes).map(Update::Config),
-            BlobAppend | InlineAppend => deserialize::<P>(&bytes).map(Update::Append),
-            BlobReplace | InlineReplace => deserialize::<P>(&bytes).map(Update::Compact),
-            Free => Ok(Update::Free),
-            other => panic!("unexpected pull: {:?}", other),
-        };
-        drop(deserialize_latency);
-
-        let update = update_res
-            .map_err(|_| ())
-            .expect("failed to deserialize data");
-
-        match update {
-            Update::Free => Err(Error::ReportableBug(
-                "non-append/compact found in pull".to_owned(),
-            )),
-            update => Ok(update),
-        }
+        read_update(&self.log, pid, lsn, ptr)
     }
 
     // caller is expected to have instantiated self.last_snapshot
     // in recovery already.
     fn advance_snapshot(&self) -> Result<()> {
+        let _result = self.spawn_snapshot(false)?;
+
+        #[cfg(any(test, feature = "check_snapshot_integrity"))]
+        _result.resolve().unwrap()?;
+
+        Ok(())
+    }
+
+    /// Spawn a task that advances the snapshot to the stable tip of the
+    /// log, resolving to the Lsn it reached. If another snapshot is in
+    /// progress, it either waits for that one to finish or, when `wait`
+    /// is false, gives up right away and resolves to -1.
+    fn spawn_snapshot(&self, wait: bool) -> Result<Promise<Result<Lsn>>> {
         let snapshot_mu = self.last_snapshot.clone();
         let config = self.config.clone();
         let iobufs = self.log.iobufs.clone();
 
         let gen_snapshot = move || {
-            let snapshot_opt_res = snapshot_mu.try_lock();
+            let snapshot_opt_res = if wait {
+                Some(snapshot_mu.lock())
+            } else {
+                snapshot_mu.try_lock()
+            };
             if snapshot_opt_res.is_none() {
                 // some other thread is snapshotting
                 debug!(
                     "snapshot skipped because previous attempt \
                      appears not to have completed"
                 );
-                return Ok(());
+                // only when not waiting, which ignores the result
+                return Ok(-1);
             }
 
             let mut snapshot_opt = snapshot_opt_res.unwrap();
@@ -1639,7 +2051,6 @@ where
 
             if let Err(e) = iobuf::flush(&iobufs) {
                 error!("failed to flush log during advance_snapshot: {}", e);
-                iobufs.with_sa(SegmentAccountant::resume_rewriting);
                 *snapshot_opt = Some(last_snapshot);
                 return Err(e);
             }
@@ -1674,20 +2085,21 @@ where
                     Err(e)
                 }
                 Ok(next_snapshot) => {
+                    let lsn = next_snapshot.last_lsn;
                     *snapshot_opt = Some(next_snapshot);
-                    Ok(())
+                    Ok(lsn)
                 }
             }
         };
 
         if let Err(e) = self.config.global_error() {
-            self.log.iobufs.interval_updated.notify_all();
+            self.log.iobufs.notify_interval_updated();
             return Err(e);
         }
 
         debug!("asynchronously spawning snapshot generation task");
         let config = self.config.clone();
-        let _result = Promise::new(move || {
+        Ok(Promise::new(move || {
             let result = gen_snapshot();
             match &result {
                 Ok(_) => {}
@@ -1698,13 +2110,7 @@ where
                 }
             }
             result
-        });
-
-        #[cfg(any(test, feature = "check_snapshot_integrity"))]
-        _result.resolve().unwrap()?;
-
-        // TODO add future for waiting on the result of this if desired
-        Ok(())
+        }))
     }
 
     fn load_snapshot(&mut self) {
@@ -1756,7 +2162,7 @@ where
                     let cache_info = CacheInfo {
                         lsn,
                         ptr,
-                        log_size: MSG_HEADER_LEN,
+                        log_size: self.config.msg_header_len(),
                         ts: 0,
                     };
                     stack.push((Some(Update::Free), cache_info));
@@ -1792,3 +2198,194 @@ where
     }
     ptrs
 }
+
+/// Read the update logged for `pid` at `lsn` back from `log`.
+pub(super) fn read_update<P: Materializer>(
+    log: &Log,
+    pid: PageId,
+    lsn: Lsn,
+    ptr: DiskPtr,
+) -> Result<Update<P>> {
+    use MessageKind::*;
+
+    trace!("pulling lsn {} ptr {} from disk", lsn, ptr);
+    let _measure = Measure::new(&M.pull);
+    let (header, bytes) = match log.read(pid, lsn, ptr) {
+        Ok(LogRead::Inline(header, buf, _len)) => {
+            assert_eq!(
+                header.pid, pid,
+                "expected pid {} on pull of ptr {}, \
+                 but got {} instead",
+                pid, ptr, header.pid
+            );
+            assert_eq!(
+                header.lsn, lsn,
+                "expected lsn {} on pull of ptr {}, \
+                 but got lsn {} instead",
+                lsn, ptr, header.lsn
+            );
+            Ok((header, buf))
+        }
+        Ok(LogRead::Blob(header, buf, _blob_pointer)) => {
+            assert_eq!(
+                header.pid, pid,
+                "expected pid {} on pull of ptr {}, \
+                 but got {} instead",
+                pid, ptr, header.pid
+            );
+            assert_eq!(
+                header.lsn, lsn,
+                "expected lsn {} on pull of ptr {}, \
+                 but got lsn {} instead",
+                lsn, ptr, header.lsn
+            );
+
+            Ok((header, buf))
+        }
+        Ok(other) => {
+            debug!("read unexpected page: {:?}", other);
+            Err(Error::Corruption { at: ptr })
+        }
+        Err(e) => {
+            debug!("failed to read page: {:?}", e);
+            Err(e)
+        }
+    }?;
+
+    log::trace!("pid: {}, header: {:?}, bytes: {:?}", pid, header, bytes);
+
+    let deserialize_latency = Measure::new(&M.deserialize);
+    let update_res = match header.kind {
+        Counter => deserialize::<u64>(&bytes).map(Update::Counter),
+        BlobMeta | InlineMeta => deserialize::<Meta>(&bytes).map(Update::Meta),
+        BlobConfig | InlineConfig => deserialize::<PersistedConfig>(&bytes).map(Update::Config),
+        BlobAppend | InlineAppend => deserialize::<P>(&bytes).map(Update::Append),
+        BlobReplace | InlineReplace => deserialize::<P>(&bytes).map(Update::Compact),
+        Free => Ok(Update::Free),
+        other => panic!("unexpected pull: {:?}", other),
+    };
+    drop(deserialize_latency);
+
+    let update = update_res
+        .map_err(|_| ())
+        .expect("failed to deserialize data");
+
+    match update {
+        Update::Free => Err(Error::ReportableBug(
+            "non-append/compact found in pull".to_owned(),
+        )),
+        update => Ok(update),
+    }
+}
+
+fn dropped_task(what: &str) -> Error {
+    Error::ReportableBug(format!("{} task ended without a result", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    fn page(key: &[u8], value: Vec<u8>) -> Page {
+        let mut page = BTreeMap::new();
+        page.insert(key.to_vec(), value);
+        page
+    }
+
+    #[test]
+    fn test_migrate_to_another_io_buf_size() {
+        let base = std::env::temp_dir().join(format!("cloyster.migrate.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let config = |name: &str, io_buf_size: usize, checksum: Checksum| {
+            ConfigBuilder::new()
+                .path(base.join(name))
+                .io_buf_size(io_buf_size)
+                .checksum(checksum)
+        };
+
+        let pc =
+            PageCache::<Page>::start(config("old", 64 * 1024, Checksum::Crc32).build()).unwrap();
+        let guard = pin();
+        let mut pids = vec![];
+        let mut ptrs = vec![];
+        for i in 0..20u8 {
+            let len = if i == 5 { 40 * 1024 } else { 64 };
+            let (pid, ptr) = pc.allocate(page(&[i], vec![i; len]), &guard).unwrap();
+            pids.push(pid);
+            ptrs.push(ptr);
+        }
+        pc.link(pids[0], ptrs.remove(0), page(b"appended", vec![1]), &guard)
+            .unwrap()
+            .unwrap();
+        pc.free(pids[3], ptrs.remove(2), &guard).unwrap().unwrap();
+        drop(ptrs);
+        pc.cas_bucket_in_meta(b"bucket", None, Some(pids[1]), &guard)
+            .unwrap()
+            .unwrap();
+        let id = pc.generate_id().unwrap();
+        drop(guard);
+        pc.flush().unwrap();
+        drop(pc);
+
+        match config("old", 128 * 1024, Checksum::Crc32).try_build() {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("opened with another io_buf_size: {:?}", other.map(|_| ())),
+        }
+
+        let copied = PageCache::<Page>::migrate(
+            config("old", 64 * 1024, Checksum::Crc32).build(),
+            config("new", 128 * 1024, Checksum::Blake3).build(),
+        )
+        .unwrap();
+        assert_eq!(copied, pids.len() - 1);
+        assert!(!base.join("new").join("migrating").exists());
+
+        // the new one is no longer empty
+        match PageCache::<Page>::migrate(
+            config("old", 64 * 1024, Checksum::Crc32).build(),
+            config("new", 128 * 1024, Checksum::Blake3).build(),
+        ) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("migrated into a used pagecache: {:?}", other),
+        }
+
+        // as a crash in the middle of copying would have left it
+        let marker = base.join("new").join("migrating");
+        std::fs::write(&marker, b"").unwrap();
+        match PageCache::<Page>::start(config("new", 128 * 1024, Checksum::Blake3).build()) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("started a half migrated pagecache: {:?}", other.map(|_| ())),
+        }
+        std::fs::remove_file(&marker).unwrap();
+
+        let pc =
+            PageCache::<Page>::start(config("new", 128 * 1024, Checksum::Blake3).build()).unwrap();
+        let guard = pin();
+        for (i, pid) in pids.iter().enumerate() {
+            let got = pc.get(*pid, &guard).unwrap();
+            if i == 3 {
+                assert!(got.is_none());
+                continue;
+            }
+            let (_, got, _) = got.unwrap();
+            let len = if i == 5 { 40 * 1024 } else { 64 };
+            let mut expected = page(&[i as u8], vec![i as u8; len]);
+            if i == 0 {
+                expected.insert(b"appended".to_vec(), vec![1]);
+            }
+            assert_eq!(got, &expected);
+        }
+        assert_eq!(pc.meta_pid_for_bucket(b"bucket", &guard).unwrap(), pids[1]);
+        assert!(pc.generate_id().unwrap() > id);
+        let (reused, _) = pc.allocate(page(b"new", vec![]), &guard).unwrap();
+        assert_eq!(reused, pids[3]);
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,3 +1,7 @@
+use std::{fs::File, path::Path};
+
+use parking_lot::Mutex;
+
 use super::*;
 
 /// Multithread IO support for Files
@@ -91,3 +95,237 @@ mod window {
         }
     }
 }
+
+/// The alignment O_DIRECT writes need, in memory, on disk and in length.
+pub(crate) const DIRECT_IO_ALIGN: usize = 512;
+
+/// A second handle on the log file, used for writing out IO buffers
+/// when `direct_io` or `dsync_io` is set, and for reading the log back
+/// with O_DIRECT when `direct_io` is.
+#[derive(Debug)]
+pub(crate) struct LogWriter {
+    file: File,
+    direct: bool,
+    // O_DIRECT writes are widened to whole sectors, which may be
+    // shared with the neighbouring IO buffers being written out
+    sectors: Mutex<()>,
+}
+
+impl LogWriter {
+    pub(crate) fn open(path: &Path, direct: bool, dsync: bool) -> io::Result<LogWriter> {
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true).write(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            let mut flags = 0;
+            #[cfg(target_os = "linux")]
+            {
+                if direct {
+                    flags |= libc::O_DIRECT;
+                }
+            }
+            if dsync {
+                flags |= libc::O_DSYNC;
+            }
+            options.custom_flags(flags);
+        }
+
+        Ok(LogWriter {
+            file: options.open(path)?,
+            direct,
+            sectors: Mutex::new(()),
+        })
+    }
+
+    /// Writes `buf` at `offset`, like `Pio::pwrite_all`.
+    pub(crate) fn pwrite_all(&self, buf: &[u8], offset: LogId) -> io::Result<()> {
+        if !self.direct {
+            return self.file.pwrite_all(buf, offset);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            self.pwrite_direct(buf, offset)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            unreachable!("direct_io is only supported on linux")
+        }
+    }
+
+    /// Reads `buf` at `offset`, like `Pio::pread_exact`.
+    pub(crate) fn pread_exact(&self, buf: &mut [u8], offset: LogId) -> io::Result<()> {
+        if !self.direct {
+            return self.file.pread_exact(buf, offset);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            self.pread_direct(buf, offset)
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            unreachable!("direct_io is only supported on linux")
+        }
+    }
+
+    /// The descriptor to submit writes to through io_uring, unless
+    /// they need the sector handling of `pwrite_all`.
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    pub(crate) fn uring_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        if self.direct {
+            None
+        } else {
+            Some(self.file.as_raw_fd())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn pread_direct(&self, buf: &mut [u8], offset: LogId) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let (start, end) = sectors(offset, buf.len());
+        let len = (end - start) as usize;
+        let mut scratch = vec![];
+        let aligned = aligned_buf(&mut scratch, len);
+
+        // the last sector of the file may be cut short, and a read
+        // that ends there can't be continued at its unaligned end
+        let mut read = 0;
+        while read < len {
+            match self
+                .file
+                .read_at(&mut aligned[read..], start + read as LogId)
+            {
+                Ok(0) => break,
+                Ok(n) => {
+                    read += n;
+                    if n % DIRECT_IO_ALIGN != 0 {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let at = (offset - start) as usize;
+        if read < at + buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        buf.copy_from_slice(&aligned[at..at + buf.len()]);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn pwrite_direct(&self, buf: &[u8], offset: LogId) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let align = DIRECT_IO_ALIGN as LogId;
+        let (start, end) = sectors(offset, buf.len());
+        let len = (end - start) as usize;
+        let mut scratch = vec![];
+        let aligned = aligned_buf(&mut scratch, len);
+
+        let _sectors = self.sectors.lock();
+
+        // keep whatever the first and last sectors already hold, reads
+        // past the end of the file leave them zeroed
+        let head_read = start < offset;
+        if head_read {
+            self.file.read_at(&mut aligned[..DIRECT_IO_ALIGN], start)?;
+        }
+        let last = end - align;
+        if end > offset + buf.len() as LogId && !(head_read && last == start) {
+            self.file
+                .read_at(&mut aligned[len - DIRECT_IO_ALIGN..], last)?;
+        }
+
+        let at = (offset - start) as usize;
+        aligned[at..at + buf.len()].copy_from_slice(buf);
+
+        self.file.write_all_at(aligned, start)
+    }
+}
+
+/// The span of whole sectors holding `len` bytes at `offset`.
+#[cfg(target_os = "linux")]
+fn sectors(offset: LogId, len: usize) -> (LogId, LogId) {
+    let align = DIRECT_IO_ALIGN as LogId;
+    let start = offset - offset % align;
+    let end = offset + len as LogId;
+    (start, end + (align - end % align) % align)
+}
+
+/// `len` zeroed bytes aligned for O_DIRECT, cut out of `scratch`.
+#[cfg(target_os = "linux")]
+fn aligned_buf(scratch: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    // over-allocate so that an aligned slice can be cut out of it
+    *scratch = vec![0_u8; len + DIRECT_IO_ALIGN];
+    let skew = scratch.as_ptr() as usize % DIRECT_IO_ALIGN;
+    let from = (DIRECT_IO_ALIGN - skew) % DIRECT_IO_ALIGN;
+    &mut scratch[from..from + len]
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    #[test]
+    fn test_direct_io_log() {
+        let path = std::env::temp_dir().join(format!("cloyster.direct_io.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = |direct| {
+            ConfigBuilder::new()
+                .path(&path)
+                .io_buf_size(4096)
+                .direct_io(direct)
+                .dsync_io(direct)
+                .build()
+        };
+
+        // flushing after each page leaves every write at an unaligned
+        // offset, sharing its first sector with the previous one
+        let pc = PageCache::<Page>::start(config(true)).unwrap();
+        let guard = pin();
+        let pids: Vec<_> = (0..64_u8)
+            .map(|i| {
+                let mut page = BTreeMap::new();
+                page.insert(vec![i], vec![i; usize::from(i) * 7]);
+                let (pid, _) = pc.allocate(page, &guard).unwrap();
+                pc.flush().unwrap();
+                (pid, i)
+            })
+            .collect();
+        drop(guard);
+        drop(pc);
+
+        // read back both through O_DIRECT and through the page cache
+        for direct in [true, false] {
+            let pc = PageCache::<Page>::start(config(direct)).unwrap();
+            let guard = pin();
+            for &(pid, i) in &pids {
+                let (_, page, _) = pc.get(pid, &guard).unwrap().unwrap();
+                assert_eq!(page[&vec![i]], vec![i; usize::from(i) * 7]);
+            }
+            drop(guard);
+            drop(pc);
+        }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
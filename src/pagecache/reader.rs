@@ -1,22 +1,19 @@
-use std::fs::File;
-
-use super::Pio;
-
 use super::*;
 
 pub(crate) trait LogReader {
-    fn read_segment_header(&self, id: LogId) -> Result<SegmentHeader>;
+    fn read_segment_header(&self, id: LogId, config: &Config) -> Result<SegmentHeader>;
 
     fn read_message(&self, lid: LogId, expected_lsn: Lsn, config: &Config) -> Result<LogRead>;
 }
 
-impl LogReader for File {
-    fn read_segment_header(&self, lid: LogId) -> Result<SegmentHeader> {
+impl LogReader for dyn StorageBackend {
+    fn read_segment_header(&self, lid: LogId, config: &Config) -> Result<SegmentHeader> {
         trace!("reading segment header at {}", lid);
 
-        let mut seg_header_buf = [0; SEG_HEADER_LEN];
-        self.pread_exact(&mut seg_header_buf, lid)?;
-        let segment_header = SegmentHeader::from(seg_header_buf);
+        let mut seg_header_buf = [0; SEG_HEADER_LEN + KEY_ID_LEN];
+        let seg_header_buf = &mut seg_header_buf[..config.seg_header_len()];
+        self.read_log(seg_header_buf, lid)?;
+        let segment_header = SegmentHeader::read(seg_header_buf);
 
         if segment_header.lsn < Lsn::try_from(lid).unwrap() {
             debug!(
@@ -31,24 +28,20 @@ impl LogReader for File {
 
     /// read a buffer from the disk
     fn read_message(&self, lid: LogId, expected_lsn: Lsn, config: &Config) -> Result<LogRead> {
-        let mut msg_header_buf = [0; MSG_HEADER_LEN];
-        self.pread_exact(&mut msg_header_buf, lid)?;
-        let header: MessageHeader = msg_header_buf.into();
+        let header_len = config.msg_header_len();
+        let mut msg_header_buf = [0; MAX_MSG_HEADER_LEN];
+        let msg_header_buf = &mut msg_header_buf[..header_len];
+        self.read_log(msg_header_buf, lid)?;
+        let header = MessageHeader::read(msg_header_buf);
 
         log::info!("lid: {}, lsn: {}, header: {:?}", lid, expected_lsn, header);
 
-        // we set the crc bytes to 0 because we will
-        // calculate the crc32 over all bytes other
-        // than the crc itself, including the bytes
+        // we set the checksum bytes to 0xFF because we will
+        // calculate the checksum over all bytes other
+        // than the checksum itself, including the bytes
         // in the header.
-        unsafe {
-            std::ptr::write_bytes(
-                msg_header_buf
-                    .as_mut_ptr()
-                    .add(MSG_HEADER_LEN - std::mem::size_of::<u32>()),
-                0xFF,
-                std::mem::size_of::<u32>(),
-            );
+        for byte in &mut msg_header_buf[DIGEST_OFFSET..] {
+            *byte = 0xFF;
         }
 
         let _measure = Measure::new(&M.read);
@@ -59,14 +52,13 @@ impl LogReader for File {
             seg_start,
             lid
         );
-        assert!(seg_start + SEG_HEADER_LEN as LogId <= lid);
+        assert!(seg_start + config.seg_header_len() as LogId <= lid);
 
         let ceiling = seg_start + segment_len as LogId;
 
-        assert!(lid + MSG_HEADER_LEN as LogId <= ceiling);
+        assert!(lid + header_len as LogId <= ceiling);
 
         if header.lsn % segment_len as Lsn != lid as Lsn % segment_len as Lsn {
-            let hb: [u8; MSG_HEADER_LEN] = header.into();
             // our message lsn was not aligned to our segment offset
             trace!(
                 "read a message whose header lsn \
@@ -75,7 +67,7 @@ impl LogReader for File {
                  expected: relative offset {} bytes: {:?}",
                 header,
                 lid % segment_len as LogId,
-                hb
+                msg_header_buf
             );
             return Ok(LogRead::Corrupted(header.len));
         }
@@ -88,7 +80,7 @@ impl LogReader for File {
             return Ok(LogRead::Corrupted(header.len));
         }
 
-        let max_possible_len = assert_usize(ceiling - lid - MSG_HEADER_LEN as LogId);
+        let max_possible_len = assert_usize(ceiling - lid - header_len as LogId);
 
         if usize::try_from(header.len).unwrap() > max_possible_len {
             trace!(
@@ -108,17 +100,15 @@ impl LogReader for File {
 
         // perform crc check on everything that isn't Corrupted
         let mut buf = vec![0; usize::try_from(header.len).unwrap()];
-        self.pread_exact(&mut buf, lid + MSG_HEADER_LEN as LogId)?;
+        self.read_log(&mut buf, lid + header_len as LogId)?;
 
-        // calculate the CRC32, calculating the hash on the
+        // calculate the checksum, calculating the hash on the
         // header afterwards
-        let mut hasher = crc32fast::Hasher::new();
+        let mut hasher = config.checksum.hasher();
         hasher.update(&buf);
-        hasher.update(&msg_header_buf);
-
-        let crc32 = hasher.finalize();
+        hasher.update(msg_header_buf);
 
-        if crc32 != header.crc32 {
+        if hasher.finalize() != header.digest {
             trace!(
                 "read a message with a bad checksum with header {:?}",
                 header
@@ -172,6 +162,28 @@ impl LogReader for File {
             | MessageKind::Free
             | MessageKind::Counter => {
                 trace!("read a successful inline message");
+                let buf = match &config.keys {
+                    Some(keys) => {
+                        let corruption = Error::Corruption {
+                            at: DiskPtr::Inline(lid),
+                        };
+                        // the segment header says which key sealed it
+                        let segment_header = self.read_segment_header(seg_start, config)?;
+                        let segment_lsn = header.lsn / segment_len as Lsn * segment_len as Lsn;
+                        let key_id = match segment_header.key_id {
+                            Some(key_id)
+                                if segment_header.ok && segment_header.lsn == segment_lsn =>
+                            {
+                                key_id
+                            }
+                            _ => return Err(corruption),
+                        };
+                        keys.get(key_id)?
+                            .open(Domain::Message, header.lsn, &u64_to_arr(header.pid), buf)
+                            .ok_or(corruption)?
+                    }
+                    None => buf,
+                };
                 let buf = if config.use_compression {
                     maybe_decompress(buf)?
                 } else {
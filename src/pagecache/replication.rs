@@ -0,0 +1,331 @@
+//! Read-only replicas fed by the log of a leader.
+//!
+//! The leader ships rounds of its log with `export_log_since`, and a
+//! `Follower` appends every round to a log of its own, then applies it
+//! to its snapshot the same way recovery does. Pages are read back from
+//! that log on demand. The replication position is logged in the same
+//! batch as each round, so a round is never applied twice.
+use std::{
+    fs,
+    io::{self, Read},
+    marker::PhantomData,
+};
+
+use parking_lot::{Mutex, RwLock};
+
+use super::{
+    archive::read_archive,
+    pagecache::{read_update, RecoveryGuard},
+    *,
+};
+
+/// A read-only replica of a `PageCache`, kept current by applying the
+/// archives its leader exports. Its own log must use
+/// `SegmentMode::Linear`, and only ever grows.
+pub struct Follower<P: Materializer> {
+    log: Log,
+    snapshot: RwLock<Snapshot>,
+    position: AtomicLsn,
+    receiving: Mutex<()>,
+    _marker: PhantomData<fn() -> P>,
+}
+
+impl<P: Materializer> Follower<P> {
+    /// Open or create a follower at `config`, resuming from the
+    /// replication position it reached before.
+    pub fn start(config: Config) -> Result<Self> {
+        if config.segment_mode != SegmentMode::Linear {
+            return Err(Error::Unsupported(
+                "followers must be configured with SegmentMode::Linear".into(),
+            ));
+        }
+
+        let snapshot = read_snapshot_or_default(&config)?;
+        let log = Log::start(config.clone(), snapshot.clone())?;
+        let position = match logged_position(&log, &snapshot)? {
+            Some(position) => position,
+            None => read_position(&config)?,
+        };
+
+        Ok(Self {
+            log,
+            snapshot: RwLock::new(snapshot),
+            position: AtomicLsn::new(position),
+            receiving: Mutex::new(()),
+            _marker: PhantomData,
+        })
+    }
+
+    /// The replication position: the Lsn of the last leader message
+    /// applied, and the `since` of the next export to receive.
+    pub fn stable_lsn(&self) -> Lsn {
+        self.position.load(Acquire)
+    }
+
+    /// Apply one archive exported by the leader. Messages at or before
+    /// the replication position are skipped, so rounds may overlap, but
+    /// they must not leave a gap. Returns the new replication position.
+    pub fn receive<R: Read>(&self, archive: R) -> Result<Lsn> {
+        let _receiving = self.receiving.lock();
+        let applied_up_to = self.stable_lsn();
+
+        let archive = read_archive(archive)?;
+        if archive.since > applied_up_to {
+            return Err(Error::Unsupported(format!(
+                "archive since lsn {} skips messages after \
+                 the replication position {}",
+                archive.since, applied_up_to
+            )));
+        }
+
+        let position = std::cmp::max(applied_up_to, archive.last_lsn);
+        let messages: Vec<_> = archive
+            .messages
+            .into_iter()
+            .filter(|(_, _, lsn, _)| *lsn > applied_up_to)
+            .collect();
+        if messages.is_empty() {
+            return Ok(position);
+        }
+
+        // a round is recovered entirely or not at all
+        let batch = RecoveryGuard::new(&self.log)?;
+        let mut applied = Vec::with_capacity(messages.len());
+        for (log_kind, pid, _, data) in messages {
+            let res = self.log.reserve(log_kind, pid, &data)?;
+            let sz = res.reservation_len();
+            let (lsn, ptr) = res.complete()?;
+            applied.push((log_kind, pid, lsn, ptr, sz));
+        }
+        let res = self.log.reserve(
+            LogKind::Replace,
+            REPLICATION_PID,
+            &u64_to_arr(position as u64),
+        )?;
+        let sz = res.reservation_len();
+        let (lsn, ptr) = res.complete()?;
+        applied.push((LogKind::Replace, REPLICATION_PID, lsn, ptr, sz));
+        batch.seal_batch()?;
+
+        self.log.flush()?;
+
+        let mut snapshot = self.snapshot.write();
+        for (log_kind, pid, lsn, ptr, sz) in applied {
+            snapshot.advance(log_kind, pid, lsn, ptr, sz);
+        }
+        drop(snapshot);
+
+        self.position.store(position, Release);
+
+        Ok(position)
+    }
+
+    /// Apply archive after archive from `stream` until it ends. Returns
+    /// the replication position reached.
+    pub fn follow<R: Read>(&self, mut stream: R) -> Result<Lsn> {
+        loop {
+            let mut first = [0];
+            match stream.read(&mut first) {
+                Ok(0) => return Ok(self.stable_lsn()),
+                Ok(_) => self.receive((&first[..]).chain(&mut stream))?,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+        }
+    }
+
+    /// Read the current state of a page.
+    pub fn get(&self, pid: PageId) -> Result<Option<P>> {
+        if is_reserved_pid(pid) {
+            return Err(Error::Unsupported(
+                "the first couple pages are reserved for \
+                 storing metadata, use meta() instead"
+                    .into(),
+            ));
+        }
+
+        let mut page: Option<P> = None;
+        for (lsn, ptr, _) in self.frags(pid) {
+            match read_update(&self.log, pid, lsn, ptr)? {
+                Update::Compact(base) => page = Some(base),
+                Update::Append(frag) => match page {
+                    Some(ref mut page) => page.merge(&frag),
+                    None => {
+                        return Err(Error::ReportableBug(format!(
+                            "pid {} has an append before its base",
+                            pid
+                        )));
+                    }
+                },
+                other => {
+                    return Err(Error::ReportableBug(format!(
+                        "unexpected {:?} in pid {}",
+                        other, pid
+                    )));
+                }
+            }
+        }
+
+        Ok(page)
+    }
+
+    /// Read the current `Meta` of the replica.
+    pub fn meta(&self) -> Result<Meta> {
+        match self.frags(META_PID).pop() {
+            None => Ok(Meta::default()),
+            Some((lsn, ptr, _)) => match read_update::<P>(&self.log, META_PID, lsn, ptr)? {
+                Update::Meta(meta) => Ok(meta),
+                other => Err(Error::ReportableBug(format!(
+                    "unexpected {:?} in the meta page",
+                    other
+                ))),
+            },
+        }
+    }
+
+    fn frags(&self, pid: PageId) -> Vec<(Lsn, DiskPtr, usize)> {
+        match self.snapshot.read().pt.get(&pid) {
+            Some(PageState::Present(frags)) => frags.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+/// The replication position last logged, if any.
+fn logged_position(log: &Log, snapshot: &Snapshot) -> Result<Option<Lsn>> {
+    let (lsn, ptr) = match snapshot.pt.get(&REPLICATION_PID) {
+        Some(PageState::Present(frags)) => match frags.last() {
+            Some((lsn, ptr, _)) => (*lsn, *ptr),
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+
+    match log.read(REPLICATION_PID, lsn, ptr)? {
+        LogRead::Inline(_, buf, _) if buf.len() == 8 => Ok(Some(arr_to_u64(&buf) as Lsn)),
+        _ => Err(Error::Corruption { at: ptr }),
+    }
+}
+
+/// The replication position of followers that kept it in a file of
+/// its own, before it was logged.
+fn read_position(config: &Config) -> Result<Lsn> {
+    let buf = match fs::read(config.replication_path()) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    if buf.len() != 12 || arr_to_u32(&buf[8..]) != crc32(&buf[..8]) {
+        return Err(
+            io::Error::new(io::ErrorKind::InvalidData, "corrupt replication position").into(),
+        );
+    }
+
+    Ok(arr_to_u64(&buf[..8]) as Lsn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::{
+        collections::BTreeMap,
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    type Page = BTreeMap<Key, Key>;
+
+    fn page(key: &[u8], len: usize) -> Page {
+        let mut page = BTreeMap::new();
+        page.insert(key.to_vec(), vec![key[0]; len]);
+        page
+    }
+
+    #[test]
+    fn test_follower_over_socket() {
+        let base = std::env::temp_dir().join(format!("cloyster.replica.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let follower_config = ConfigBuilder::new()
+            .path(base.join("follower"))
+            .io_buf_size(4096)
+            .segment_mode(SegmentMode::Linear)
+            .build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let follower = thread::spawn({
+            let config = follower_config.clone();
+            move || {
+                let follower = Follower::<Page>::start(config).unwrap();
+                let (stream, _) = listener.accept().unwrap();
+                follower.follow(stream).unwrap();
+                follower
+            }
+        });
+
+        let leader = PageCache::<Page>::start(
+            ConfigBuilder::new()
+                .path(base.join("leader"))
+                .io_buf_size(4096)
+                .build(),
+        )
+        .unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let guard = pin();
+
+        let (small, _) = leader.allocate(page(b"a", 8), &guard).unwrap();
+        let (large, _) = leader.allocate(page(b"b", 2048), &guard).unwrap();
+        let position = leader.export_log_since(0, &mut stream).unwrap();
+
+        let (ptr, _, _) = leader.get(small, &guard).unwrap().unwrap();
+        leader
+            .link(small, ptr, page(b"c", 8), &guard)
+            .unwrap()
+            .unwrap();
+        let (ptr, _, _) = leader.get(large, &guard).unwrap().unwrap();
+        leader.free(large, ptr, &guard).unwrap().unwrap();
+        // rounds may overlap
+        leader.export_log_since(0, &mut stream).unwrap();
+        let position = leader.export_log_since(position, &mut stream).unwrap();
+        drop(stream);
+
+        let follower = follower.join().unwrap();
+        assert_eq!(follower.stable_lsn(), position);
+
+        let mut expected = page(b"a", 8);
+        expected.extend(page(b"c", 8));
+        assert_eq!(follower.get(small).unwrap(), Some(expected.clone()));
+        assert_eq!(follower.get(large).unwrap(), None);
+        assert_eq!(follower.meta().unwrap(), *leader.meta(&guard).unwrap());
+        match follower.get(REPLICATION_PID) {
+            Err(Error::Unsupported(_)) => {}
+            other => panic!("read the replication position as a page: {:?}", other),
+        }
+
+        // a gap in the stream is refused
+        let mut gap = vec![];
+        leader.allocate(page(b"d", 8), &guard).unwrap();
+        leader.export_log_since(position + 1, &mut gap).unwrap();
+        assert!(follower.receive(&gap[..]).is_err());
+
+        // resumes where it left off, the position is in its log
+        drop(follower);
+        assert!(!follower_config.replication_path().exists());
+        let follower = Follower::<Page>::start(follower_config).unwrap();
+        assert_eq!(follower.stable_lsn(), position);
+        assert_eq!(follower.get(small).unwrap(), Some(expected.clone()));
+
+        // and does not apply the appends it has seen again
+        let mut again = vec![];
+        leader.export_log_since(0, &mut again).unwrap();
+        assert!(follower.receive(&again[..]).unwrap() > position);
+        assert_eq!(follower.get(small).unwrap(), Some(expected));
+
+        drop(guard);
+        drop(leader);
+        drop(follower);
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -83,7 +83,7 @@ impl<'a> Reservation<'a> {
 
         let buf = u64_to_arr(u64::try_from(lsn).unwrap());
 
-        let dst = &mut self.buf[MSG_HEADER_LEN..];
+        let dst = &mut self.buf[self.log.config.msg_header_len()..];
 
         dst.copy_from_slice(&buf);
     }
@@ -104,21 +104,11 @@ impl<'a> Reservation<'a> {
         // the order of hashing must be the
         // same here as during calls to
         // LogReader::read_message
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(&self.buf[MSG_HEADER_LEN..]);
-        hasher.update(&self.buf[..MSG_HEADER_LEN]);
-        let crc32 = hasher.finalize();
-        let crc32_arr = u32_to_arr(crc32 ^ 0xFFFF_FFFF);
-
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                crc32_arr.as_ptr(),
-                self.buf
-                    .as_mut_ptr()
-                    .add(MSG_HEADER_LEN - std::mem::size_of::<u32>()),
-                std::mem::size_of::<u32>(),
-            );
-        }
+        let header_len = self.log.config.msg_header_len();
+        let mut hasher = self.log.config.checksum.hasher();
+        hasher.update(&self.buf[header_len..]);
+        hasher.update(&self.buf[..header_len]);
+        write_digest(&hasher.finalize(), &mut self.buf[..header_len]);
         self.log.exit_reservation(&self.iobuf)?;
 
         Ok((self.lsn(), self.ptr()))
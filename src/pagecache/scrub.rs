@@ -0,0 +1,207 @@
+//! Checking the pages on disk against their checksums.
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::*;
+use crate::sync::Arc;
+
+/// A message, or the blob it points to, that failed its checksum or
+/// could not be read back as the update it should hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CorruptPtr {
+    /// The page the update belongs to.
+    pub pid: PageId,
+    /// The Lsn the update was logged at.
+    pub lsn: Lsn,
+    /// Where the update is on disk.
+    pub ptr: DiskPtr,
+}
+
+/// What `PageCache::scrub` found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    /// The number of pages whose updates were checked.
+    pub pages: u64,
+    /// The number of messages checked, blobs included.
+    pub messages: u64,
+    /// The number of bytes read to check them.
+    pub bytes: u64,
+    /// Everything that failed to check out, in the order it was found.
+    pub corrupt: Vec<CorruptPtr>,
+    /// Whether every page was checked, false if the scrub was
+    /// cancelled partway through.
+    pub finished: bool,
+}
+
+/// A scrub running on a thread of its own, see
+/// `PageCache::scrub_in_background`.
+#[derive(Debug)]
+pub struct Scrub {
+    cancelled: Arc<AtomicBool>,
+    thread: thread::JoinHandle<Result<ScrubReport>>,
+}
+
+impl Scrub {
+    pub(super) fn new(
+        cancelled: Arc<AtomicBool>,
+        thread: thread::JoinHandle<Result<ScrubReport>>,
+    ) -> Self {
+        Scrub { cancelled, thread }
+    }
+
+    /// Stops the scrub after the page it is checking. `join` then
+    /// returns what was found up to there.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, SeqCst);
+    }
+
+    /// Waits for the scrub to finish, or to stop after `cancel`.
+    pub fn join(self) -> Result<ScrubReport> {
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(Error::ReportableBug("the scrub thread panicked".to_owned())))
+    }
+}
+
+/// Reads the updates of one page after another back from storage,
+/// keeping to `bytes_per_sec` if there is a limit.
+pub(super) struct Scrubber<'a> {
+    config: &'a Config,
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    report: ScrubReport,
+}
+
+impl<'a> Scrubber<'a> {
+    pub(super) fn new(config: &'a Config, bytes_per_sec: Option<u64>) -> Self {
+        Scrubber {
+            config,
+            bytes_per_sec,
+            started: Instant::now(),
+            report: ScrubReport::default(),
+        }
+    }
+
+    /// Checks the update of `pid` logged at `lsn` to `ptr`. The caller
+    /// keeps the segment from being reused meanwhile by staying pinned
+    /// since it found `ptr` in the page table.
+    pub(super) fn check(&mut self, pid: PageId, lsn: Lsn, ptr: DiskPtr) -> Result<()> {
+        let read = self
+            .config
+            .storage
+            .read_message(ptr.lid(), lsn, self.config);
+
+        self.report.messages += 1;
+        let header_len = self.config.msg_header_len();
+        let len = match read {
+            Ok(LogRead::Inline(_, _, len)) => header_len + assert_usize(len),
+            Ok(LogRead::Blob(_, buf, _)) => header_len + BLOB_INLINE_LEN + buf.len(),
+            Ok(other) => {
+                warn!(
+                    "scrub found {:?} for pid {} at lsn {} ptr {}",
+                    other, pid, lsn, ptr
+                );
+                self.report.corrupt.push(CorruptPtr { pid, lsn, ptr });
+                header_len
+            }
+            Err(Error::Corruption { .. }) => {
+                warn!(
+                    "scrub found a corrupt blob for pid {} at lsn {} ptr {}",
+                    pid, lsn, ptr
+                );
+                self.report.corrupt.push(CorruptPtr { pid, lsn, ptr });
+                header_len
+            }
+            Err(e) => return Err(e),
+        };
+        self.report.bytes += len as u64;
+
+        Ok(())
+    }
+
+    /// Notes that all of a page has been checked, and sleeps for as
+    /// long as it takes to get back down to `bytes_per_sec`. Must not
+    /// be called while pinned, as that would hold up the reuse of
+    /// segments for as long.
+    pub(super) fn page_done(&mut self) {
+        self.report.pages += 1;
+
+        if let Some(bytes_per_sec) = self.bytes_per_sec {
+            let due =
+                Duration::from_secs_f64(self.report.bytes as f64 / bytes_per_sec.max(1) as f64);
+            let elapsed = self.started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+
+    pub(super) fn finish(mut self, finished: bool) -> ScrubReport {
+        self.report.finished = finished;
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    fn page(key: &[u8], value: Vec<u8>) -> Page {
+        let mut page = BTreeMap::new();
+        page.insert(key.to_vec(), value);
+        page
+    }
+
+    #[test]
+    fn test_scrub_finds_corrupt_messages_and_blobs() {
+        let storage = Arc::new(MemoryBackend::default());
+        let config = ConfigBuilder::new()
+            .storage(storage.clone())
+            .io_buf_size(64 * 1024)
+            .build();
+        let pc = PageCache::<Page>::start(config).unwrap();
+
+        let guard = pin();
+        let (inline_pid, _) = pc.allocate(page(b"a", vec![7; 64]), &guard).unwrap();
+        let (blob_pid, _) = pc.allocate(page(b"b", vec![9; 32 * 1024]), &guard).unwrap();
+        drop(guard);
+        pc.flush().unwrap();
+
+        let clean = pc.scrub(None).unwrap();
+        assert!(clean.finished);
+        assert!(clean.corrupt.is_empty(), "{:?}", clean.corrupt);
+        assert!(clean.pages >= 2);
+
+        // flip a byte of the inline value in the log
+        let mut log = vec![0; assert_usize(storage.log_len().unwrap())];
+        storage.read_log(&mut log, 0).unwrap();
+        let at = log.windows(64).position(|w| w == [7; 64]).unwrap();
+        storage.write_log(&[8], at as LogId).unwrap();
+
+        // and one of the blob
+        let (blob, _) = storage.list_blobs().unwrap()[0];
+        let mut buf = storage.get_blob(blob).unwrap();
+        *buf.last_mut().unwrap() ^= 1;
+        storage.delete_blob(blob).unwrap();
+        storage.put_blob(blob, &buf).unwrap();
+
+        // throttled, in the background
+        let report = pc
+            .scrub_in_background(Some(16 * 1024 * 1024))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert!(report.finished);
+        assert_eq!(report.pages, clean.pages);
+        let mut pids: Vec<_> = report.corrupt.iter().map(|c| c.pid).collect();
+        pids.sort_unstable();
+        assert_eq!(pids, vec![inline_pid, blob_pid]);
+        assert!(report.corrupt.iter().any(|c| c.ptr.is_blob()));
+    }
+}
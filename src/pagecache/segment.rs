@@ -82,11 +82,22 @@ pub struct SegmentAccountant {
     tip: LogId,
     max_stabilized_lsn: Lsn,
     to_clean: VecSet<LogId>,
-    pause_rewriting: bool,
+    /// number of outstanding `pause_rewriting` calls
+    pause_rewriting: usize,
     ordering: BTreeMap<Lsn, LogId>,
     async_truncations: Vec<Promise<Result<()>>>,
     deferred_free_segments: Option<Vec<LogId>>,
     deferred_free_segments_after: Lsn,
+    /// blobs that are no longer referenced, kept until the Lsn they
+    /// were retired at falls out of the history window
+    retired_blobs: Vec<(Lsn, BlobPointer)>,
+    /// free segments whose headers get zeroed once the history they
+    /// hold falls out of the history window
+    to_zero: Vec<LogId>,
+    /// the oldest Lsn that point-in-time recovery can still reach
+    history_floor: Lsn,
+    /// the key every segment in use was last found to be sealed with
+    rotated_to: Option<KeyId>,
 }
 
 /// A `Segment` holds the bookkeeping information for
@@ -107,6 +118,10 @@ struct Segment {
     // set of pages that we replaced from other segments
     deferred_replacements: FastSet8<(PageId, SegmentId)>,
     lsn: Option<Lsn>,
+    // the lsn this segment was last freed at
+    freed_at: Lsn,
+    // the key its messages are sealed with, if encrypted
+    key_id: Option<KeyId>,
     state: SegmentState,
 }
 
@@ -180,18 +195,19 @@ impl Segment {
         self.deferred_rm_blob.clear();
         self.deferred_replacements.clear();
         self.lsn = Some(new_lsn);
+        self.key_id = None;
         self.state = Active;
     }
 
     /// Transitions a segment to being in the Inactive state.
     /// Returns the set of page replacements that happened
-    /// while this Segment was Active
+    /// while this Segment was Active, and the blobs whose
+    /// removal was deferred until now.
     fn active_to_inactive(
         &mut self,
         lsn: Lsn,
         from_recovery: bool,
-        config: &Config,
-    ) -> Result<FastSet8<(PageId, usize)>> {
+    ) -> (FastSet8<(PageId, usize)>, FastSet8<BlobPointer>) {
         trace!("setting Segment with lsn {:?} to Inactive", self.lsn());
         assert!(
             self.state == Active || self.state == Draining,
@@ -209,20 +225,11 @@ impl Segment {
 
         // now we can push any deferred blob removals to the removed set
         let deferred_rm_blob = mem::replace(&mut self.deferred_rm_blob, FastSet8::default());
-        for ptr in deferred_rm_blob {
-            trace!(
-                "removing blob {} while transitioning \
-                 segment lsn {:?} to Inactive",
-                ptr,
-                self.lsn,
-            );
-            remove_blob(ptr, config)?;
-        }
 
         let deferred_replacements =
             mem::replace(&mut self.deferred_replacements, FastSet8::default());
 
-        Ok(deferred_replacements)
+        (deferred_replacements, deferred_rm_blob)
     }
 
     fn inactive_to_draining(&mut self, lsn: Lsn) {
@@ -318,13 +325,15 @@ impl Segment {
         self.deferred_replacements.extend(deferred);
     }
 
-    fn remove_blob(&mut self, blob_ptr: BlobPointer, config: &Config) -> Result<()> {
+    /// Returns true if the blob can be removed right away.
+    fn remove_blob(&mut self, blob_ptr: BlobPointer) -> bool {
         match self.state {
             Active => {
                 // we have received a removal before
                 // transferring this segment to Inactive, so
                 // we defer this pid's removal until the transfer.
                 self.deferred_rm_blob.insert(blob_ptr);
+                false
             }
             Inactive | Draining => {
                 trace!(
@@ -333,12 +342,10 @@ impl Segment {
                      or Draining.",
                     blob_ptr,
                 );
-                remove_blob(blob_ptr, config)?;
+                true
             }
             Free => panic!("remove_blob called on a Free Segment"),
         }
-
-        Ok(())
     }
 
     // The live percentage between 0 and 100
@@ -373,13 +380,29 @@ impl SegmentAccountant {
             tip: 0,
             max_stabilized_lsn: -1,
             to_clean: VecSet::default(),
-            pause_rewriting: false,
+            pause_rewriting: 0,
             ordering: BTreeMap::default(),
             async_truncations: Vec::default(),
             deferred_free_segments: None,
             deferred_free_segments_after: 0,
+            retired_blobs: vec![],
+            to_zero: vec![],
+            history_floor: 0,
+            rotated_to: None,
         };
 
+        if !ret.config.read_only && ret.config.file_storage() {
+            if ret.config.history_window == 0 {
+                timeline::clear_history_floor(&ret.config)?;
+            } else if let Some(floor) = timeline::history_floor(&ret.config)? {
+                ret.history_floor = floor;
+            } else {
+                // whatever came before was not kept for recovery
+                timeline::set_history_floor(&ret.config, snapshot.last_lsn)?;
+                ret.history_floor = snapshot.last_lsn;
+            }
+        }
+
         if let SegmentMode::Linear = ret.config.segment_mode {
             // this is a hack to prevent segments from being overwritten
             // when operating without a `PageCache`
@@ -393,12 +416,13 @@ impl SegmentAccountant {
 
     fn initialize_from_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
         let io_buf_size = self.config.io_buf_size;
-        let file_len = self.config.file.metadata()?.len();
+        let header_len = self.config.msg_header_len();
+        let file_len = self.config.storage.log_len()?;
         let empty_snapshot = snapshot.pt.is_empty();
         let number_of_segments = usize::try_from(file_len / io_buf_size as u64).unwrap()
             + if empty_snapshot
                 || file_len % u64::try_from(io_buf_size).unwrap()
-                    < u64::try_from(SEG_HEADER_LEN).unwrap()
+                    < u64::try_from(self.config.seg_header_len()).unwrap()
             {
                 0
             } else {
@@ -436,7 +460,7 @@ impl SegmentAccountant {
                     }
                 }
                 PageState::Free(lsn, ptr) => {
-                    add(pid, lsn, MSG_HEADER_LEN, ptr.lid(), &mut segments);
+                    add(pid, lsn, header_len, ptr.lid(), &mut segments);
                 }
             }
         }
@@ -494,11 +518,20 @@ impl SegmentAccountant {
                         "freeing segment with lid {} during SA initialization",
                         segment_base
                     );
-                    if self.tip == segment_base + io_buf_size as LogId {
+                    let keeps_history = self.config.history_window > 0;
+                    if self.tip == segment_base + io_buf_size as LogId && !keeps_history {
                         self.tip -= io_buf_size as LogId;
                     } else {
                         segment.state = Free;
-                        self.free_segment(segment_base, true);
+                        segment.freed_at = snapshot.last_lsn;
+                        self.free_segment(segment_base, snapshot.last_lsn, true)?;
+                    }
+
+                    if keeps_history {
+                        // its messages may still be replayed by
+                        // point-in-time recovery
+                        self.to_zero.push(segment_base);
+                        continue;
                     }
 
                     // NB we corrupt the segment header to cause this
@@ -513,12 +546,12 @@ impl SegmentAccountant {
                         segment_base
                     );
                     maybe_fail!("segment initial free zero");
-                    self.config.file.pwrite_all(
-                        &*vec![MessageKind::Corrupted.into(); SEG_HEADER_LEN],
-                        segment_base,
-                    )?;
-                    if !self.config.temporary {
-                        self.config.file.sync_all()?;
+                    if !self.config.read_only {
+                        self.config.storage.write_log(
+                            &*vec![MessageKind::Corrupted.into(); self.config.seg_header_len()],
+                            segment_base,
+                        )?;
+                        self.config.storage.sync_log()?;
                     }
                 } else if segment_sizes[idx] <= drain_sz {
                     trace!(
@@ -545,6 +578,24 @@ impl SegmentAccountant {
             self.deferred_free_segments_after = snapshot.last_lsn;
         }
 
+        if self.config.keys.is_some() {
+            // learn which key each segment in use is sealed with
+            for (idx, segment) in segments.iter_mut().enumerate() {
+                let segment_base = idx as LogId * io_buf_size as LogId;
+                let segment_lsn = match segment.lsn {
+                    Some(lsn) if !segment.is_free() || self.to_zero.contains(&segment_base) => lsn,
+                    _ => continue,
+                };
+                let header = self
+                    .config
+                    .storage
+                    .read_segment_header(segment_base, &self.config)?;
+                if header.ok && header.lsn == segment_lsn {
+                    segment.key_id = header.key_id;
+                }
+            }
+        }
+
         trace!("initialized self.segments to {:?}", segments);
         self.segments = segments;
 
@@ -565,7 +616,7 @@ impl SegmentAccountant {
         Ok(())
     }
 
-    fn free_segment(&mut self, lid: LogId, in_recovery: bool) {
+    fn free_segment(&mut self, lid: LogId, lsn: Lsn, in_recovery: bool) -> Result<()> {
         debug!("freeing segment {}", lid);
         debug!("free list before free {:?}", self.free);
 
@@ -598,21 +649,69 @@ impl SegmentAccountant {
             }
         }
 
+        if !in_recovery && self.has_stale_key(lid) {
+            // NB like empty segments during initialization, this gets
+            // zeroed so that recovery skips it and never needs the key
+            // it was sealed with again, but only once point-in-time
+            // recovery no longer replays it.
+            trace!("zeroing segment {} sealed with a rotated key later", lid);
+            self.to_zero.push(lid);
+        }
+
+        self.segments[idx].freed_at = lsn;
         self.free.insert(lid);
+        Ok(())
+    }
+
+    /// Removes a blob that is no longer referenced by the log after
+    /// `lsn`, or keeps it around while that is within the history
+    /// window.
+    fn retire_blob(&mut self, blob_ptr: BlobPointer, lsn: Lsn) -> Result<()> {
+        if self.config.history_window == 0 {
+            remove_blob(blob_ptr, &self.config)
+        } else {
+            self.retired_blobs.push((lsn, blob_ptr));
+            Ok(())
+        }
+    }
+
+    /// Durably raises the history floor above history retired at
+    /// `lsn`, before that history is overwritten or removed.
+    fn drop_history(&mut self, lsn: Lsn) -> Result<()> {
+        if self.config.history_window == 0 {
+            return Ok(());
+        }
+        // NB retirement is noted at the start of the segment holding
+        // what replaced it, so the replacement may be later than `lsn`
+        let floor = lsn + self.config.io_buf_size as Lsn;
+        if floor > self.history_floor {
+            timeline::set_history_floor(&self.config, floor)?;
+            self.history_floor = floor;
+        }
+        Ok(())
+    }
+
+    /// Whether history retired at `lsn` is still needed for
+    /// point-in-time recovery.
+    fn in_history_window(&self, lsn: Lsn) -> bool {
+        self.config.history_window > 0
+            && lsn + self.config.history_window as Lsn >= self.max_stabilized_lsn
     }
 
     /// Causes all new allocations to occur at the end of the file, which
     /// is necessary to preserve consistency while concurrently iterating
     /// through the log during snapshot creation.
+    /// Pauses nest, rewriting only resumes once every pause has been
+    /// matched by a `resume_rewriting`.
     pub(super) fn pause_rewriting(&mut self) {
-        self.pause_rewriting = true;
+        self.pause_rewriting += 1;
     }
 
     /// Re-enables segment rewriting after iteration is complete.
     pub(super) fn resume_rewriting(&mut self) {
         // we never want to resume segment rewriting in Linear mode
         if self.config.segment_mode != SegmentMode::Linear {
-            self.pause_rewriting = false;
+            self.pause_rewriting = self.pause_rewriting.saturating_sub(1);
         }
     }
 
@@ -647,8 +746,11 @@ impl SegmentAccountant {
         // Do we need to schedule any blob cleanups?
         // Not if we just moved the pointer without changing
         // the underlying blob, as is the case with a single Blob
-        // with nothing else.
-        let schedule_rm_blob = !(old_ptrs.len() == 1 && old_ptrs[0].is_blob());
+        // with nothing else, unless it was rewritten into a new one.
+        let schedule_rm_blob = !(old_ptrs.len() == 1
+            && old_ptrs[0].is_blob()
+            && new_ptr.is_blob()
+            && old_ptrs[0].blob().1 == new_ptr.blob().1);
 
         let mut deferred_replacements = FastSet8::default();
 
@@ -657,7 +759,10 @@ impl SegmentAccountant {
 
             if schedule_rm_blob && old_ptr.is_blob() {
                 trace!("queueing blob removal for {} in our own segment", old_ptr);
-                self.segments[new_idx].remove_blob(old_ptr.blob().1, &self.config)?;
+                let blob_ptr = old_ptr.blob().1;
+                if self.segments[new_idx].remove_blob(blob_ptr) {
+                    self.retire_blob(blob_ptr, lsn)?;
+                }
             }
 
             let old_idx = self.lid_to_idx(old_lid);
@@ -700,7 +805,7 @@ impl SegmentAccountant {
         Ok(())
     }
 
-    fn possibly_clean_or_free_segment(&mut self, idx: usize, lsn: Lsn) {
+    fn possibly_clean_or_free_segment(&mut self, idx: usize, lsn: Lsn) -> Result<()> {
         let can_drain = segment_is_drainable(
             idx,
             self.segments.len(),
@@ -729,8 +834,109 @@ impl SegmentAccountant {
                 "freed segment {} in possibly_clean_or_free_segment",
                 segment_start
             );
-            self.free_segment(segment_start, false);
+            self.free_segment(segment_start, lsn, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the segment at `lid` is sealed with a key other than
+    /// the current one.
+    pub(super) fn has_stale_key(&mut self, lid: LogId) -> bool {
+        let idx = self.lid_to_idx(lid);
+        match (&self.config.keys, self.segments[idx].key_id) {
+            (Some(keys), Some(key_id)) => key_id != keys.current_id(),
+            _ => false,
+        }
+    }
+
+    /// Whether the segment at `idx` is sealed with a key other than
+    /// `current` and may still be read, because it is in use or its
+    /// header was not zeroed yet.
+    fn is_stale(&self, idx: usize, current: KeyId) -> bool {
+        let segment = &self.segments[idx];
+        let lid = (idx * self.config.io_buf_size) as LogId;
+        matches!(segment.key_id, Some(id) if id != current)
+            && (!segment.is_free() || self.to_zero.contains(&lid))
+    }
+
+    /// Notes that every segment in use is sealed with the key
+    /// `current`.
+    fn finish_rotation(&mut self, current: KeyId) {
+        self.rotated_to = Some(current);
+        if let Err(e) = self.config.record_key(current) {
+            warn!("failed to record the key in the config file: {:?}", e);
+        }
+    }
+
+    /// Reports the segments in use that are still sealed with keys
+    /// other than the current one.
+    pub(super) fn key_rotation(&mut self) -> Option<KeyRotation> {
+        let current = self.config.keys.as_ref()?.current_id();
+        let mut rotation = KeyRotation {
+            current,
+            segments: 0,
+            pages: 0,
+            blobs: 0,
+        };
+        for idx in 0..self.segments.len() {
+            if self.is_stale(idx, current) {
+                rotation.segments += 1;
+                rotation.pages += self.segments[idx].len();
+            }
+        }
+        if rotation.is_done() && self.rotated_to != Some(current) {
+            self.finish_rotation(current);
         }
+        Some(rotation)
+    }
+
+    /// Starts draining a segment still sealed with a key other than
+    /// the current one, one at a time, so that its pages get sealed
+    /// with the current key as they are rewritten.
+    fn drain_stale_key_segment(&mut self) -> Result<()> {
+        let current = match &self.config.keys {
+            Some(keys) => keys.current_id(),
+            None => return Ok(()),
+        };
+        if self.rotated_to == Some(current) {
+            return Ok(());
+        }
+
+        let stale: Vec<usize> = (0..self.segments.len())
+            .filter(|idx| self.is_stale(*idx, current))
+            .collect();
+        if stale.is_empty() {
+            self.finish_rotation(current);
+            return Ok(());
+        }
+        if stale.iter().any(|idx| self.segments[*idx].is_draining()) {
+            return Ok(());
+        }
+        let idx = match stale
+            .into_iter()
+            .find(|idx| self.segments[*idx].is_inactive())
+        {
+            Some(idx) => idx,
+            None => return Ok(()),
+        };
+
+        let lsn = self.max_stabilized_lsn;
+        let segment_start = (idx * self.config.io_buf_size) as LogId;
+        trace!(
+            "SA draining segment {} to rotate the key it is sealed with",
+            segment_start
+        );
+        self.segments[idx].inactive_to_draining(lsn);
+        self.to_clean.insert(segment_start);
+
+        if self.segments[idx].can_free() {
+            self.segments[idx].draining_to_free(lsn);
+            self.to_clean.remove(&segment_start);
+            self.free_segment(segment_start, lsn, false)?;
+        }
+
+        Ok(())
     }
 
     /// Called by the `PageCache` to find pages that are in
@@ -830,10 +1036,11 @@ impl SegmentAccountant {
 
         if self.deferred_free_segments.is_some() && lsn > self.deferred_free_segments_after {
             let deferred_free_segments = self.deferred_free_segments.take().unwrap();
+            let freed_at = self.deferred_free_segments_after;
             for segment_base in deferred_free_segments {
                 let idx = self.lid_to_idx(segment_base);
                 self.segments[idx].state = Free;
-                self.free_segment(segment_base, false);
+                self.free_segment(segment_base, freed_at, false)?;
             }
         }
 
@@ -854,6 +1061,38 @@ impl SegmentAccountant {
             self.deactivate_segment(lsn)?;
         }
 
+        self.drain_stale_key_segment()?;
+
+        let retired_blobs = mem::take(&mut self.retired_blobs);
+        for (retired_at, blob_ptr) in retired_blobs {
+            if self.in_history_window(retired_at) {
+                self.retired_blobs.push((retired_at, blob_ptr));
+            } else {
+                self.drop_history(retired_at)?;
+                remove_blob(blob_ptr, &self.config)?;
+            }
+        }
+
+        let to_zero = mem::take(&mut self.to_zero);
+        for segment_base in to_zero {
+            let idx = self.lid_to_idx(segment_base);
+            let freed_at = self.segments[idx].freed_at;
+            if self.in_history_window(freed_at) {
+                self.to_zero.push(segment_base);
+                continue;
+            }
+            self.drop_history(freed_at)?;
+            trace!(
+                "zeroing segment {} now that its history is dropped",
+                segment_base
+            );
+            self.config.storage.write_log(
+                &vec![MessageKind::Corrupted.into(); self.config.seg_header_len()],
+                segment_base,
+            )?;
+            self.config.storage.sync_log()?;
+        }
+
         Ok(())
     }
 
@@ -874,7 +1113,18 @@ impl SegmentAccountant {
         );
 
         let replacements = if self.segments[idx].state == Active {
-            self.segments[idx].active_to_inactive(lsn, false, &self.config)?
+            let (replacements, deferred_rm_blob) =
+                self.segments[idx].active_to_inactive(lsn, false);
+            for blob_ptr in deferred_rm_blob {
+                trace!(
+                    "removing blob {} while transitioning \
+                     segment lsn {} to Inactive",
+                    blob_ptr,
+                    lsn,
+                );
+                self.retire_blob(blob_ptr, lsn)?;
+            }
+            replacements
         } else {
             Default::default()
         };
@@ -926,7 +1176,7 @@ impl SegmentAccountant {
         }
 
         for old_idx in old_segments {
-            self.possibly_clean_or_free_segment(old_idx, lsn);
+            self.possibly_clean_or_free_segment(old_idx, lsn)?;
         }
 
         // if we have a lot of free segments in our whole file,
@@ -973,8 +1223,9 @@ impl SegmentAccountant {
         lid
     }
 
-    /// Returns the next offset to write a new segment in.
-    pub(super) fn next(&mut self, lsn: Lsn) -> Result<LogId> {
+    /// Returns the next offset to write a new segment in, which will
+    /// be sealed with the key `key_id` if encrypted.
+    pub(super) fn next(&mut self, lsn: Lsn, key_id: Option<KeyId>) -> Result<LogId> {
         let _measure = Measure::new(&M.accountant_next);
 
         assert_eq!(
@@ -988,7 +1239,10 @@ impl SegmentAccountant {
             .iter()
             .filter(|lid| {
                 let idx = usize::try_from(*lid / self.config.io_buf_size as LogId).unwrap();
-                if let Some(last_lsn) = self.segments[idx].lsn {
+                if self.in_history_window(self.segments[idx].freed_at) {
+                    // still holds history for point-in-time recovery
+                    false
+                } else if let Some(last_lsn) = self.segments[idx].lsn {
                     last_lsn < self.max_stabilized_lsn
                 } else {
                     true
@@ -1003,6 +1257,9 @@ impl SegmentAccountant {
         while self.tip != 0 && self.free.len() > 1 {
             let last_segment = self.tip - self.config.io_buf_size as LogId;
             if free.contains(&last_segment) {
+                let idx = self.lid_to_idx(last_segment);
+                self.drop_history(self.segments[idx].freed_at)?;
+                self.to_zero.retain(|lid| *lid != last_segment);
                 self.free.remove(&last_segment);
                 self.truncate(last_segment)?;
             } else {
@@ -1013,9 +1270,12 @@ impl SegmentAccountant {
         // pop free or add to end
         let safe = free.first();
 
-        let lid = match (self.pause_rewriting, safe) {
+        let lid = match (self.pause_rewriting > 0, safe) {
             (true, _) | (_, None) => self.bump_tip(),
             (_, Some(&next)) => {
+                let idx = self.lid_to_idx(next);
+                self.drop_history(self.segments[idx].freed_at)?;
+                self.to_zero.retain(|lid| *lid != next);
                 self.free.remove(&next);
                 next
             }
@@ -1032,6 +1292,10 @@ impl SegmentAccountant {
         }
 
         self.segments[idx].free_to_active(lsn);
+        self.segments[idx].key_id = key_id;
+        if key_id != self.rotated_to {
+            self.rotated_to = None;
+        }
 
         self.ordering.insert(lsn, lid);
 
@@ -1070,7 +1334,7 @@ impl SegmentAccountant {
         );
 
         assert!(
-            self.pause_rewriting,
+            self.pause_rewriting > 0,
             "must pause rewriting before \
              iterating over segments"
         );
@@ -1114,9 +1378,9 @@ impl SegmentAccountant {
         let result = Promise::new(move || {
             debug!("truncating file to length {}", at);
             config
-                .file
-                .set_len(at)
-                .and_then(|_| config.file.sync_all())
+                .storage
+                .truncate_log(at)
+                .and_then(|_| config.storage.sync_log())
                 .map_err(|e| e.into())
         });
 
@@ -0,0 +1,298 @@
+//! A disk that can be crashed, for testing recovery.
+use std::{collections::BTreeMap, io};
+
+use parking_lot::Mutex;
+
+use super::{backend::not_found, *};
+
+/// The granularity at which `SimulatedDisk` tears writes.
+const SECTOR: LogId = 512;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Write(LogId, Vec<u8>),
+    Truncate(u64),
+}
+
+impl Op {
+    fn apply(&self, log: &mut Vec<u8>) {
+        match self {
+            Op::Write(offset, buf) => {
+                let start = assert_usize(*offset);
+                let end = start + buf.len();
+                if log.len() < end {
+                    log.resize(end, 0);
+                }
+                log[start..end].copy_from_slice(buf);
+            }
+            Op::Truncate(len) => log.resize(assert_usize(*len), 0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct DiskState {
+    rng: u64,
+    // what reads see
+    log: Vec<u8>,
+    // what is known to survive a crash
+    synced_log: Vec<u8>,
+    // log operations since the last sync, in the order they were issued
+    unsynced: Vec<Op>,
+    blobs: BTreeMap<Lsn, Vec<u8>>,
+    snapshots: BTreeMap<Lsn, Vec<u8>>,
+    // log writes seen so far, and the one to crash at
+    writes: usize,
+    crash_on_write: Option<usize>,
+    crashed: Option<Box<DiskState>>,
+}
+
+impl DiskState {
+    // xorshift64*, seeded deterministically so failures can be replayed
+    fn next(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn coin(&mut self) -> bool {
+        self.next() & 1 == 0
+    }
+
+    fn new(
+        rng: u64,
+        log: Vec<u8>,
+        blobs: BTreeMap<Lsn, Vec<u8>>,
+        snapshots: BTreeMap<Lsn, Vec<u8>>,
+    ) -> DiskState {
+        DiskState {
+            // xorshift gets stuck on 0
+            rng: rng | 1,
+            log: log.clone(),
+            synced_log: log,
+            unsynced: vec![],
+            blobs,
+            snapshots,
+            writes: 0,
+            crash_on_write: None,
+            crashed: None,
+        }
+    }
+
+    fn crash(&mut self) -> DiskState {
+        let mut survivors = vec![];
+        for op in self.unsynced.clone() {
+            if !self.coin() {
+                continue;
+            }
+
+            match op {
+                Op::Write(offset, buf) if self.coin() => {
+                    // torn: only some of the sectors made it
+                    let end = offset + buf.len() as LogId;
+                    let mut start = offset;
+                    while start < end {
+                        let sector_end = std::cmp::min(end, (start / SECTOR + 1) * SECTOR);
+                        if self.coin() {
+                            let from = assert_usize(start - offset);
+                            let to = assert_usize(sector_end - offset);
+                            survivors.push(Op::Write(start, buf[from..to].to_vec()));
+                        }
+                        start = sector_end;
+                    }
+                }
+                op => survivors.push(op),
+            }
+        }
+
+        // the device is free to persist them in any order
+        for i in (1..survivors.len()).rev() {
+            let j = assert_usize(self.next() % (i as u64 + 1));
+            survivors.swap(i, j);
+        }
+
+        let mut log = self.synced_log.clone();
+        for op in &survivors {
+            op.apply(&mut log);
+        }
+
+        let rng = self.next();
+        DiskState::new(rng, log, self.blobs.clone(), self.snapshots.clone())
+    }
+}
+
+/// An in-memory `StorageBackend` whose log behaves like a disk without
+/// a battery-backed cache: writes only become durable once `sync_log`
+/// is called. `crash` returns the disk as a restart would find it.
+///
+/// Blobs and snapshots are treated as durable once stored, as the file
+/// storage syncs them before returning.
+#[derive(Debug)]
+pub struct SimulatedDisk {
+    state: Mutex<DiskState>,
+}
+
+impl SimulatedDisk {
+    /// An empty disk. `seed` decides which writes a crash keeps.
+    pub fn new(seed: u64) -> SimulatedDisk {
+        SimulatedDisk {
+            state: Mutex::new(DiskState::new(
+                seed,
+                vec![],
+                BTreeMap::new(),
+                BTreeMap::new(),
+            )),
+        }
+    }
+
+    /// The disk as it would be after losing power now. Every log write
+    /// since the last sync may be dropped, torn at a sector boundary, or
+    /// land after writes issued later than it. This disk is left as it
+    /// was, so whoever is still writing to it can't affect the result.
+    pub fn crash(&self) -> SimulatedDisk {
+        SimulatedDisk {
+            state: Mutex::new(self.state.lock().crash()),
+        }
+    }
+
+    /// Loses power while the `n`th log write from now is in flight, the
+    /// result is kept for `crashed`. Writing carries on as if nothing
+    /// happened, which lets a test crash in the middle of a call without
+    /// having to interrupt it.
+    pub fn crash_on_write(&self, n: usize) {
+        let mut state = self.state.lock();
+        state.crash_on_write = Some(state.writes + n);
+        state.crashed = None;
+    }
+
+    /// The disk left behind by `crash_on_write`, if that write happened.
+    pub fn crashed(&self) -> Option<SimulatedDisk> {
+        let mut state = self.state.lock();
+        state.crashed.take().map(|crashed| SimulatedDisk {
+            state: Mutex::new(*crashed),
+        })
+    }
+
+    fn push(&self, op: Op) {
+        let mut state = self.state.lock();
+        op.apply(&mut state.log);
+        state.unsynced.push(op);
+    }
+}
+
+impl StorageBackend for SimulatedDisk {
+    fn read_log(&self, buf: &mut [u8], offset: LogId) -> io::Result<()> {
+        let state = self.state.lock();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        match state
+            .log
+            .get(start..)
+            .and_then(|tail| tail.get(..buf.len()))
+        {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            )),
+        }
+    }
+
+    fn write_log(&self, buf: &[u8], offset: LogId) -> io::Result<()> {
+        self.push(Op::Write(offset, buf.to_vec()));
+
+        let mut state = self.state.lock();
+        state.writes += 1;
+        if state.crash_on_write == Some(state.writes) {
+            state.crash_on_write = None;
+            let crashed = state.crash();
+            state.crashed = Some(Box::new(crashed));
+        }
+        Ok(())
+    }
+
+    fn sync_log(&self) -> io::Result<()> {
+        let mut state = self.state.lock();
+        let unsynced = std::mem::take(&mut state.unsynced);
+        for op in &unsynced {
+            op.apply(&mut state.synced_log);
+        }
+        Ok(())
+    }
+
+    fn log_len(&self) -> io::Result<u64> {
+        Ok(self.state.lock().log.len() as u64)
+    }
+
+    fn truncate_log(&self, len: u64) -> io::Result<()> {
+        self.push(Op::Truncate(len));
+        Ok(())
+    }
+
+    fn put_blob(&self, lsn: Lsn, data: &[u8]) -> io::Result<()> {
+        self.state.lock().blobs.insert(lsn, data.to_vec());
+        Ok(())
+    }
+
+    fn get_blob(&self, lsn: Lsn) -> io::Result<Vec<u8>> {
+        let state = self.state.lock();
+        state
+            .blobs
+            .get(&lsn)
+            .cloned()
+            .ok_or_else(|| not_found("blob", lsn))
+    }
+
+    fn delete_blob(&self, lsn: Lsn) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state
+            .blobs
+            .remove(&lsn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("blob", lsn))
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<(Lsn, u64)>> {
+        let state = self.state.lock();
+        Ok(state
+            .blobs
+            .iter()
+            .map(|(lsn, blob)| (*lsn, blob.len() as u64))
+            .collect())
+    }
+
+    fn put_snapshot(&self, lsn: Lsn, data: &[u8]) -> io::Result<()> {
+        self.state.lock().snapshots.insert(lsn, data.to_vec());
+        Ok(())
+    }
+
+    fn get_snapshot(&self, lsn: Lsn) -> io::Result<Vec<u8>> {
+        let state = self.state.lock();
+        state
+            .snapshots
+            .get(&lsn)
+            .cloned()
+            .ok_or_else(|| not_found("snapshot", lsn))
+    }
+
+    fn delete_snapshot(&self, lsn: Lsn) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state
+            .snapshots
+            .remove(&lsn)
+            .map(|_| ())
+            .ok_or_else(|| not_found("snapshot", lsn))
+    }
+
+    fn list_snapshots(&self) -> io::Result<Vec<(Lsn, u64)>> {
+        let state = self.state.lock();
+        Ok(state
+            .snapshots
+            .iter()
+            .map(|(lsn, snapshot)| (*lsn, snapshot.len() as u64))
+            .collect())
+    }
+}
@@ -1,5 +1,3 @@
-use std::io::{Read, Write};
-
 #[cfg(feature = "zstd")]
 use zstd::block::{compress, decompress};
 
@@ -53,6 +51,23 @@ impl PageState {
 }
 
 impl Snapshot {
+    /// Apply the message logged at `lsn`, which must be past the
+    /// last one applied.
+    pub(super) fn advance(
+        &mut self,
+        log_kind: LogKind,
+        pid: PageId,
+        lsn: Lsn,
+        disk_ptr: DiskPtr,
+        sz: usize,
+    ) {
+        assert!(lsn > self.last_lsn);
+        self.last_lsn = lsn;
+        self.last_lid = disk_ptr.lid();
+
+        self.apply(log_kind, pid, lsn, disk_ptr, sz);
+    }
+
     fn apply(&mut self, log_kind: LogKind, pid: PageId, lsn: Lsn, disk_ptr: DiskPtr, sz: usize) {
         // unwrapping this because it's already passed the crc check
         // in the log iterator
@@ -102,9 +117,20 @@ impl Snapshot {
 }
 
 pub(super) fn advance_snapshot(
+    iter: LogIter,
+    snapshot: Snapshot,
+    config: &Config,
+) -> Result<Snapshot> {
+    advance_and_check(iter, snapshot, config, false)
+}
+
+/// Like `advance_snapshot`, but when `check` is set, it first makes
+/// sure the advanced snapshot only points at what is still stored.
+fn advance_and_check(
     iter: LogIter,
     mut snapshot: Snapshot,
     config: &Config,
+    check: bool,
 ) -> Result<Snapshot> {
     let _measure = Measure::new(&M.advance_snapshot);
 
@@ -131,14 +157,14 @@ pub(super) fn advance_snapshot(
             continue;
         }
 
-        assert!(lsn > snapshot.last_lsn);
-        snapshot.last_lsn = lsn;
-        snapshot.last_lid = ptr.lid();
+        snapshot.advance(log_kind, pid, lsn, ptr, sz);
+    }
 
-        snapshot.apply(log_kind, pid, lsn, ptr, sz);
+    if check {
+        check_intact(&snapshot, config)?;
     }
 
-    if snapshot.last_lsn != old_lsn {
+    if snapshot.last_lsn != old_lsn && !config.read_only {
         write_snapshot(config, &snapshot)?;
     }
 
@@ -150,46 +176,199 @@ pub(super) fn advance_snapshot(
 /// Read a `Snapshot` or generate a default, then advance it to
 /// the tip of the data file, if present.
 pub fn read_snapshot_or_default(config: &Config) -> Result<Snapshot> {
+    if let Some(target) = recovery_target(config)? {
+        return read_snapshot_at(target, config);
+    }
+
     log::info!("read snapshot");
-    let mut last_snap = read_snapshot(config)?.unwrap_or_else(Snapshot::default);
+    let (mut last_snap, fell_back) = read_snapshot(config)?.unwrap_or_default();
+
+    // an older snapshot is only used as long as nothing it points at
+    // was reused or removed since it was written
+    if fell_back {
+        check_intact(&last_snap, config)?;
+    }
 
     let (log_iter, max_header_stable_lsn) = raw_segment_iter_from(last_snap.last_lsn, config)?;
 
     last_snap.max_header_stable_lsn = max_header_stable_lsn;
 
-    advance_snapshot(log_iter, last_snap, config)
+    advance_and_check(log_iter, last_snap, config, fell_back)
 }
 
-/// Read a `Snapshot` from disk.
-fn read_snapshot(config: &Config) -> std::io::Result<Option<Snapshot>> {
-    let mut f = loop {
-        let mut candidates = config.get_snapshot_files()?;
-        if candidates.is_empty() {
-            debug!("no previous snapshot found");
-            return Ok(None);
+/// Makes sure that every segment `snapshot` points into still holds
+/// what it was written with, and that every blob it points at still
+/// exists. Segments and blobs are reused and removed without regard
+/// to older snapshots, so an older one may no longer be intact.
+fn check_intact(snapshot: &Snapshot, config: &Config) -> Result<()> {
+    let io_buf_size = config.io_buf_size as Lsn;
+    let blobs: FastSet8<Lsn> = config
+        .storage
+        .list_blobs()?
+        .into_iter()
+        .map(|(lsn, _)| lsn)
+        .collect();
+    let mut checked = FastSet8::default();
+
+    for state in snapshot.pt.values() {
+        for (lsn, ptr, _) in state.iter() {
+            let segment_lsn = lsn / io_buf_size * io_buf_size;
+            let segment_base = ptr.lid() / io_buf_size as LogId * io_buf_size as LogId;
+            let intact = (!ptr.is_blob() || blobs.contains(&ptr.blob().1))
+                && (!checked.insert(segment_base) || {
+                    let header = config.storage.read_segment_header(segment_base, config)?;
+                    header.ok && header.lsn == segment_lsn
+                });
+            if !intact {
+                error!(
+                    "an older snapshot was recovered from, but {} that it \
+                     or the log after it points at for lsn {} has been \
+                     overwritten or removed since",
+                    ptr, lsn
+                );
+                return Err(Error::Corruption { at: ptr });
+            }
         }
+    }
 
-        candidates.sort();
+    Ok(())
+}
+
+/// The Lsn that point-in-time recovery should stop at, if requested.
+fn recovery_target(config: &Config) -> Result<Option<Lsn>> {
+    if let Some(lsn) = config.recover_to_lsn {
+        return Ok(Some(lsn));
+    }
 
-        let path = candidates.pop().unwrap();
+    match config.recover_to_time {
+        None => Ok(None),
+        Some(ms) => match timeline::lsn_at(config, ms)? {
+            Some(lsn) => Ok(Some(lsn)),
+            None => Err(Error::Unsupported(format!(
+                "no history was recorded as early as {} ms",
+                ms
+            ))),
+        },
+    }
+}
+
+/// Replay the log from its start up to `target`, inclusive. Batches
+/// that were not complete by then are left out, and since the result
+/// is not the state of the tip, it is never written out as a snapshot.
+fn read_snapshot_at(target: Lsn, config: &Config) -> Result<Snapshot> {
+    log::info!("recover snapshot as of lsn {}", target);
+
+    match timeline::history_floor(config)? {
+        Some(floor) if target >= floor => {}
+        Some(floor) => {
+            return Err(Error::Unsupported(format!(
+                "the log only holds its history since lsn {}, \
+                 which is after lsn {}",
+                floor, target
+            )))
+        }
+        None => {
+            return Err(Error::Unsupported(format!(
+                "the log does not hold its history as of lsn {}, \
+                 it was not written with a history_window",
+                target
+            )))
+        }
+    }
+
+    let (mut log_iter, max_header_stable_lsn) = raw_segment_iter_from(0, config)?;
+    log_iter.max_lsn = std::cmp::min(log_iter.max_lsn, target);
+
+    let mut snapshot = Snapshot {
+        max_header_stable_lsn,
+        ..Snapshot::default()
+    };
+    for (log_kind, pid, lsn, ptr, sz) in log_iter {
+        snapshot.advance(log_kind, pid, lsn, ptr, sz);
+    }
+
+    if snapshot.pt.is_empty() {
+        return Err(Error::Unsupported(format!(
+            "the log no longer holds its history as of lsn {}, \
+             it may have been written without a history_window",
+            target
+        )));
+    }
+
+    Ok(snapshot)
+}
+
+/// What `Config::list_snapshots` knows about a snapshot file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotInfo {
+    /// The Lsn of the last message the snapshot includes.
+    pub lsn: Lsn,
+    /// The size of the snapshot file in bytes.
+    pub size: u64,
+    /// Whether the snapshot passes its crc check and can be decoded.
+    pub valid: bool,
+}
+
+/// The stored snapshots with their sizes, newest first.
+fn snapshot_files(config: &Config) -> std::io::Result<Vec<(Lsn, u64)>> {
+    let mut snapshots = config.storage.list_snapshots()?;
+    snapshots.sort_by_key(|(lsn, _)| std::cmp::Reverse(*lsn));
+    Ok(snapshots)
+}
+
+pub(super) fn list_snapshots(config: &Config) -> Result<Vec<SnapshotInfo>> {
+    let mut infos = vec![];
+
+    for (lsn, size) in snapshot_files(config)? {
+        let valid = match config.storage.get_snapshot(lsn) {
+            Ok(buf) => decode_snapshot(config, lsn, buf).is_some(),
+            // removed by a concurrent snapshot
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        infos.push(SnapshotInfo { lsn, size, valid });
+    }
 
-        match std::fs::OpenOptions::new().read(true).open(&path) {
-            Ok(f) => break f,
+    Ok(infos)
+}
+
+/// Read the newest valid `Snapshot` from storage, falling back to older
+/// ones if the newer ones are corrupt, along with whether it did so.
+fn read_snapshot(config: &Config) -> std::io::Result<Option<(Snapshot, bool)>> {
+    let snapshots = snapshot_files(config)?;
+    if snapshots.is_empty() {
+        debug!("no previous snapshot found");
+        return Ok(None);
+    }
+
+    for (i, (lsn, _)) in snapshots.into_iter().enumerate() {
+        match config.storage.get_snapshot(lsn) {
+            Ok(buf) => match decode_snapshot(config, lsn, buf) {
+                Some(snapshot) => return Ok(Some((snapshot, i > 0))),
+                None => warn!(
+                    "snapshot at lsn {} is corrupt, falling back to an older one",
+                    lsn
+                ),
+            },
             Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
                 // this can happen if there's a race
                 continue;
             }
             Err(other) => return Err(other),
         }
-    };
+    }
+
+    Ok(None)
+}
 
-    if f.metadata()?.len() <= 12 {
+/// Decode a stored snapshot, returning `None` if it is corrupt.
+fn decode_snapshot(config: &Config, snapshot_lsn: Lsn, mut buf: Vec<u8>) -> Option<Snapshot> {
+    if buf.len() <= 12 {
         warn!("empty/corrupt snapshot file found");
-        return Ok(None);
+        return None;
     }
 
-    let mut buf = vec![];
-    f.read_to_end(&mut buf)?;
     let len = buf.len();
     let mut len_expected_bytes = [0; 8];
     len_expected_bytes.copy_from_slice(&buf[len - 12..len - 4]);
@@ -197,19 +376,33 @@ fn read_snapshot(config: &Config) -> std::io::Result<Option<Snapshot>> {
     let mut crc_expected_bytes = [0; 4];
     crc_expected_bytes.copy_from_slice(&buf[len - 4..]);
 
-    buf.split_off(len - 12);
+    buf.truncate(len - 12);
     let crc_expected: u32 = arr_to_u32(&crc_expected_bytes);
 
     let crc_actual = crc32(&buf);
 
     if crc_expected != crc_actual {
-        return Ok(None);
+        return None;
     }
 
+    let buf = match &config.keys {
+        Some(keys) => match keys.open(Domain::Snapshot, snapshot_lsn, &[], buf) {
+            Ok(buf) => buf?,
+            Err(e) => {
+                warn!("cannot decrypt snapshot at lsn {}: {:?}", snapshot_lsn, e);
+                return None;
+            }
+        },
+        None => buf,
+    };
+
     #[cfg(feature = "zstd")]
     let bytes = if config.use_compression {
         let len_expected: u64 = arr_to_u64(&len_expected_bytes);
-        decompress(&*buf, len_expected as usize).unwrap()
+        match decompress(&*buf, len_expected as usize) {
+            Ok(bytes) => bytes,
+            Err(_) => return None,
+        }
     } else {
         buf
     };
@@ -217,7 +410,7 @@ fn read_snapshot(config: &Config) -> std::io::Result<Option<Snapshot>> {
     #[cfg(not(feature = "zstd"))]
     let bytes = buf;
 
-    Ok(deserialize::<Snapshot>(&*bytes).ok())
+    deserialize::<Snapshot>(&*bytes).ok()
 }
 
 fn write_snapshot(config: &Config, snapshot: &Snapshot) -> Result<()> {
@@ -234,60 +427,235 @@ fn write_snapshot(config: &Config, snapshot: &Snapshot) -> Result<()> {
     #[cfg(not(feature = "zstd"))]
     let bytes = raw_bytes;
 
+    let mut bytes = match &config.keys {
+        Some(keys) => keys.seal(Domain::Snapshot, snapshot.last_lsn, &[], &bytes)?,
+        None => bytes,
+    };
+
+    // the snapshot bytes are followed by their length and a crc32
     let crc32: [u8; 4] = u32_to_arr(crc32(&bytes));
     let len_bytes: [u8; 8] = u64_to_arr(decompressed_len as u64);
+    bytes.extend_from_slice(&len_bytes);
+    bytes.extend_from_slice(&crc32);
+
+    maybe_fail!("snap write");
+    config.storage.put_snapshot(snapshot.last_lsn, &bytes)?;
+    maybe_fail!("snap write post");
+
+    trace!("wrote snapshot at lsn {}", snapshot.last_lsn);
 
-    let path_1_suffix = format!("snap.{:016X}.generating", snapshot.last_lsn);
+    // clean up any old snapshots beyond the ones we retain
+    for (lsn, _) in snapshot_files(config)?
+        .into_iter()
+        .skip(config.snapshot_retention)
+    {
+        debug!("removing old snapshot at lsn {}", lsn);
 
-    let mut path_1 = config.snapshot_prefix();
-    path_1.push(path_1_suffix);
+        maybe_fail!("snap write rm old");
 
-    let path_2_suffix = format!("snap.{:016X}", snapshot.last_lsn);
+        if let Err(e) = config.storage.delete_snapshot(lsn) {
+            // TODO should this just be a try return?
+            warn!(
+                "failed to remove old snapshot file, maybe snapshot race? {}",
+                e
+            );
+        }
+    }
+    Ok(())
+}
 
-    let mut path_2 = config.snapshot_prefix();
-    path_2.push(path_2_suffix);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pool::block_on, prelude::Key};
+    use std::collections::BTreeMap;
 
-    let parent = path_1.parent().unwrap();
-    std::fs::create_dir_all(parent)?;
-    let mut f = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(&path_1)?;
+    type Page = BTreeMap<Key, Key>;
 
-    // write the snapshot bytes, followed by a crc64 checksum at the end
-    maybe_fail!("snap write");
-    f.write_all(&*bytes)?;
-    maybe_fail!("snap write len");
-    f.write_all(&len_bytes)?;
-    maybe_fail!("snap write crc");
-    f.write_all(&crc32)?;
-    maybe_fail!("snap write post");
+    fn page(key: u8) -> Page {
+        let mut page = BTreeMap::new();
+        page.insert(vec![key], vec![key; 8]);
+        page
+    }
 
-    trace!("wrote snapshot to {}", path_1.to_string_lossy());
+    #[test]
+    fn test_fall_back_to_older_snapshot() {
+        let path = std::env::temp_dir().join(format!("cloyster.snapshots.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = || {
+            ConfigBuilder::new()
+                .path(&path)
+                .snapshot_retention(2)
+                .build()
+        };
+
+        // every restart takes a snapshot of what was written before it
+        let mut pids = vec![];
+        for key in 0..3 {
+            let pc = PageCache::<Page>::start(config()).unwrap();
+            let guard = pin();
+            pids.push(pc.allocate(page(key), &guard).unwrap().0);
+            pc.flush().unwrap();
+        }
 
-    maybe_fail!("snap write mv");
-    std::fs::rename(&path_1, &path_2)?;
-    maybe_fail!("snap write mv post");
+        let listed = config();
+        let snapshots = listed.list_snapshots().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots[0].lsn > snapshots[1].lsn);
+        assert!(snapshots.iter().all(|s| s.valid && s.size > 12));
+
+        let newest = listed
+            .snapshot_prefix()
+            .join(format!("snap.{:016X}", snapshots[0].lsn));
+        let mut buf = std::fs::read(&newest).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        std::fs::write(&newest, buf).unwrap();
+
+        let snapshots = listed.list_snapshots().unwrap();
+        assert!(!snapshots[0].valid);
+        assert!(snapshots[1].valid);
+        drop(listed);
+
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        for (key, pid) in pids.into_iter().enumerate() {
+            let (_, value, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert_eq!(value, &page(key as u8));
+        }
+        drop(guard);
+        drop(pc);
 
-    trace!("renamed snapshot to {}", path_2.to_string_lossy());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
 
-    // clean up any old snapshots
-    let candidates = config.get_snapshot_files()?;
-    for path in candidates {
-        let path_str = path.file_name().unwrap().to_str().unwrap();
-        if !path_2.to_string_lossy().ends_with(&*path_str) {
-            debug!("removing old snapshot file {:?}", path);
+    #[test]
+    fn test_refuse_older_snapshot_that_is_not_intact() {
+        let path =
+            std::env::temp_dir().join(format!("cloyster.snapshots_intact.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = || {
+            ConfigBuilder::new()
+                .path(&path)
+                .snapshot_retention(2)
+                .build()
+        };
+
+        for key in 0..3 {
+            let pc = PageCache::<Page>::start(config()).unwrap();
+            let guard = pin();
+            pc.allocate(page(key), &guard).unwrap();
+            pc.flush().unwrap();
+        }
 
-            maybe_fail!("snap write rm old");
+        let listed = config();
+        let snapshots = listed.list_snapshots().unwrap();
+        let newest = listed
+            .snapshot_prefix()
+            .join(format!("snap.{:016X}", snapshots[0].lsn));
+        let mut buf = std::fs::read(&newest).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        std::fs::write(&newest, buf).unwrap();
+
+        // as if the first segment had been reused since
+        listed
+            .storage
+            .write_log(
+                &vec![MessageKind::Corrupted.into(); listed.seg_header_len()],
+                0,
+            )
+            .unwrap();
+        drop(listed);
+
+        match PageCache::<Page>::start(config()) {
+            Err(Error::Corruption { .. }) => {}
+            other => panic!("expected corruption, got {:?}", other.map(|_| ())),
+        }
 
-            if let Err(e) = std::fs::remove_file(&path) {
-                // TODO should this just be a try return?
-                warn!(
-                    "failed to remove old snapshot file, maybe snapshot race? {}",
-                    e
-                );
-            }
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_refuse_older_snapshot_pointing_at_removed_blob() {
+        let path =
+            std::env::temp_dir().join(format!("cloyster.snapshots_blob.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = || {
+            ConfigBuilder::new()
+                .path(&path)
+                .io_buf_size(64 * 1024)
+                .snapshot_retention(2)
+                .build()
+        };
+        let mut large = BTreeMap::new();
+        large.insert(vec![0], vec![0; 32 * 1024]);
+
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        let (pid, _) = pc.allocate(large, &guard).unwrap();
+        pc.flush().unwrap();
+        drop(guard);
+        drop(pc);
+        let blobs = config().storage.list_blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+
+        // the older snapshot points at the blob, the newer one no longer
+        let pc = PageCache::<Page>::start(config()).unwrap();
+        let guard = pin();
+        let (ptr, _, _) = pc.get(pid, &guard).unwrap().unwrap();
+        pc.replace(pid, ptr, page(1), &guard).unwrap().unwrap();
+        pc.flush().unwrap();
+        drop(guard);
+        drop(pc);
+        drop(PageCache::<Page>::start(config()).unwrap());
+
+        let listed = config();
+        let snapshots = listed.list_snapshots().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let newest = listed
+            .snapshot_prefix()
+            .join(format!("snap.{:016X}", snapshots[0].lsn));
+        let mut buf = std::fs::read(&newest).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xFF;
+        std::fs::write(&newest, buf).unwrap();
+
+        // as if the blob had been removed since, which the log after the
+        // older snapshot no longer points at
+        let _ = listed.storage.delete_blob(blobs[0].0);
+        drop(listed);
+
+        match PageCache::<Page>::start(config()) {
+            Err(Error::Corruption { .. }) => {}
+            other => panic!("expected corruption, got {:?}", other.map(|_| ())),
         }
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_now_and_flush_async() {
+        let path =
+            std::env::temp_dir().join(format!("cloyster.snapshot_now.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = ConfigBuilder::new().path(&path).build();
+
+        let pc = PageCache::<Page>::start(config.clone()).unwrap();
+        let guard = pin();
+        pc.allocate(page(1), &guard).unwrap();
+        assert!(block_on(pc.flush_async()).unwrap() > 0);
+        let stable = pc.stable_lsn();
+
+        let lsn = block_on(pc.snapshot_now()).unwrap();
+        assert!(lsn > 0 && lsn <= stable);
+        assert_eq!(config.list_snapshots().unwrap()[0].lsn, lsn);
+
+        pc.allocate(page(2), &guard).unwrap();
+        assert!(block_on(pc.snapshot_now()).unwrap() > lsn);
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
-    Ok(())
 }
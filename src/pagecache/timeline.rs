@@ -0,0 +1,400 @@
+//! A coarse mapping from wall-clock time to the stable Lsn, used to
+//! find where point-in-time recovery should stop for a given time.
+//!
+//! The timeline is an append-only file of fixed-size records holding a
+//! unix time in ms and the Lsn that was stable by then. It is only
+//! kept while `history_window` is set, which the config only allows
+//! with the default file storage, and writes that are stable
+//! within the same second may be resolved to an earlier one. Records
+//! that fall out of the history window are compacted away once they
+//! make up half of the file.
+//!
+//! Next to it, the history floor file holds the oldest Lsn that the
+//! log can still be recovered to, which rises whenever a segment or
+//! blob holding older history is reused or removed.
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::*;
+
+const RECORD_LEN: usize = 16;
+
+/// At most one record is written per interval.
+const RESOLUTION_MS: u64 = 1000;
+
+pub(super) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Timeline {
+    // opened for appending on the first record
+    file: Option<fs::File>,
+    // what the file holds, oldest first
+    records: VecDeque<(u64, Lsn)>,
+    last_ms: Option<u64>,
+    // the latest point not written out yet because it came too soon
+    // after the last record. it goes out ahead of the next record, so
+    // a quiet period after it does not hide it.
+    pending: Option<(u64, Lsn)>,
+}
+
+impl Timeline {
+    /// Note that `stable` has been written by now.
+    pub(super) fn record(&mut self, config: &Config, stable: Lsn) -> Result<()> {
+        let now = now_ms();
+        if let Some(last) = self.last_ms {
+            if now < last + RESOLUTION_MS {
+                self.pending = Some((now, stable));
+                return Ok(());
+            }
+        }
+        self.last_ms = Some(now);
+
+        if self.file.is_none() {
+            self.records = read_records(config)?.into();
+            // rewriting it drops a torn record a crash left at the end
+            self.compact(config, 0)?;
+        }
+
+        let new: Vec<_> = self
+            .pending
+            .take()
+            .into_iter()
+            .chain(Some((now, stable)))
+            .collect();
+        self.file.as_mut().unwrap().write_all(&encode(&new))?;
+        self.records.extend(new);
+
+        let window = config.history_window as Lsn;
+        let expired = self
+            .records
+            .iter()
+            .take_while(|(_, lsn)| lsn + window < stable)
+            .count();
+        if expired > 0 && expired * 2 >= self.records.len() {
+            self.compact(config, expired)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the file without its `expired` oldest records.
+    fn compact(&mut self, config: &Config, expired: usize) -> Result<()> {
+        trace!("dropping {} expired records from the timeline", expired);
+        self.records.drain(..expired);
+        let path = config.timeline_path();
+        let generating = path.with_extension("generating");
+        let mut f = fs::File::create(&generating)?;
+        f.write_all(&encode(self.records.make_contiguous()))?;
+        f.sync_all()?;
+        drop(f);
+        fs::rename(&generating, &path)?;
+
+        self.file = Some(fs::OpenOptions::new().append(true).open(path)?);
+        Ok(())
+    }
+}
+
+fn encode(records: &[(u64, Lsn)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(records.len() * RECORD_LEN);
+    for (ms, lsn) in records {
+        buf.extend_from_slice(&u64_to_arr(*ms));
+        buf.extend_from_slice(&u64_to_arr(*lsn as u64));
+    }
+    buf
+}
+
+fn read_records(config: &Config) -> Result<Vec<(u64, Lsn)>> {
+    let buf = match fs::read(config.timeline_path()) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    // a torn record at the end is ignored
+    Ok(buf
+        .chunks_exact(RECORD_LEN)
+        .map(|record| (arr_to_u64(&record[..8]), arr_to_u64(&record[8..]) as Lsn))
+        .collect())
+}
+
+/// The oldest Lsn that point-in-time recovery can reach, or `None`
+/// if history is not being kept.
+pub(super) fn history_floor(config: &Config) -> Result<Option<Lsn>> {
+    let buf = match fs::read(config.history_floor_path()) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if buf.len() != 12 || arr_to_u32(&buf[8..]) != crc32(&buf[..8]) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt history floor").into());
+    }
+    Ok(Some(arr_to_u64(&buf[..8]) as Lsn))
+}
+
+/// Durably records `floor` as the oldest Lsn that point-in-time
+/// recovery can reach. This must happen before the history below it
+/// is overwritten.
+pub(super) fn set_history_floor(config: &Config, floor: Lsn) -> Result<()> {
+    let mut buf = u64_to_arr(floor as u64).to_vec();
+    buf.extend_from_slice(&u32_to_arr(crc32(&buf)));
+
+    let path = config.history_floor_path();
+    let generating = path.with_extension("generating");
+    let mut f = fs::File::create(&generating)?;
+    f.write_all(&buf)?;
+    f.sync_all()?;
+    fs::rename(&generating, &path)?;
+    Ok(())
+}
+
+/// Forgets the history floor once history stops being kept, so that
+/// point-in-time recovery is refused from then on.
+pub(super) fn clear_history_floor(config: &Config) -> Result<()> {
+    match fs::remove_file(config.history_floor_path()) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => Ok(other?),
+    }
+}
+
+/// The last Lsn known to be stable at `ms`, or `None` if nothing was
+/// recorded that early.
+pub(super) fn lsn_at(config: &Config, ms: u64) -> Result<Option<Lsn>> {
+    Ok(read_records(config)?
+        .into_iter()
+        .take_while(|(at, _)| *at <= ms)
+        .map(|(_, lsn)| lsn)
+        .last())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::{collections::BTreeMap, sync::Arc, thread, time::Duration};
+
+    type Page = BTreeMap<Key, Key>;
+
+    fn page(key: &[u8], len: usize) -> Page {
+        let mut page = BTreeMap::new();
+        page.insert(key.to_vec(), vec![key[0]; len]);
+        page
+    }
+
+    fn value(pc: &PageCache<Page>, pid: PageId) -> Option<Page> {
+        let guard = pin();
+        pc.get(pid, &guard)
+            .unwrap()
+            .map(|(_, page, _)| page.clone())
+    }
+
+    fn rewrite(pc: &PageCache<Page>, pid: PageId, new: Page) {
+        let guard = pin();
+        let (ptr, _, _) = pc.get(pid, &guard).unwrap().unwrap();
+        pc.replace(pid, ptr, new, &guard).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_recover_to_lsn_and_time() {
+        let path = std::env::temp_dir().join(format!("cloyster.pitr.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let builder = ConfigBuilder::new()
+            .path(&path)
+            .io_buf_size(4096)
+            .history_window(1 << 30);
+
+        let pc = PageCache::<Page>::start(builder.clone().build()).unwrap();
+        let (small, large) = {
+            let guard = pin();
+            let (small, _) = pc.allocate(page(b"a", 8), &guard).unwrap();
+            let (large, _) = pc.allocate(page(b"a", 2048), &guard).unwrap();
+            (small, large)
+        };
+
+        // enough rewrites to free and reuse segments without a window
+        for i in 0..=255_u8 {
+            rewrite(&pc, small, page(&[b'b', i], 256));
+        }
+        rewrite(&pc, large, page(b"c", 2048));
+        pc.flush().unwrap();
+        let as_of_lsn = pc.stable_lsn();
+
+        thread::sleep(Duration::from_millis(1100));
+        let as_of_time = now_ms();
+        thread::sleep(Duration::from_millis(100));
+
+        for i in 0..=255_u8 {
+            rewrite(&pc, small, page(&[b'd', i], 256));
+        }
+        rewrite(&pc, large, page(b"e", 2048));
+        pc.flush().unwrap();
+        drop(pc);
+
+        let by_lsn = PageCache::<Page>::start(
+            builder
+                .clone()
+                .read_only(true)
+                .recover_to_lsn(Some(as_of_lsn))
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(value(&by_lsn, small), Some(page(&[b'b', 255], 256)));
+        assert_eq!(value(&by_lsn, large), Some(page(b"c", 2048)));
+        {
+            let guard = pin();
+            assert!(by_lsn.allocate(page(b"f", 8), &guard).is_err());
+        }
+        drop(by_lsn);
+
+        let by_time = PageCache::<Page>::start(
+            builder
+                .clone()
+                .read_only(true)
+                .recover_to_time(Some(as_of_time))
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(value(&by_time, small), Some(page(&[b'b', 255], 256)));
+        assert_eq!(value(&by_time, large), Some(page(b"c", 2048)));
+        drop(by_time);
+
+        // nothing about the tip was changed
+        let latest = PageCache::<Page>::start(builder.build()).unwrap();
+        assert_eq!(value(&latest, small), Some(page(&[b'd', 255], 256)));
+        assert_eq!(value(&latest, large), Some(page(b"e", 2048)));
+        drop(latest);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_timeline_compaction() {
+        let path = std::env::temp_dir().join(format!("cloyster.timeline.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let config = ConfigBuilder::new()
+            .path(&path)
+            .history_window(1000)
+            .build();
+
+        let old: Vec<_> = (0..10)
+            .map(|i| (i * RESOLUTION_MS, i as Lsn * 100))
+            .collect();
+        let mut buf = encode(&old);
+        buf.extend_from_slice(&[1, 2, 3]);
+        fs::write(config.timeline_path(), buf).unwrap();
+
+        let mut timeline = Timeline::default();
+        timeline.record(&config, 1800).unwrap();
+
+        // everything stable before 800 fell out of the window, and
+        // the torn record is gone
+        let records = read_records(&config).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], (8 * RESOLUTION_MS, 800));
+        assert_eq!(records[2].1, 1800);
+        assert_eq!(
+            fs::metadata(config.timeline_path()).unwrap().len(),
+            (3 * RECORD_LEN) as u64
+        );
+        assert_eq!(lsn_at(&config, 9 * RESOLUTION_MS).unwrap(), Some(900));
+        assert_eq!(lsn_at(&config, RESOLUTION_MS).unwrap(), None);
+
+        drop(config);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_recover_before_history_floor() {
+        let path = std::env::temp_dir().join(format!("cloyster.pitr_floor.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let builder = ConfigBuilder::new()
+            .path(&path)
+            .io_buf_size(4096)
+            .history_window(4 * 4096);
+
+        let pc = PageCache::<Page>::start(builder.clone().build()).unwrap();
+        let pid = {
+            let guard = pin();
+            pc.allocate(page(b"a", 8), &guard).unwrap().0
+        };
+        rewrite(&pc, pid, page(b"b", 256));
+        pc.flush().unwrap();
+        let early = pc.stable_lsn();
+
+        // far more than the window, so the segments holding `early`
+        // get reused
+        for _ in 0..4 {
+            for i in 0..=255_u8 {
+                rewrite(&pc, pid, page(&[b'c', i], 256));
+            }
+            pc.flush().unwrap();
+        }
+        let late = pc.stable_lsn();
+        drop(pc);
+
+        assert!(history_floor(&builder.clone().build()).unwrap().unwrap() > early);
+        let too_early = PageCache::<Page>::start(
+            builder
+                .clone()
+                .read_only(true)
+                .recover_to_lsn(Some(early))
+                .build(),
+        );
+        assert!(matches!(too_early, Err(Error::Unsupported(_))));
+
+        let in_window = PageCache::<Page>::start(
+            builder
+                .clone()
+                .read_only(true)
+                .recover_to_lsn(Some(late))
+                .build(),
+        )
+        .unwrap();
+        assert_eq!(value(&in_window, pid), Some(page(&[b'c', 255], 256)));
+        drop(in_window);
+
+        // once history stops being kept, nothing can be recovered
+        drop(PageCache::<Page>::start(builder.clone().history_window(0).build()).unwrap());
+        let without_window =
+            PageCache::<Page>::start(builder.read_only(true).recover_to_lsn(Some(late)).build());
+        assert!(matches!(without_window, Err(Error::Unsupported(_))));
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_history_only_in_file_storage() {
+        let path =
+            std::env::temp_dir().join(format!("cloyster.pitr_storage.{}", std::process::id()));
+        let storage = Arc::new(MemoryBackend::default());
+        for builder in [
+            ConfigBuilder::new().path(&path).storage(storage.clone()),
+            ConfigBuilder::new().path(&path).temporary(true),
+        ] {
+            match builder.clone().history_window(4096).try_build() {
+                Err(Error::Unsupported(_)) => {}
+                other => panic!("kept history outside the files: {:?}", other.map(|_| ())),
+            }
+            match builder
+                .clone()
+                .read_only(true)
+                .recover_to_lsn(Some(0))
+                .try_build()
+            {
+                Err(Error::Unsupported(_)) => {}
+                other => panic!("recovered outside the files: {:?}", other.map(|_| ())),
+            }
+            drop(PageCache::<Page>::start(builder.build()).unwrap());
+        }
+        assert!(!path.exists());
+    }
+}
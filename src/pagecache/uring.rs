@@ -0,0 +1,347 @@
+//! Writing the log through io_uring.
+use std::{
+    collections::HashMap,
+    io,
+    os::unix::io::RawFd,
+    sync::{Arc, Weak},
+    thread,
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+use parking_lot::Mutex;
+
+use super::*;
+
+// two entries per IO buffer, for the write and its fsync with
+// `sync_every_write`
+const QUEUE_DEPTH: u32 = 64;
+
+// user_data of the no-op telling the completion thread to exit
+const SHUTDOWN: u64 = u64::MAX;
+
+#[derive(Debug)]
+struct InFlight {
+    iobuf: Arc<IoBuf>,
+    len: usize,
+    written: Option<i32>,
+    synced: Option<i32>,
+}
+
+struct Ring {
+    ring: IoUring,
+    fd: RawFd,
+    // only one thread may push to the submission queue at a time
+    submission: Mutex<()>,
+    // IO buffers the kernel is writing out, by their id. They are kept
+    // alive here until it is done with them.
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+    next_id: AtomicU64,
+    // held by the completion thread while it holds on to the IoBufs
+    finishing: Mutex<()>,
+}
+
+/// Submits sealed IO buffers to an io_uring: each one is written, with
+/// a linked fdatasync if `sync_every_write` is set, and as many as
+/// `QUEUE_DEPTH / 2` of them may be in flight at once. A completion
+/// thread finishes the writes off by marking their intervals written,
+/// in whatever order the kernel completes them.
+pub(crate) struct Uring {
+    ring: Arc<Ring>,
+    sync_every_write: bool,
+    completer: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Debug for Uring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Uring")
+            .field("fd", &self.ring.fd)
+            .field("in_flight", &self.ring.in_flight.lock().len())
+            .finish()
+    }
+}
+
+impl Uring {
+    /// A ring writing to the log of `config`, or `None` if its storage
+    /// can't be written through one, or the kernel won't give us one.
+    pub(crate) fn new(config: &Config) -> Option<Uring> {
+        let fd = config.storage.log_fd()?;
+
+        let ring = match IoUring::new(QUEUE_DEPTH) {
+            Ok(ring) => ring,
+            Err(e) => {
+                warn!(
+                    "io_uring is not available, writing the log with pwrite: {}",
+                    e
+                );
+                return None;
+            }
+        };
+
+        Some(Uring {
+            ring: Arc::new(Ring {
+                ring,
+                fd,
+                submission: Mutex::new(()),
+                in_flight: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                finishing: Mutex::new(()),
+            }),
+            sync_every_write: config.sync_every_write,
+            completer: Mutex::new(None),
+        })
+    }
+
+    /// Spawns the completion thread. It only holds on to `iobufs`
+    /// weakly, so it doesn't keep them alive.
+    pub(crate) fn start(&self, iobufs: Weak<IoBufs>) -> Result<()> {
+        let ring = self.ring.clone();
+        let completer = thread::Builder::new()
+            .name("cloyster-uring".to_owned())
+            .spawn(move || complete(&ring, &iobufs))?;
+        *self.completer.lock() = Some(completer);
+        Ok(())
+    }
+
+    /// Queues the first `len` bytes of `iobuf` to be written at its
+    /// `lid`. Returns once they are submitted, the rest of
+    /// `write_to_log` happens on the completion thread.
+    pub(crate) fn submit(&self, iobuf: &Arc<IoBuf>, len: usize) -> Result<()> {
+        let id = self.ring.next_id.fetch_add(1, SeqCst);
+        let buf = unsafe { (*iobuf.buf.get()).as_ptr() };
+        let fd = types::Fd(self.ring.fd);
+
+        let write = opcode::Write::new(fd, buf, u32::try_from(len).unwrap())
+            .offset(iobuf.lid)
+            .build()
+            .user_data(id << 1);
+        let sync = self.sync_every_write;
+
+        let entries = if sync {
+            vec![
+                write.flags(squeue::Flags::IO_LINK),
+                opcode::Fsync::new(fd)
+                    .flags(types::FsyncFlags::DATASYNC)
+                    .build()
+                    .user_data(id << 1 | 1),
+            ]
+        } else {
+            vec![write]
+        };
+
+        self.ring.in_flight.lock().insert(
+            id,
+            InFlight {
+                iobuf: iobuf.clone(),
+                len,
+                written: None,
+                // nothing to wait for if the log is synced later on
+                synced: if sync { None } else { Some(0) },
+            },
+        );
+
+        self.ring.push(&entries)
+    }
+
+    /// Waits for every write submitted so far to be finished off, and
+    /// for the completion thread to let go of the IoBufs. Otherwise the
+    /// last reference to them, and the file lock held by their config,
+    /// may be dropped on that thread after the log is closed.
+    pub(crate) fn quiesce(&self) {
+        loop {
+            let _finishing = self.ring.finishing.lock();
+            if self.ring.in_flight.lock().is_empty() {
+                return;
+            }
+            drop(_finishing);
+            thread::yield_now();
+        }
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        let nop = opcode::Nop::new().build().user_data(SHUTDOWN);
+        if let Err(e) = self.ring.push(&[nop]) {
+            error!("failed to stop the io_uring completion thread: {:?}", e);
+            return;
+        }
+
+        if let Some(completer) = self.completer.lock().take() {
+            // the completion thread may be the one dropping the last
+            // reference to the IoBufs, it exits by itself after this
+            if completer.thread().id() == thread::current().id() {
+                return;
+            }
+            if completer.join().is_err() {
+                error!("error joining the io_uring completion thread");
+            }
+        }
+    }
+}
+
+impl Ring {
+    fn push(&self, entries: &[squeue::Entry]) -> Result<()> {
+        let _submission = self.submission.lock();
+        loop {
+            // the entries point into IO buffers kept alive by in_flight,
+            // and linked ones are pushed together
+            let pushed = unsafe { self.ring.submission_shared().push_multiple(entries) };
+            if pushed.is_ok() {
+                break;
+            }
+            // full, get the kernel to take what is queued
+            self.ring.submit()?;
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+}
+
+fn complete(ring: &Ring, iobufs: &Weak<IoBufs>) {
+    let mut shutdown = false;
+
+    while !shutdown || !ring.in_flight.lock().is_empty() {
+        if let Err(e) = ring.ring.submit_and_wait(1) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("io_uring completion thread failed: {:?}", e);
+            if let Some(iobufs) = iobufs.upgrade() {
+                fail(&iobufs, e.into());
+            }
+            return;
+        }
+
+        let completions: Vec<(u64, i32)> = unsafe { ring.ring.completion_shared() }
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+
+        for (user_data, result) in completions {
+            if user_data == SHUTDOWN {
+                shutdown = true;
+                continue;
+            }
+
+            let id = user_data >> 1;
+            let _finishing = ring.finishing.lock();
+            let done = {
+                let mut in_flight = ring.in_flight.lock();
+                let write = in_flight.get_mut(&id).expect("unknown io_uring completion");
+                if user_data & 1 == 0 {
+                    write.written = Some(result);
+                } else {
+                    write.synced = Some(result);
+                }
+                if write.written.is_some() && write.synced.is_some() {
+                    in_flight.remove(&id)
+                } else {
+                    None
+                }
+            };
+
+            // nobody is waiting for anything anymore once the IoBufs
+            // are gone, the buffer just has to outlive the write
+            if let (Some(write), Some(iobufs)) = (done, iobufs.upgrade()) {
+                if let Err(e) = finish(&iobufs, &write) {
+                    error!(
+                        "hit error while writing iobuf with lsn {}: {:?}",
+                        write.iobuf.lsn, e
+                    );
+                    fail(&iobufs, e);
+                }
+            }
+        }
+    }
+}
+
+fn finish(iobufs: &IoBufs, write: &InFlight) -> Result<()> {
+    let written = check(write.written)?;
+    if written < write.len {
+        // a short write breaks the link, so any fsync was cancelled.
+        // write the rest the slow way.
+        let data = unsafe { &(*write.iobuf.buf.get()).as_slice()[written..write.len] };
+        let f = &iobufs.config.storage;
+        f.write_log(data, write.iobuf.lid + written as LogId)?;
+        if iobufs.config.sync_every_write {
+            f.sync_log()?;
+        }
+    } else {
+        check(write.synced)?;
+    }
+
+    iobufs.finish_write(&write.iobuf, write.len)
+}
+
+fn check(result: Option<i32>) -> Result<usize> {
+    match result {
+        Some(res) if res >= 0 => Ok(assert_usize(res)),
+        Some(res) => Err(io::Error::from_raw_os_error(-res).into()),
+        None => unreachable!(),
+    }
+}
+
+fn fail(iobufs: &IoBufs, e: Error) {
+    // set the error first, so that whoever is woken sees it
+    iobufs.config.set_global_error(e);
+    let _lock = iobufs.intervals.lock();
+    iobufs.notify_interval_updated();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::Key;
+    use std::collections::BTreeMap;
+
+    type Page = BTreeMap<Key, Key>;
+
+    #[test]
+    fn test_uring_log() {
+        let path = std::env::temp_dir().join(format!("cloyster.uring.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let config = ConfigBuilder::new().path(&path).io_buf_size(4096).build();
+
+        // file storage goes through the ring wherever the kernel has one
+        if IoUring::new(2).is_ok() {
+            assert!(Uring::new(&config).is_some());
+        }
+
+        // small buffers, so that plenty are in flight and roll over
+        // into new segments
+        let pc = Arc::new(PageCache::<Page>::start(config.clone()).unwrap());
+        let threads: Vec<_> = (0..4u8)
+            .map(|i| {
+                let pc = pc.clone();
+                thread::spawn(move || {
+                    let mut pids = vec![];
+                    for j in 0..64u8 {
+                        let guard = pin();
+                        let mut page = BTreeMap::new();
+                        page.insert(vec![i, j], vec![j; 100]);
+                        let (pid, _) = pc.allocate(page, &guard).unwrap();
+                        pids.push((pid, vec![i, j]));
+                    }
+                    pids
+                })
+            })
+            .collect();
+
+        let pids: Vec<_> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        pc.flush().unwrap();
+        drop(pc);
+
+        let pc = PageCache::<Page>::start(config).unwrap();
+        let guard = pin();
+        for (pid, key) in pids {
+            let (_, page, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert_eq!(page.get(&key), Some(&vec![key[1]; 100]));
+        }
+        drop(guard);
+        drop(pc);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
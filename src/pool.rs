@@ -0,0 +1,53 @@
+//! Running blocking work off an async executor's threads.
+#[cfg(feature = "async")]
+use crate::sync::*;
+#[cfg(feature = "async")]
+use abyss_promise::Promise;
+
+/// Run `work` on the IO thread pool, resolving to what it returns.
+#[cfg(feature = "async")]
+pub(crate) async fn spawn_blocking<F, T>(work: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // promises hand out clones of their result, which ours may not
+    // support, so it is passed back on the side.
+    let slot = Arc::new(Mutex::new(None));
+    let promise = Promise::new({
+        let slot = slot.clone();
+        move || *slot.lock() = Some(work())
+    });
+
+    promise.await;
+    let result = slot.lock().take();
+    result.expect("blocking task panicked on the IO thread pool")
+}
+
+/// Just enough of an executor to drive a future to completion.
+#[cfg(test)]
+pub(crate) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::{
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        thread::{self, Thread},
+    };
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Arc::new(Unpark(thread::current())).into();
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -1,6 +1,13 @@
 #![allow(unused)]
 use crate::{
-    atomic::*, config::*, context::Context, iter::*, node::Node, pagecache::PageId, prelude::*,
+    atomic::*,
+    committed::CommittedBlock,
+    config::*,
+    context::Context,
+    iter::*,
+    node::Node,
+    pagecache::{Lsn, PageId},
+    prelude::*,
     sync::*,
 };
 use binary_heap_plus::{BinaryHeap, MinComparator};
@@ -17,8 +24,9 @@ pub struct TreeBlock {
 
     pub(crate) context: Context,
 
-    /// Table ID
-    pub(crate) id: PageId,
+    /// Table ID, shared between clones so that a deduplicated commit
+    /// redirects every handle onto the surviving page
+    pub(crate) id: Arc<AtomicU64>,
 
     /// Cookie for insertion
     pub(crate) cookie: Arc<RwLock<BTreeMap<Key, Entry>>>,
@@ -27,6 +35,41 @@ pub struct TreeBlock {
     // TODO: Bloom Filter facility
 }
 
+/// The outcome of `TreeBlock::commit`, dereferences to the block hash
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Commit {
+    /// The block is stored as a new page
+    Created(Hash),
+    /// A block with identical content was already committed, the block has
+    /// been merged onto it. The existing block is made as durable as the
+    /// commit asked for, even if whoever created it has not flushed it yet
+    Existed(Hash),
+}
+
+impl Commit {
+    /// Hash of the committed block
+    pub fn hash(&self) -> Hash {
+        match self {
+            Commit::Created(hash) | Commit::Existed(hash) => *hash,
+        }
+    }
+
+    /// Whether this commit stored a new block
+    pub fn is_created(&self) -> bool {
+        matches!(self, Commit::Created(_))
+    }
+}
+
+impl ops::Deref for Commit {
+    type Target = Hash;
+
+    fn deref(&self) -> &Hash {
+        match self {
+            Commit::Created(hash) | Commit::Existed(hash) => hash,
+        }
+    }
+}
+
 impl TreeBlock {
     /// New TreeBlock
     pub fn new<'a>(
@@ -44,30 +87,24 @@ impl TreeBlock {
             context,
             hash,
             cookie,
-            id,
+            id: Arc::new(AtomicU64::new(id)),
         })
     }
 
+    /// The page id this block is stored in
+    pub(crate) fn id(&self) -> PageId {
+        self.id.load(Acquire)
+    }
+
     /// The preceded block
-    pub fn prev(&self) -> DBResult<Self> {
+    pub fn prev(&self) -> DBResult<CommittedBlock> {
         let guard = pin();
-        let context = self.context.clone();
-        let page = self.context.get(self.id, &guard)?;
-
-        Ok(page
-            .map(|(_, node, _)| {
-                let h = node.hash.as_ref().map(|raw| raw.clone().into());
-                let hash = Arc::new(RwLock::new(h));
-                let cookie = Arc::new(RwLock::new(BTreeMap::new()));
-
-                node.prev.map(|id| Self {
-                    context,
-                    hash,
-                    cookie,
-                    id,
-                })
-            })
-            .flatten())
+        let page = self.context.get(self.id(), &guard)?;
+
+        match page.and_then(|(_, node, _)| node.prev) {
+            Some(prev) => CommittedBlock::open(self.context.clone(), prev, &guard),
+            None => Ok(None),
+        }
     }
 
     /// Get value
@@ -75,6 +112,20 @@ impl TreeBlock {
         self.get_inner(key.as_ref(), &mut pin())
     }
 
+    /// Like `get`, but the lookup is done on the IO thread pool, as it
+    /// may have to read from disk. This is so even when everything it
+    /// needs is already cached.
+    #[cfg(feature = "async")]
+    pub fn get_async(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> impl std::future::Future<Output = DBResult<Value>> {
+        let block = self.clone();
+        let key = key.as_ref().to_vec();
+
+        crate::pool::spawn_blocking(move || block.get(key))
+    }
+
     pub(crate) fn get_inner(&self, key: &[u8], guard: &mut Guard) -> DBResult<Value> {
         // #1. read from cookie
         {
@@ -89,26 +140,7 @@ impl TreeBlock {
         // TODO: lru & bloom-filter
 
         // #2. lookup through the chined page-id
-        let id = self.id;
-        let (_, mut node, _) = self.context.get(id, guard)?.unwrap();
-        match node.inner.get(key) {
-            Some(Entry::Value { value }) => return Ok(Some(value.clone())),
-            Some(Entry::Deletion) => return Ok(None),
-            None => {}
-        }
-
-        while let Some(prev) = node.prev {
-            let (_, prev_node, _) = self.context.get(prev, guard)?.unwrap();
-            match prev_node.inner.get(key) {
-                Some(Entry::Value { value }) => return Ok(Some(value.clone())),
-                Some(Entry::Deletion) => return Ok(None),
-                None => {}
-            }
-
-            node = prev_node;
-        }
-
-        Ok(None)
+        lookup(&self.context, self.id(), key, guard)
     }
 
     /// Hash code of current state or the stablized hash
@@ -116,7 +148,7 @@ impl TreeBlock {
         if let Some(hash) = *self.hash.read() {
             return Ok(hash);
         } else {
-            let (mut key, node, _) = self.context.get(self.id, guard).unwrap().unwrap();
+            let (mut key, node, _) = self.context.get(self.id(), guard).unwrap().unwrap();
 
             let prev = if let Some(prev) = node.prev {
                 let (mut key, node, _) = self.context.get(prev, guard).unwrap().unwrap();
@@ -130,12 +162,9 @@ impl TreeBlock {
             let cookie = self.cookie.read();
             let hash = crate::hasher::calc_root(
                 prev,
-                cookie.iter().filter_map(|(k, v)| {
-                    if let Entry::Value { value } = v {
-                        Some((k, value))
-                    } else {
-                        None
-                    }
+                cookie.iter().map(|(k, v)| match v {
+                    Entry::Value { value } => (k, Some(value)),
+                    Entry::Deletion => (k, None),
                 }),
             );
             return Ok(hash);
@@ -143,10 +172,63 @@ impl TreeBlock {
     }
 
     /// Commit this TreeBlock
-    pub fn commit(mut self) -> IResult<Hash> {
+    ///
+    /// Blocks are addressed by their hash, so committing content which has
+    /// already been committed by another block does not create a second
+    /// copy: this block is merged onto the existing page and its own page is
+    /// freed. The returned `Commit` tells which of the two happened.
+    pub fn commit(self) -> IResult<Commit> {
+        self.commit_with(Durability::Synced)
+    }
+
+    /// Like `commit`, but only waits for the new block to reach
+    /// `durability`, e.g. bulk loads which can redo their work after a
+    /// crash may get away with `Durability::None`.
+    pub fn commit_with(self, durability: Durability) -> IResult<Commit> {
+        let context = self.context.clone();
+        let (commit, lsn) = self.commit_unflushed()?;
+
+        // only wait for this block's own writes, concurrent synced
+        // commits are batched into a single flush by the pagecache
+        if let Some(lsn) = lsn {
+            context.make_durable(lsn, durability)?;
+        }
+
+        Ok(commit)
+    }
+
+    /// Like `commit`, but the work is done on the IO thread pool and
+    /// the returned future is woken once the new block is on disk.
+    #[cfg(feature = "async")]
+    pub fn commit_async(self) -> impl std::future::Future<Output = IResult<Commit>> {
+        let context = self.context.clone();
+
+        async move {
+            let (commit, lsn) =
+                crate::pool::spawn_blocking(move || self.commit_unflushed()).await?;
+
+            if let Some(lsn) = lsn {
+                context.make_stable_async(lsn).await?;
+            }
+
+            Ok(commit)
+        }
+    }
+
+    /// Everything `commit` does short of flushing the new block, which
+    /// is done once the returned Lsn is stable.
+    fn commit_unflushed(mut self) -> IResult<(Commit, Option<Lsn>)> {
         let guard = pin();
+        // the existing block may itself still be waiting to be flushed,
+        // it is mapped by the meta page after being written, so making
+        // the meta page durable up to its head covers it
+        let existed = |hash| -> IResult<(Commit, Option<Lsn>)> {
+            let lsn = self.context.get_meta(&guard)?.0.last_lsn();
+            Ok((Commit::Existed(hash), Some(lsn)))
+        };
+
         if let Some(hash) = *self.hash.read() {
-            return Ok(hash);
+            return existed(hash);
         }
 
         let mut hash_rwl = self.hash.write();
@@ -154,51 +236,101 @@ impl TreeBlock {
 
         let inner = std::mem::replace(&mut *cookie, BTreeMap::new());
 
-        let id = self.id;
-        let hash = if let Some((mut key, node, _)) = self.context.get(id, &guard)? {
-            let mut node = Node::new(node.prev);
-
-            let prev_hash = node
-                .prev
-                .map(|prev| {
-                    let (mut key, node, _) = self.context.get(prev, &guard).unwrap().unwrap();
+        let id = self.id();
+        let (key, node) = if let Some((key, node, _)) = self.context.get(id, &guard)? {
+            (key, node)
+        } else {
+            panic!("pid {} should exist in stable storage.", id);
+        };
 
-                    // should not panic
-                    node.hash.as_ref().map(|raw| raw.clone().into())
-                })
-                .flatten();
+        let mut node = Node::new(node.prev);
 
-            let hash = crate::hasher::calc_root(
-                prev_hash,
-                inner.iter().filter_map(|(k, v)| {
-                    if let Entry::Value { value } = v {
-                        Some((k, value))
-                    } else {
-                        None
-                    }
-                }),
-            );
+        let prev_hash = node
+            .prev
+            .map(|prev| {
+                let (mut key, node, _) = self.context.get(prev, &guard).unwrap().unwrap();
 
-            node.hash.replace(hash.as_bytes().clone());
-            node.inner = inner;
+                // should not panic
+                node.hash.as_ref().map(|raw| raw.clone().into())
+            })
+            .flatten();
+
+        let hash = crate::hasher::calc_root(
+            prev_hash,
+            inner.iter().map(|(k, v)| match v {
+                Entry::Value { value } => (k, Some(value)),
+                Entry::Deletion => (k, None),
+            }),
+        );
 
-            // stablize the changes
-            self.context.link(id, key, node, &guard)?;
-            hash
-        } else {
-            panic!("pid {} should exist in stable storage.", id);
-        };
+        // identical content has been committed before, no need to write it
+        if let Some(existing) = self.context.meta(&guard)?.get_block(hash.as_bytes()) {
+            self.dedupe_onto(existing, &guard)?;
+            hash_rwl.replace(hash);
+            return existed(hash);
+        }
 
-        hash_rwl.replace(hash);
+        node.hash.replace(hash.as_bytes().clone());
+        node.inner = inner;
+
+        // stablize the changes
+        if self.context.link(id, key, node, &guard)?.is_err() {
+            return Err(Error::PCError(crate::pagecache::Error::ReportableBug(
+                format!(
+                    "uncommitted block pid {} has been modified concurrently",
+                    id
+                ),
+            )));
+        }
 
         // update meta-page
         // NB: maybe fairly slow
-        self.context
-            .cas_block_in_meta(hash.as_bytes(), None, Some(id), &guard)?;
+        let created =
+            match self
+                .context
+                .cas_block_in_meta(hash.as_bytes(), None, Some(id), &guard)?
+            {
+                Ok(()) => true,
+                // lost the race against another block with the same content
+                Err(Some(existing)) => {
+                    self.dedupe_onto(existing, &guard)?;
+                    false
+                }
+                Err(None) => {
+                    return Err(Error::PCError(crate::pagecache::Error::ReportableBug(
+                        "block hash vanished from the meta page while committing".into(),
+                    )))
+                }
+            };
 
-        self.context.flush();
+        hash_rwl.replace(hash);
 
-        Ok(hash)
+        if created {
+            // the meta page is written after the block itself, so its
+            // head covers both
+            let lsn = self.context.get_meta(&guard)?.0.last_lsn();
+            Ok((Commit::Created(hash), Some(lsn)))
+        } else {
+            existed(hash)
+        }
+    }
+
+    /// Point this block at `existing` and free the page it was using.
+    fn dedupe_onto(&self, existing: PageId, guard: &Guard) -> IResult<()> {
+        let duplicate = self.id.swap(existing, AcqRel);
+        if duplicate == existing {
+            return Ok(());
+        }
+
+        if let Some((ptr, _, _)) = self.context.get(duplicate, guard)? {
+            if self.context.free(duplicate, ptr, guard)?.is_err() {
+                return Err(Error::PCError(crate::pagecache::Error::ReportableBug(
+                    format!("failed to free the duplicated block pid {}", duplicate),
+                )));
+            }
+        }
+
+        Ok(())
     }
 
     pub fn commited(&self) -> bool {
@@ -250,8 +382,36 @@ impl TreeBlock {
     where
         R: RangeBounds<Key>,
     {
-        Iter::new(self.clone(), range)
+        Iter::new(
+            self.context.clone(),
+            self.id(),
+            Some(self.cookie.clone()),
+            range,
+        )
+    }
+}
+
+/// Lookup a key through the chain of blocks starting from page `id`
+pub(crate) fn lookup(context: &Context, id: PageId, key: &[u8], guard: &Guard) -> DBResult<Value> {
+    let (_, mut node, _) = context.get(id, guard)?.unwrap();
+    match node.inner.get(key) {
+        Some(Entry::Value { value }) => return Ok(Some(value.clone())),
+        Some(Entry::Deletion) => return Ok(None),
+        None => {}
+    }
+
+    while let Some(prev) = node.prev {
+        let (_, prev_node, _) = context.get(prev, guard)?.unwrap();
+        match prev_node.inner.get(key) {
+            Some(Entry::Value { value }) => return Ok(Some(value.clone())),
+            Some(Entry::Deletion) => return Ok(None),
+            None => {}
+        }
+
+        node = prev_node;
     }
+
+    Ok(None)
 }
 
 #[cfg(test)]
@@ -265,7 +425,7 @@ mod tests {
         let db = Database::default();
 
         let block = db.genesis().unwrap();
-        let hash = block.commit().unwrap();
+        let hash = block.commit().unwrap().hash();
         println!("[test] db path: {:?}, genesis: {:?}", db.path(), hash);
 
         (db, hash)
@@ -274,15 +434,23 @@ mod tests {
     #[cfg(not(loom))]
     #[test]
     fn test_block_persistence() {
-        let db = Database::open("./cloyster.db".into()).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("cloyster.persistence.{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        let db = Database::open(path.clone()).unwrap();
         let block = db.genesis().unwrap();
         block.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
         let ref hash = block.commit().unwrap();
         drop(db);
 
-        let db = Database::open("./cloyster.db".into()).unwrap();
+        let db = Database::open(path.clone()).unwrap();
         let block = db.open_block(hash).unwrap().unwrap();
         assert_eq!(block.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+        drop(block);
+        drop(db);
+
+        std::fs::remove_dir_all(&path).unwrap();
     }
 
     #[cfg(not(loom))]
@@ -292,22 +460,237 @@ mod tests {
         let block = db.open_block(hash).unwrap();
 
         assert!(block.is_some(), "open by id should be ok.");
-        let block = block.unwrap();
+        let block = block.unwrap().fork().unwrap();
 
         block.insert(b"210".to_vec(), b"2".to_vec()).unwrap();
         block.insert(b"220".to_vec(), b"2".to_vec()).unwrap();
         block.insert(b"100".to_vec(), b"1".to_vec()).unwrap();
 
         let hash = block.commit().unwrap();
-        let block = db.open_block(&hash).unwrap().unwrap();
+        let block = db.open_block(&hash).unwrap().unwrap().fork().unwrap();
         block.commit().unwrap();
     }
 
+    #[cfg(not(loom))]
+    #[test]
+    fn test_committed_block() {
+        let (db, hash) = &*INIT;
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
+
+        block.insert(b"c1".to_vec(), b"1".to_vec()).unwrap();
+        block.insert(b"c2".to_vec(), b"2".to_vec()).unwrap();
+        let commit = block.commit().unwrap();
+
+        let committed = db.open_block(&commit).unwrap().unwrap();
+        assert_eq!(committed.hash(), commit.hash());
+        assert_eq!(committed.get(b"c1").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(committed.prev().unwrap().unwrap().hash(), *hash);
+
+        let proof = committed.proof().unwrap();
+        assert!(committed.verify().unwrap());
+        assert!(proof.verify(&commit));
+        assert_eq!(proof.get(b"c2"), Some(&b"2".to_vec()));
+
+        let child = committed.fork().unwrap();
+        child.delete(b"c1".to_vec()).unwrap();
+        assert_eq!(child.get(b"c1").unwrap(), None);
+        assert_eq!(committed.get(b"c1").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(committed.iter().count(), 2);
+        assert_eq!(child.iter().count(), 1);
+
+        // deletions are part of the proof
+        let commit = child.commit().unwrap();
+        let proof = db.open_block(&commit).unwrap().unwrap().proof().unwrap();
+        assert!(proof.verify(&commit));
+        assert_eq!(proof.entries, vec![(b"c1".to_vec(), None)]);
+        assert_eq!(proof.get(b"c1"), None);
+    }
+
+    #[cfg(all(feature = "async", not(loom)))]
+    #[test]
+    fn test_block_async() {
+        use crate::pool::block_on;
+
+        // the futures must be able to move between executor threads
+        fn send<F: Send>(future: F) -> F {
+            future
+        }
+
+        let (db, hash) = &*INIT;
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
+        block.insert(b"async".to_vec(), b"1".to_vec()).unwrap();
+        assert_eq!(
+            block_on(send(block.get_async(b"async"))).unwrap(),
+            Some(b"1".to_vec())
+        );
+
+        let commit = block_on(send(block.commit_async())).unwrap();
+        assert!(commit.is_created());
+
+        let child = db.open_block(&commit).unwrap().unwrap().fork().unwrap();
+        assert_eq!(
+            block_on(send(child.get_async(b"async"))).unwrap(),
+            Some(b"1".to_vec())
+        );
+        block_on(send(db.flush_async())).unwrap();
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_block_commit_dedupe() {
+        let (db, hash) = &*INIT;
+
+        let first = db.open_block(hash).unwrap().unwrap().fork().unwrap();
+        first.insert(b"dedupe".to_vec(), b"1".to_vec()).unwrap();
+
+        let second = db.open_block(hash).unwrap().unwrap().fork().unwrap();
+        second.insert(b"dedupe".to_vec(), b"1".to_vec()).unwrap();
+
+        let first_id = first.id();
+        let second_id = second.id();
+        let second_handle = second.clone();
+
+        let created = first.commit().unwrap();
+        assert!(created.is_created());
+
+        let existed = second.commit().unwrap();
+        assert_eq!(existed, Commit::Existed(created.hash()));
+
+        // the duplicated page is freed and the remaining handle follows
+        let guard = pin();
+        assert!(db.context.get(second_id, &guard).unwrap().is_none());
+        assert_eq!(second_handle.id(), first_id);
+        assert_eq!(second_handle.get(b"dedupe").unwrap(), Some(b"1".to_vec()));
+
+        let meta = db.context.meta(&guard).unwrap();
+        assert_eq!(meta.get_block(created.as_bytes()), Some(first_id));
+        drop(guard);
+
+        // deletions and the split between keys and values are part of
+        // the content, such blocks must not be deduplicated
+        let mut hashes = vec![];
+        for changes in &[
+            &[(&b"dedupe.ab"[..], Some(&b"c"[..]))][..],
+            &[(&b"dedupe.a"[..], Some(&b"bc"[..]))][..],
+            &[(&b"dedupe.a"[..], Some(&b"bc"[..])), (b"dedupe.b", None)][..],
+        ] {
+            let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
+            for (key, value) in changes.iter() {
+                match value {
+                    Some(value) => block.insert(key.to_vec(), value.to_vec()).unwrap(),
+                    None => block.delete(key.to_vec()).unwrap(),
+                };
+            }
+            let commit = block.commit().unwrap();
+            assert!(commit.is_created());
+            hashes.push(commit.hash());
+        }
+        hashes.dedup();
+        assert_eq!(hashes.len(), 3);
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_block_crash_recovery() {
+        use crate::pagecache::SimulatedDisk;
+
+        fn fill(block: &TreeBlock, tag: u8) -> BTreeMap<Key, Value> {
+            let mut kvs = BTreeMap::new();
+            for i in 0..32u8 {
+                let key = vec![tag, i];
+                let value = vec![i; 64 + tag as usize];
+                block.insert(key.clone(), value.clone()).unwrap();
+                kvs.insert(key, value);
+            }
+            kvs
+        }
+
+        fn check(db: &Database, hash: &Hash, kvs: &BTreeMap<Key, Value>) {
+            let block = db.open_block(hash).unwrap().unwrap();
+            let found: BTreeMap<Key, Value> = block.iter().map(|kv| kv.unwrap()).collect();
+            assert_eq!(&found, kvs);
+        }
+
+        for seed in 0..32 {
+            let disk = Arc::new(SimulatedDisk::new(seed));
+            let db = Database::new(Config::with_storage(disk.clone())).unwrap();
+
+            let genesis = db.genesis().unwrap();
+            let mut kvs = fill(&genesis, 0);
+            let mut hash = genesis.commit().unwrap().hash();
+
+            // lose power somewhere in the middle of the commits below
+            disk.crash_on_write(seed as usize % 8 + 1);
+
+            let mut survived = vec![(hash, kvs.clone())];
+            let mut in_flight = None;
+            let mut recovered = None;
+            for tag in 1..8 {
+                let child = db.open_block(&hash).unwrap().unwrap().fork().unwrap();
+                kvs.append(&mut fill(&child, tag));
+                hash = child.commit().unwrap().hash();
+
+                if recovered.is_none() {
+                    recovered = disk.crashed();
+                    if recovered.is_some() {
+                        in_flight = Some((hash, kvs.clone()));
+                    } else {
+                        survived.push((hash, kvs.clone()));
+                    }
+                }
+            }
+            let recovered = Arc::new(recovered.unwrap_or_else(|| disk.crash()));
+            drop(db);
+
+            let db = Database::new(Config::with_storage(recovered)).unwrap();
+            for (hash, kvs) in &survived {
+                check(&db, hash, kvs);
+            }
+            // the commit the crash hit is either all there or not at all
+            if let Some((hash, kvs)) = in_flight {
+                if db.open_block(&hash).unwrap().is_some() {
+                    check(&db, &hash, &kvs);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_block_commit_existed_is_durable() {
+        use crate::pagecache::{Durability, SimulatedDisk};
+
+        for seed in 0..16 {
+            let disk = Arc::new(SimulatedDisk::new(seed));
+            let db = Database::new(Config::with_storage(disk.clone())).unwrap();
+            let genesis = db.genesis().unwrap().commit().unwrap().hash();
+
+            // the first copy is committed without waiting for the disk
+            let first = db.open_block(&genesis).unwrap().unwrap().fork().unwrap();
+            first.insert(b"k".to_vec(), b"v".to_vec()).unwrap();
+            let created = first.commit_with(Durability::None).unwrap();
+            assert!(created.is_created());
+
+            let second = db.open_block(&genesis).unwrap().unwrap().fork().unwrap();
+            second.insert(b"k".to_vec(), b"v".to_vec()).unwrap();
+            let existed = second.commit().unwrap();
+            assert_eq!(existed, Commit::Existed(created.hash()));
+
+            let recovered = Arc::new(disk.crash());
+            drop(db);
+
+            let db = Database::new(Config::with_storage(recovered)).unwrap();
+            let block = db.open_block(&existed).unwrap().unwrap();
+            let found: Vec<_> = block.iter().map(|kv| kv.unwrap()).collect();
+            assert_eq!(found, vec![(b"k".to_vec(), b"v".to_vec())]);
+        }
+    }
+
     #[cfg(not(loom))]
     #[test]
     fn test_block_insert_get() {
         let (db, hash) = &*INIT;
-        let block = db.open_block(hash).unwrap().unwrap();
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
 
         assert_eq!(block.get(&b"0".to_vec()).unwrap(), None);
         assert_eq!(block.get(&b"1".to_vec()).unwrap(), None);
@@ -344,7 +727,7 @@ mod tests {
         assert_eq!(block.get(&b"5".to_vec()).unwrap(), None);
 
         let hash = block.commit().unwrap();
-        let block = db.open_block(&hash).unwrap().unwrap();
+        let block = db.open_block(&hash).unwrap().unwrap().fork().unwrap();
 
         assert_eq!(block.get(&b"0".to_vec()).unwrap(), None);
         assert_eq!(block.get(&b"1".to_vec()).unwrap(), None);
@@ -371,7 +754,7 @@ mod tests {
     #[test]
     fn test_block_range() {
         let (db, hash) = &*INIT;
-        let block = db.open_block(hash).unwrap().unwrap();
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
 
         // case #1. empty database
         assert!(block.iter().next().is_none());
@@ -474,7 +857,7 @@ mod tests {
     #[test]
     fn test_block_scan_prefix() {
         let (db, hash) = &*INIT;
-        let block = db.open_block(hash).unwrap().unwrap();
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
 
         block.insert(b"210".to_vec(), b"2".to_vec()).unwrap();
         block.insert(b"220".to_vec(), b"2".to_vec()).unwrap();
@@ -521,7 +904,7 @@ mod tests {
     #[test]
     fn test_block_iter_count() {
         let (db, hash) = &*INIT;
-        let block = db.open_block(hash).unwrap().unwrap();
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
 
         block.insert(b"210".to_vec(), b"2".to_vec()).unwrap();
         block.insert(b"220".to_vec(), b"2".to_vec()).unwrap();
@@ -530,7 +913,7 @@ mod tests {
 
         let ref hash = block.commit().unwrap();
 
-        let block = db.open_block(hash).unwrap().unwrap();
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
 
         block.insert(b"123".to_vec(), b"1".to_vec()).unwrap();
         block.insert(b"400".to_vec(), b"4".to_vec()).unwrap();
@@ -539,7 +922,7 @@ mod tests {
         assert_eq!(block.iter().count(), 7);
 
         let ref hash = block.commit().unwrap();
-        let block = db.open_block(hash).unwrap().unwrap();
+        let block = db.open_block(hash).unwrap().unwrap().fork().unwrap();
 
         // two more insertions
         block.insert(b"010".to_vec(), b"0".to_vec()).unwrap();
@@ -0,0 +1,300 @@
+//! Typed views over blocks and buckets.
+//!
+//! Values are serialized with bincode. Keys go through the order-preserving
+//! encoding of `codec`, so range scans follow the logical order of the keys.
+use crate::{bucket::Bucket, codec, iter::Iter, prelude::*};
+use serde::de::DeserializeOwned;
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+pub(crate) fn encode_key<K: Serialize>(key: &K) -> IResult<Key> {
+    Ok(codec::to_key(key)?)
+}
+
+pub(crate) fn decode_key<K: DeserializeOwned>(raw: &[u8]) -> IResult<K> {
+    Ok(codec::from_key(raw)?)
+}
+
+fn encode_value<V: Serialize>(value: &V) -> IResult<Value> {
+    Ok(bincode::serialize(value)?)
+}
+
+fn decode_value<V: DeserializeOwned>(raw: &[u8]) -> IResult<V> {
+    Ok(bincode::deserialize(raw)?)
+}
+
+fn encode_bound<K: Serialize>(bound: Bound<&K>) -> IResult<Bound<Key>> {
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(encode_key(key)?),
+        Bound::Excluded(key) => Bound::Excluded(encode_key(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+fn encode_range<K: Serialize, R: RangeBounds<K>>(range: &R) -> IResult<(Bound<Key>, Bound<Key>)> {
+    Ok((
+        encode_bound(range.start_bound())?,
+        encode_bound(range.end_bound())?,
+    ))
+}
+
+/// A `TreeBlock` with typed keys and values
+#[derive(Clone)]
+pub struct TypedBlock<K, V> {
+    inner: TreeBlock,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedBlock<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Wrap a `TreeBlock`
+    pub fn new(inner: TreeBlock) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The underlying `TreeBlock`
+    pub fn into_inner(self) -> TreeBlock {
+        self.inner
+    }
+
+    /// Get value
+    pub fn get(&self, key: &K) -> DBResult<V> {
+        self.inner
+            .get(encode_key(key)?)?
+            .map(|raw| decode_value(&raw))
+            .transpose()
+    }
+
+    /// insert a value, returns old value
+    pub fn insert(&self, key: &K, value: &V) -> DBResult<V> {
+        self.inner
+            .insert(encode_key(key)?, encode_value(value)?)?
+            .map(|raw| decode_value(&raw))
+            .transpose()
+    }
+
+    /// delete a value, returns old value
+    pub fn delete(&self, key: &K) -> DBResult<V> {
+        self.inner
+            .delete(encode_key(key)?)?
+            .map(|raw| decode_value(&raw))
+            .transpose()
+    }
+
+    /// Commit the underlying `TreeBlock`
+    pub fn commit(self) -> IResult<Commit> {
+        self.inner.commit()
+    }
+
+    /// Commit the underlying `TreeBlock` with the given `Durability`
+    pub fn commit_with(self, durability: Durability) -> IResult<Commit> {
+        self.inner.commit_with(durability)
+    }
+
+    /// Iterator over the block
+    pub fn iter(&self) -> TypedIter<K, V> {
+        TypedIter::new(self.inner.iter())
+    }
+
+    /// Scan with key-range
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> IResult<TypedIter<K, V>> {
+        Ok(TypedIter::new(self.inner.range(encode_range(&range)?)))
+    }
+}
+
+/// A `Bucket` with typed keys and values
+#[derive(Clone)]
+pub struct TypedBucket<K, V> {
+    inner: Bucket,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedBucket<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Wrap a `Bucket`
+    pub fn new(inner: Bucket) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The underlying `Bucket`
+    pub fn into_inner(self) -> Bucket {
+        self.inner
+    }
+
+    /// Get value
+    pub fn get(&self, key: &K) -> DBResult<V> {
+        self.inner
+            .get(encode_key(key)?)?
+            .map(|raw| decode_value(&raw))
+            .transpose()
+    }
+
+    /// insert a value, returns old value
+    pub fn insert(&self, key: &K, value: &V) -> DBResult<V> {
+        self.inner
+            .insert(encode_key(key)?, encode_value(value)?)?
+            .map(|raw| decode_value(&raw))
+            .transpose()
+    }
+
+    /// remove a value, returns old value
+    pub fn remove(&self, key: &K) -> DBResult<V> {
+        self.inner
+            .remove(encode_key(key)?)?
+            .map(|raw| decode_value(&raw))
+            .transpose()
+    }
+
+    /// Iterator over the bucket
+    pub fn iter(&self) -> TypedIter<K, V> {
+        TypedIter::new(self.inner.iter())
+    }
+
+    /// Scan with key-range
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> IResult<TypedIter<K, V>> {
+        Ok(TypedIter::new(self.inner.range(encode_range(&range)?)))
+    }
+}
+
+/// An iterator over typed keys and values
+pub struct TypedIter<K, V> {
+    inner: Iter,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> TypedIter<K, V> {
+    fn new(inner: Iter) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Iterator for TypedIter<K, V>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    type Item = IResult<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|res| {
+            let (k, v) = res?;
+            Ok((decode_key(&k)?, decode_value(&v)?))
+        })
+    }
+
+    fn last(self) -> Option<Self::Item> {
+        self.inner.last().map(|res| {
+            let (k, v) = res?;
+            Ok((decode_key(&k)?, decode_value(&v)?))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Database;
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_typed_block_range() {
+        let db = Database::default();
+        let block = TypedBlock::<(u32, u64), String>::new(db.genesis().unwrap());
+
+        for (a, b) in [(2, 1), (1, 300), (1, 2), (256, 0), (2, 0)].iter() {
+            block.insert(&(*a, *b), &format!("{}-{}", a, b)).unwrap();
+        }
+
+        assert_eq!(block.get(&(1, 300)).unwrap(), Some("1-300".to_string()));
+        assert_eq!(block.delete(&(2, 1)).unwrap(), Some("2-1".to_string()));
+
+        let keys = block
+            .iter()
+            .map(|res| res.map(|(k, _)| k))
+            .collect::<IResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, vec![(1, 2), (1, 300), (2, 0), (256, 0)]);
+
+        let keys = block
+            .range((1, 3)..(256, 0))
+            .unwrap()
+            .map(|res| res.map(|(k, _)| k))
+            .collect::<IResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, vec![(1, 300), (2, 0)]);
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_typed_block_signed_and_string_keys() {
+        let db = Database::default();
+        let block = TypedBlock::<(String, i64), u8>::new(db.genesis().unwrap());
+
+        for (name, at) in [("b", -1), ("a", 5), ("ab", -300), ("a", -2), ("b", 0)].iter() {
+            block.insert(&(name.to_string(), *at), &0).unwrap();
+        }
+
+        let keys = block
+            .range(("a".to_string(), 0)..("b".to_string(), 0))
+            .unwrap()
+            .map(|res| res.map(|(k, _)| k))
+            .collect::<IResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                ("a".to_string(), 5),
+                ("ab".to_string(), -300),
+                ("b".to_string(), -1),
+            ]
+        );
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn test_typed_bucket() {
+        let db = Database::default();
+        let bucket =
+            TypedBucket::<u64, Vec<String>>::new(db.open_bucket(b"typed".to_vec()).unwrap());
+
+        assert_eq!(bucket.insert(&512, &vec!["a".into()]).unwrap(), None);
+        assert_eq!(bucket.insert(&3, &vec![]).unwrap(), None);
+        assert_eq!(
+            bucket.insert(&512, &vec!["b".into()]).unwrap(),
+            Some(vec!["a".to_string()])
+        );
+
+        // reopening the bucket sees the same data
+        let bucket =
+            TypedBucket::<u64, Vec<String>>::new(db.open_bucket(b"typed".to_vec()).unwrap());
+        assert_eq!(bucket.get(&512).unwrap(), Some(vec!["b".to_string()]));
+
+        let keys = bucket
+            .range(..)
+            .unwrap()
+            .map(|res| res.map(|(k, _)| k))
+            .collect::<IResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys, vec![3, 512]);
+
+        assert_eq!(bucket.remove(&3).unwrap(), Some(vec![]));
+        assert_eq!(bucket.iter().count(), 1);
+    }
+}
@@ -0,0 +1,91 @@
+//! Drives the `maybe_fail!` / `io_fail!` points of the pagecache. The
+//! failpoint registry is global to the process, so these live in a test
+//! binary of their own instead of next to the code, where they would
+//! fail every test running alongside them.
+#![cfg(feature = "failpoints")]
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use cloyster::pagecache::{pin, ConfigBuilder, Error, PageCache, SimulatedDisk};
+
+type Page = BTreeMap<Vec<u8>, Vec<u8>>;
+
+fn page(i: u8) -> Page {
+    // every fourth one is large enough to be stored as a blob
+    let len = if i % 4 == 3 { 8192 } else { 64 };
+    let mut page = BTreeMap::new();
+    page.insert(vec![i], vec![i; len]);
+    page
+}
+
+fn config() -> ConfigBuilder {
+    ConfigBuilder::new().io_buf_size(8192)
+}
+
+#[test]
+fn test_io_failpoints_then_recover() {
+    for (seed, point) in [
+        "buffer write",
+        "buffer write post",
+        "log sync",
+        "blob blob write",
+    ]
+    .iter()
+    .enumerate()
+    {
+        let disk = Arc::new(SimulatedDisk::new(seed as u64));
+        let pc = PageCache::<Page>::start_with_storage(config(), disk.clone()).unwrap();
+        let guard = pin();
+
+        let mut durable = vec![];
+        for i in 0..16 {
+            let (pid, _) = pc.allocate(page(i), &guard).unwrap();
+            durable.push((pid, i));
+        }
+        pc.flush().unwrap();
+
+        fail::cfg(*point, "return").unwrap();
+        let mut in_flight = vec![];
+        let mut failed = false;
+        for i in 16..32 {
+            match pc.allocate(page(i), &guard) {
+                Ok((pid, _)) => in_flight.push((pid, i)),
+                Err(Error::FailPoint) => {
+                    failed = true;
+                    break;
+                }
+                Err(e) => panic!("{}: unexpected error {:?}", point, e),
+            }
+        }
+        if !failed {
+            match pc.flush() {
+                Err(Error::FailPoint) => {}
+                other => panic!("{}: flushed through the failpoint: {:?}", point, other),
+            }
+        }
+        fail::remove(*point);
+
+        drop(guard);
+        drop(pc);
+
+        // everything flushed before is there, whatever the failure
+        // interrupted is either all there or missing
+        let recovered = Arc::new(disk.crash());
+        let pc = PageCache::<Page>::start_with_storage(config(), recovered).unwrap();
+        let guard = pin();
+        for (pid, i) in durable {
+            let (_, got, _) = pc.get(pid, &guard).unwrap().unwrap();
+            assert_eq!(got, &page(i), "{}: lost a flushed page", point);
+        }
+        for (pid, i) in in_flight {
+            if let Some((_, got, _)) = pc.get(pid, &guard).unwrap() {
+                assert_eq!(got, &page(i), "{}: recovered a torn page", point);
+            }
+        }
+
+        // and it takes writes again
+        let (pid, _) = pc.allocate(page(32), &guard).unwrap();
+        pc.flush().unwrap();
+        assert_eq!(pc.get(pid, &guard).unwrap().unwrap().1, &page(32));
+    }
+}